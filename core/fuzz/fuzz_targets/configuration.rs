@@ -0,0 +1,38 @@
+#![no_main]
+// Settings loaded from NVS holding anything at all. The firmware panics when
+// init fails, so a corrupt value must fall back to defaults instead of
+// failing every boot. The admin credential is the exception, it locks.
+
+use libfuzzer_sys::fuzz_target;
+use ota_core::configuration::{AppConfiguration, NvsStorage, Wifi};
+use ota_host::storage::MemoryStorage;
+use std::sync::{Arc, RwLock};
+
+const KEYS: [&str; 8] = [
+    "ap", "sta", "bms", "mqtt", "ota", "health", "update", "auth",
+];
+
+fuzz_target!(|data: &[u8]| {
+    // One value per key, separated by NUL bytes
+    let mut store = MemoryStorage::new();
+    for (key, value) in KEYS.iter().zip(data.split(|b| *b == 0)) {
+        if store.set_val(key, value).is_err() {
+            return;
+        }
+    }
+    let store = Arc::new(RwLock::new(store));
+    let mut config = AppConfiguration::default();
+    config
+        .init(store.clone(), Wifi::default(), Wifi::default())
+        .unwrap();
+    // What init leaves behind loads again unchanged
+    let mut reloaded = AppConfiguration::default();
+    reloaded
+        .init(store, Wifi::default(), Wifi::default())
+        .unwrap();
+    assert_eq!(config.auth.locked, reloaded.auth.locked);
+    assert_eq!(
+        serde_json::to_string(&config).unwrap(),
+        serde_json::to_string(&reloaded).unwrap()
+    );
+});
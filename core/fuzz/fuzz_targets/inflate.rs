@@ -0,0 +1,44 @@
+#![no_main]
+// Any bytes as a gzip, zlib or raw deflate upload, fed whole and in small
+// chunks. Nothing may panic and the chunking must not change the output or
+// whether it inflates.
+
+use libfuzzer_sys::fuzz_target;
+use ota_core::inflate::{Compression, Inflater};
+
+fn inflate(format: Compression, data: &[u8], chunk: usize) -> (Vec<u8>, bool) {
+    let mut inflater = Inflater::new(format);
+    let mut out = Vec::new();
+    for piece in data.chunks(chunk) {
+        let pushed = inflater.push(piece, |data| {
+            out.extend_from_slice(data);
+            Ok(())
+        });
+        if pushed.is_err() {
+            return (out, false);
+        }
+    }
+    assert_eq!(inflater.total_out(), out.len());
+    let ok = inflater.finish().is_ok();
+    (out, ok)
+}
+
+fuzz_target!(|data: &[u8]| {
+    // <format and chunk size byte><compressed>
+    let (setup, data) = match data.split_first() {
+        Some((setup, data)) => (*setup, data),
+        None => return,
+    };
+    let format = match setup % 3 {
+        0 => Compression::Gzip,
+        1 => Compression::Zlib,
+        _ => Compression::Deflate,
+    };
+    let chunk = (setup / 3) as usize % 64 + 1;
+    let (whole, whole_ok) = inflate(format, data, data.len().max(1));
+    let (chunked, chunked_ok) = inflate(format, data, chunk);
+    assert_eq!(whole_ok, chunked_ok);
+    if whole_ok {
+        assert_eq!(whole, chunked);
+    }
+});
@@ -0,0 +1,59 @@
+#![no_main]
+// Any Content-Type and body, fed whole and in small chunks. Nothing may panic
+// and the chunking must not change the parts, the data or whether it parses.
+
+use libfuzzer_sys::fuzz_target;
+use ota_core::multipart::{boundary_from_content_type, is_multipart, Event, MultipartParser};
+
+#[derive(Debug, PartialEq)]
+enum Out {
+    Start(String),
+    Data(Vec<u8>),
+    End,
+}
+
+fn parse(boundary: &str, body: &[u8], chunk: usize) -> (Vec<Out>, bool) {
+    let mut parser = MultipartParser::new(boundary);
+    let mut out = Vec::new();
+    for data in body.chunks(chunk) {
+        let fed = parser.feed(data, |event| {
+            match event {
+                Event::PartStart(part) => out.push(Out::Start(format!("{part:?}"))),
+                // Joined, chunking decides where Data events split
+                Event::Data(data) => match out.last_mut() {
+                    Some(Out::Data(joined)) => joined.extend_from_slice(data),
+                    _ => out.push(Out::Data(data.to_vec())),
+                },
+                Event::PartEnd => out.push(Out::End),
+            }
+            Ok(())
+        });
+        if fed.is_err() {
+            return (out, false);
+        }
+    }
+    let ok = parser.finish().is_ok();
+    (out, ok)
+}
+
+fuzz_target!(|data: &[u8]| {
+    // <Content-Type>\n<chunk size byte><body>
+    let (header, rest) = match data.iter().position(|b| *b == b'\n') {
+        Some(at) => (String::from_utf8_lossy(&data[..at]), &data[at + 1..]),
+        None => return,
+    };
+    let (chunk, body) = match rest.split_first() {
+        Some((chunk, body)) => (*chunk as usize % 64 + 1, body),
+        None => return,
+    };
+    let boundary = match is_multipart(&header) {
+        true => boundary_from_content_type(&header).unwrap_or(&header),
+        false => &header,
+    };
+    let (whole, whole_ok) = parse(boundary, body, body.len().max(1));
+    let (chunked, chunked_ok) = parse(boundary, body, chunk);
+    assert_eq!(whole_ok, chunked_ok);
+    if whole_ok {
+        assert_eq!(whole, chunked);
+    }
+});
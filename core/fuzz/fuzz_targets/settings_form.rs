@@ -0,0 +1,13 @@
+#![no_main]
+// POST /settings bodies. Whatever is accepted has to fit the Wi-Fi driver,
+// which panics on an SSID or passphrase that is too long.
+
+use libfuzzer_sys::fuzz_target;
+use ota_core::configuration::{WifiForm, MAX_PASS_LEN, MAX_SSID_LEN};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(form) = WifiForm::parse(data) {
+        assert!(!form.ssid.is_empty() && form.ssid.len() <= MAX_SSID_LEN);
+        assert!(form.pass.len() <= MAX_PASS_LEN);
+    }
+});
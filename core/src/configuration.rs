@@ -0,0 +1,729 @@
+// nvs
+
+#![allow(unused_imports)]
+#![allow(dead_code)]
+
+use anyhow::anyhow;
+use anyhow::Context;
+use serde_json::*;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+use crate::error::OtaError;
+use crate::manifest::Check;
+use log::info;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+type KeyPair = std::collections::HashMap<String, serde_json::Value>;
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct Wifi {
+    pub nvs: String,
+    pub ssid: Option<String>,
+    pub pass: Option<String>,
+    pub channel: Option<u8>,
+}
+
+// Longest SSID and passphrase the Wi-Fi driver takes, it panics on more
+pub const MAX_SSID_LEN: usize = 32;
+pub const MAX_PASS_LEN: usize = 64;
+
+/// The POST /settings form, `ssid=...&pass=...`
+#[derive(Debug, PartialEq, Eq)]
+pub struct WifiForm {
+    pub ssid: String,
+    pub pass: String,
+}
+
+impl WifiForm {
+    pub fn parse(body: &[u8]) -> std::result::Result<Self, OtaError> {
+        let field = |name: &str| {
+            url::form_urlencoded::parse(body)
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.into_owned())
+                .ok_or_else(|| OtaError::BadRequest(format!("Missing form field {name}")))
+        };
+        let (ssid, pass) = (field("ssid")?, field("pass")?);
+        if ssid.is_empty() || ssid.len() > MAX_SSID_LEN {
+            return Err(OtaError::BadRequest(format!(
+                "SSID must be 1 to {MAX_SSID_LEN} bytes"
+            )));
+        }
+        if pass.len() > MAX_PASS_LEN {
+            return Err(OtaError::BadRequest(format!(
+                "Password must be at most {MAX_PASS_LEN} bytes"
+            )));
+        }
+        Ok(Self { ssid, pass })
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct BmsSettings {
+    pub nvs: String,
+}
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct MqttSettings {
+    pub nvs: String,
+    pub address: String,
+    pub username: String,
+    pub password: String,
+    pub client_id: String,
+    pub base_topic: String,
+    pub qos: u8,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct OtaSettings {
+    pub nvs: String,
+    pub url: Option<String>,
+    /// Refuse images whose secure_version is below the counter kept in NVS
+    #[serde(default)]
+    pub secure_version_check: bool,
+}
+
+/// Self-tests a freshly updated build has to pass before it is marked valid
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthCheck {
+    WifiSta,
+    GatewayPing,
+    HttpServer,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HealthSettings {
+    pub nvs: String,
+    /// Minimum uptime before the update is confirmed
+    pub probation_secs: u64,
+    /// Roll back if the checks have not passed by then
+    pub timeout_secs: u64,
+    pub checks: Vec<HealthCheck>,
+}
+
+impl Default for HealthSettings {
+    fn default() -> Self {
+        Self {
+            nvs: String::new(),
+            probation_secs: 30,
+            timeout_secs: 120,
+            checks: vec![
+                HealthCheck::WifiSta,
+                HealthCheck::GatewayPing,
+                HealthCheck::HttpServer,
+            ],
+        }
+    }
+}
+
+/// What to do when the update server offers a newer build
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdatePolicy {
+    /// Download, install and reboot
+    Auto,
+    /// Only report it on GET /api/ota/update
+    Notify,
+    /// No polling, checks happen on request
+    #[default]
+    Manual,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct UpdateSettings {
+    pub nvs: String,
+    pub manifest_url: Option<String>,
+    pub policy: UpdatePolicy,
+    pub channel: String,
+    /// Matched against the manifest hardware id, the chip name when unset
+    pub hardware_id: Option<String>,
+    pub poll_interval_secs: u64,
+}
+
+impl Default for UpdateSettings {
+    fn default() -> Self {
+        Self {
+            nvs: String::new(),
+            manifest_url: None,
+            policy: UpdatePolicy::default(),
+            channel: "stable".to_string(),
+            hardware_id: None,
+            poll_interval_secs: 3600,
+        }
+    }
+}
+
+/// Floor for poll_interval_secs, also how often the poll loop notices a settings change
+pub const MIN_POLL_INTERVAL_SECS: u64 = 60;
+
+impl UpdateSettings {
+    /// Checks settings from PUT /api/ota/update/settings, a too short poll interval is raised.
+    pub fn validate(&mut self) -> std::result::Result<(), OtaError> {
+        if let Some(url) = &self.manifest_url {
+            let parsed =
+                url::Url::parse(url).map_err(|e| OtaError::BadRequest(format!("{url}: {e}")))?;
+            if !matches!(parsed.scheme(), "http" | "https") {
+                return Err(OtaError::BadRequest(format!(
+                    "Unsupported url scheme {}",
+                    parsed.scheme()
+                )));
+            }
+        }
+        self.poll_interval_secs = self.poll_interval_secs.max(MIN_POLL_INTERVAL_SECS);
+        Ok(())
+    }
+
+    /// Whether the poll loop checks now, given how long ago the last check was.
+    pub fn poll_due(&self, since_check: Option<std::time::Duration>) -> bool {
+        if self.policy == UpdatePolicy::Manual || self.manifest_url.is_none() {
+            return false;
+        }
+        let interval = self.poll_interval_secs.max(MIN_POLL_INTERVAL_SECS);
+        !matches!(since_check, Some(since) if since.as_secs() < interval)
+    }
+
+    /// Whether a poll that found `check` goes on to install it.
+    pub fn installs(&self, check: &Check) -> bool {
+        self.policy == UpdatePolicy::Auto && matches!(check, Check::Available { .. })
+    }
+}
+
+/// Admin credential, hashes only, see auth.rs
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct AuthSettings {
+    pub nvs: String,
+    pub username: Option<String>,
+    /// hex
+    pub salt: Option<String>,
+    /// PBKDF2-HMAC-SHA256, hex
+    pub password_hash: Option<String>,
+    /// PBKDF2 rounds of `password_hash`, unset for credentials from before it was stored
+    pub iterations: Option<u32>,
+    /// SHA-256 of the bearer token, hex
+    pub token_hash: Option<String>,
+    /// The stored credential did not parse. Nothing is accepted and it is never
+    /// overwritten, only erasing NVS over serial opens setup again.
+    #[serde(skip)]
+    pub locked: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AppConfiguration<S: NvsStorage> {
+    name: &'static str,
+    pub sta: Wifi,
+    pub ap: Wifi,
+    pub bms: BmsSettings,
+    pub mqtt: MqttSettings,
+    #[serde(default)]
+    pub ota: OtaSettings,
+    #[serde(default)]
+    pub health: HealthSettings,
+    #[serde(default)]
+    pub update: UpdateSettings,
+    // Kept out of /json
+    #[serde(default, skip_serializing)]
+    pub auth: AuthSettings,
+    #[serde(skip_serializing, skip_deserializing)]
+    nvs: Option<Arc<RwLock<S>>>,
+}
+
+// Derived, these would ask the storage to be Default and Clone too
+impl<S: NvsStorage> Default for AppConfiguration<S> {
+    fn default() -> Self {
+        Self {
+            name: Default::default(),
+            sta: Default::default(),
+            ap: Default::default(),
+            bms: Default::default(),
+            mqtt: Default::default(),
+            ota: Default::default(),
+            health: Default::default(),
+            update: Default::default(),
+            auth: Default::default(),
+            nvs: None,
+        }
+    }
+}
+
+impl<S: NvsStorage> Clone for AppConfiguration<S> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name,
+            sta: self.sta.clone(),
+            ap: self.ap.clone(),
+            bms: self.bms.clone(),
+            mqtt: self.mqtt.clone(),
+            ota: self.ota.clone(),
+            health: self.health.clone(),
+            update: self.update.clone(),
+            auth: self.auth.clone(),
+            nvs: self.nvs.clone(),
+        }
+    }
+}
+
+/// Largest value a key holds, reads go through a buffer this size
+pub const MAX_VALUE_LEN: usize = 512;
+
+/// Key-value store the settings live in. NVS on the device, see the firmware's
+/// esp_nvs.rs, tools/ota-host has in-memory and JSON file backends.
+pub trait NvsStorage {
+    fn contains(&self, key: &str) -> anyhow::Result<bool>;
+    fn get_val(&self, key: &str) -> anyhow::Result<Vec<u8>>;
+    fn set_val(&mut self, key: &str, val: &[u8]) -> anyhow::Result<bool>;
+    fn remove(&mut self, key: &str) -> anyhow::Result<bool>;
+}
+
+const SECURE_VERSION_KEY: &str = "secure_ver";
+
+impl<S: NvsStorage> AppConfiguration<S> {
+    /// Anti-rollback counter, only ever raised
+    pub fn secure_version(&self) -> anyhow::Result<u32> {
+        let store = self.nvs.as_ref().context("NVS not initialised")?;
+        let store = store
+            .read()
+            .map_err(|_| anyhow!("Failed to get read lock"))?;
+        // Only a counter never raised reads as 0, a failed read must not pass a downgrade
+        if !store.contains(SECURE_VERSION_KEY)? {
+            return Ok(0);
+        }
+        match store.get_val(SECURE_VERSION_KEY)? {
+            val if val.len() == 4 => Ok(u32::from_le_bytes([val[0], val[1], val[2], val[3]])),
+            _ => Err(anyhow!("NVS Key:{SECURE_VERSION_KEY} Bad len check")),
+        }
+    }
+
+    pub fn raise_secure_version(&self, version: u32) -> anyhow::Result<()> {
+        let current = self.secure_version()?;
+        if version <= current {
+            return Ok(());
+        }
+        info!("Raising secure version {current} -> {version}");
+        let store = self.nvs.as_ref().context("NVS not initialised")?;
+        let mut store = store
+            .write()
+            .map_err(|_| anyhow!("Failed to get write lock"))?;
+        store.set_val(SECURE_VERSION_KEY, &version.to_le_bytes())?;
+        Ok(())
+    }
+
+    pub fn erase_values_in_nvs(&mut self) -> anyhow::Result<()> {
+        let store = self.nvs.as_ref().unwrap().clone();
+        info!("Erasing old data in NVS");
+        if let Ok(mut store) = store.write() {
+            // Not the admin credential, that only goes with a full NVS erase
+            for val in ["ap", "sta", "bms", "mqtt", "ota", "health", "update"] {
+                match store.remove(val) {
+                    Ok(_) => info!("Removed {val}"),
+                    Err(e) => info!("Removed {val} failed {}", e),
+                };
+            }
+        }
+        Ok(())
+    }
+    /// Loads the settings from `nvs`, `sta` and `ap` are the Wi-Fi defaults
+    /// used until settings have been saved.
+    pub fn init(&mut self, nvs: Arc<RwLock<S>>, sta: Wifi, ap: Wifi) -> anyhow::Result<()> {
+        self.nvs = Some(nvs.clone());
+        self.ap = ap;
+        self.ap.set_nvs_key("ap".into());
+        self.ap.channel = Some(1);
+
+        self.sta = sta;
+        self.sta.set_nvs_key("sta".into());
+        self.bms.set_nvs_key("bms".into());
+        self.mqtt.set_nvs_key("mqtt".into());
+        self.ota.set_nvs_key("ota".into());
+        self.health.set_nvs_key("health".into());
+        self.update.set_nvs_key("update".into());
+        self.auth.set_nvs_key("auth".into());
+        let valid = if let Ok(store) = nvs.write() {
+            store.contains(&self.ap.nvs)?
+                && store.contains(&self.sta.nvs)?
+                && store.contains(&self.bms.nvs)?
+                && store.contains(&self.mqtt.nvs)?
+        } else {
+            false
+        };
+
+        let store = self.nvs.as_ref().unwrap().clone();
+        // Before anything is written back, a lost credential must not reopen setup
+        self.load_auth(&store)?;
+        if valid {
+            // A value that no longer parses falls back to its default alone
+            reload(&mut self.ap, &store)?;
+            reload(&mut self.sta, &store)?;
+            reload(&mut self.bms, &store)?;
+            reload(&mut self.mqtt, &store)?;
+        } else {
+            self.erase_values_in_nvs()?;
+            self.store_values_to_nvs()?;
+        }
+        // Added after the first release, fall back to defaults without wiping the rest
+        reload(&mut self.ota, &store)?;
+        reload(&mut self.health, &store)?;
+        reload(&mut self.update, &store)?;
+        Ok(())
+    }
+
+    // Missing is a device that was never set up. Anything stored that does not
+    // parse locks the credential instead of falling back to the open default.
+    fn load_auth(&mut self, store: &RwLock<S>) -> anyhow::Result<()> {
+        let stored = store
+            .read()
+            .map_err(|_| anyhow!("Failed to get read lock"))?
+            .contains(&self.auth.nvs)?;
+        match self.auth.read_from_nvs(store) {
+            Ok(auth) => self.auth = auth,
+            Err(_) if !stored => {
+                self.auth.write_to_nvs(store)?;
+            }
+            Err(e) => {
+                info!("Admin credential unreadable, locked until NVS is erased: {e}");
+                self.auth.locked = true;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn store_values_to_nvs(&mut self) -> anyhow::Result<()> {
+        let store = self.nvs.as_ref().unwrap().clone();
+        info!("Storing all settings to NVS");
+        if self.ap.nvs.is_empty() {
+            eprintln!("Attempted to call store on an empty");
+            self.ap.nvs = "ap".to_string();
+        }
+        self.ap.write_to_nvs(&store)?;
+
+        if self.sta.nvs.is_empty() {
+            eprintln!("Attempted to call store on an empty");
+            self.sta.nvs = "sta".to_string();
+        }
+        self.sta.write_to_nvs(&store)?;
+
+        if self.bms.nvs.is_empty() {
+            eprintln!("Attempted to call store on an empty");
+            self.bms.nvs = "bms".to_string();
+        }
+        self.bms.write_to_nvs(&store)?;
+
+        if self.mqtt.nvs.is_empty() {
+            eprintln!("Attempted to call store on an empty");
+            self.mqtt.nvs = "mqtt".to_string();
+        }
+        self.mqtt.write_to_nvs(&store)?;
+
+        if self.ota.nvs.is_empty() {
+            eprintln!("Attempted to call store on an empty");
+            self.ota.nvs = "ota".to_string();
+        }
+        self.ota.write_to_nvs(&store)?;
+
+        if self.health.nvs.is_empty() {
+            eprintln!("Attempted to call store on an empty");
+            self.health.nvs = "health".to_string();
+        }
+        self.health.write_to_nvs(&store)?;
+
+        if self.update.nvs.is_empty() {
+            eprintln!("Attempted to call store on an empty");
+            self.update.nvs = "update".to_string();
+        }
+        self.update.write_to_nvs(&store)?;
+
+        if self.auth.nvs.is_empty() {
+            eprintln!("Attempted to call store on an empty");
+            self.auth.nvs = "auth".to_string();
+        }
+        self.auth.write_to_nvs(&store)?;
+        Ok(())
+    }
+}
+
+// The stored value, or `value` written back when it is missing or does not parse
+fn reload<T: NvsStruct, S: NvsStorage>(value: &mut T, store: &RwLock<S>) -> anyhow::Result<()> {
+    match value.read_from_nvs(store) {
+        Ok(stored) => *value = stored,
+        Err(e) => {
+            info!("Stored settings unusable, writing defaults: {e}");
+            value.write_to_nvs(store)?;
+        }
+    }
+    Ok(())
+}
+
+pub trait NvsStruct {
+    fn set_nvs_key(&mut self, key: String) -> &mut Self;
+    fn read_from_nvs<S: NvsStorage>(
+        &mut self,
+        store: &RwLock<S>,
+    ) -> anyhow::Result<Self, anyhow::Error>
+    where
+        Self: std::marker::Sized;
+    fn write_to_nvs<S: NvsStorage>(
+        &mut self,
+        store: &RwLock<S>,
+    ) -> anyhow::Result<&mut Self, anyhow::Error>
+    where
+        Self: std::marker::Sized;
+}
+
+impl NvsStruct for Wifi {
+    fn set_nvs_key(&mut self, key: String) -> &mut Self {
+        info!("Setting nvs key to {key}");
+        self.nvs = key;
+        self
+    }
+    fn read_from_nvs<S: NvsStorage>(
+        &mut self,
+        store: &RwLock<S>,
+    ) -> anyhow::Result<Self, anyhow::Error> {
+        if let Ok(store) = store.read() {
+            match store.get_val(&self.nvs) {
+                Ok(val) => Ok(serde_json::from_slice(&val)?),
+                Err(e) => {
+                    eprintln!("{} - Using defaults - Error {}", self.nvs, e);
+                    Err(anyhow!("{} Error {}", self.nvs, e))
+                }
+            }
+        } else {
+            Err(anyhow!("Failed to get read lock"))
+        }
+    }
+
+    fn write_to_nvs<S: NvsStorage>(
+        &mut self,
+        store: &RwLock<S>,
+    ) -> anyhow::Result<&mut Self, anyhow::Error> {
+        let message = serde_json::to_string(&self)?;
+        if let Ok(mut store) = store.write() {
+            store.set_val(&self.nvs, message.as_bytes())?;
+            Ok(self)
+        } else {
+            Err(anyhow!("Failed to get write lock"))
+        }
+    }
+}
+impl NvsStruct for BmsSettings {
+    fn set_nvs_key(&mut self, key: String) -> &mut Self {
+        info!("Setting nvs key to {key}");
+        self.nvs = key;
+        self
+    }
+    fn read_from_nvs<S: NvsStorage>(
+        &mut self,
+        store: &RwLock<S>,
+    ) -> anyhow::Result<Self, anyhow::Error> {
+        if let Ok(store) = store.read() {
+            match store.get_val(&self.nvs) {
+                Ok(val) => Ok(serde_json::from_slice(&val)?),
+                Err(e) => {
+                    eprintln!("{} - Using defaults - Error {}", self.nvs, e);
+                    Err(anyhow!("{} Error {}", self.nvs, e))
+                }
+            }
+        } else {
+            Err(anyhow!("Failed to get read lock"))
+        }
+    }
+
+    fn write_to_nvs<S: NvsStorage>(
+        &mut self,
+        store: &RwLock<S>,
+    ) -> anyhow::Result<&mut Self, anyhow::Error> {
+        let message = serde_json::to_string(&self)?;
+        if let Ok(mut store) = store.write() {
+            store.set_val(&self.nvs, message.as_bytes())?;
+            Ok(self)
+        } else {
+            Err(anyhow!("Failed to get write lock"))
+        }
+    }
+}
+impl NvsStruct for MqttSettings {
+    fn set_nvs_key(&mut self, key: String) -> &mut Self {
+        info!("Setting nvs key to {key}");
+        self.nvs = key;
+        self
+    }
+    fn read_from_nvs<S: NvsStorage>(
+        &mut self,
+        store: &RwLock<S>,
+    ) -> anyhow::Result<Self, anyhow::Error> {
+        if let Ok(store) = store.read() {
+            match store.get_val(&self.nvs) {
+                Ok(val) => Ok(serde_json::from_slice(&val)?),
+                Err(e) => {
+                    eprintln!("{} - Using defaults - Error {}", self.nvs, e);
+                    Err(anyhow!("{} Error {}", self.nvs, e))
+                }
+            }
+        } else {
+            Err(anyhow!("Failed to get read lock"))
+        }
+    }
+
+    fn write_to_nvs<S: NvsStorage>(
+        &mut self,
+        store: &RwLock<S>,
+    ) -> anyhow::Result<&mut Self, anyhow::Error> {
+        let message = serde_json::to_string(&self)?;
+        if let Ok(mut store) = store.write() {
+            store.set_val(&self.nvs, message.as_bytes())?;
+            Ok(self)
+        } else {
+            Err(anyhow!("Failed to get write lock"))
+        }
+    }
+}
+impl NvsStruct for OtaSettings {
+    fn set_nvs_key(&mut self, key: String) -> &mut Self {
+        info!("Setting nvs key to {key}");
+        self.nvs = key;
+        self
+    }
+    fn read_from_nvs<S: NvsStorage>(
+        &mut self,
+        store: &RwLock<S>,
+    ) -> anyhow::Result<Self, anyhow::Error> {
+        if let Ok(store) = store.read() {
+            match store.get_val(&self.nvs) {
+                Ok(val) => Ok(serde_json::from_slice(&val)?),
+                Err(e) => {
+                    eprintln!("{} - Using defaults - Error {}", self.nvs, e);
+                    Err(anyhow!("{} Error {}", self.nvs, e))
+                }
+            }
+        } else {
+            Err(anyhow!("Failed to get read lock"))
+        }
+    }
+
+    fn write_to_nvs<S: NvsStorage>(
+        &mut self,
+        store: &RwLock<S>,
+    ) -> anyhow::Result<&mut Self, anyhow::Error> {
+        let message = serde_json::to_string(&self)?;
+        if let Ok(mut store) = store.write() {
+            store.set_val(&self.nvs, message.as_bytes())?;
+            Ok(self)
+        } else {
+            Err(anyhow!("Failed to get write lock"))
+        }
+    }
+}
+impl NvsStruct for HealthSettings {
+    fn set_nvs_key(&mut self, key: String) -> &mut Self {
+        info!("Setting nvs key to {key}");
+        self.nvs = key;
+        self
+    }
+    fn read_from_nvs<S: NvsStorage>(
+        &mut self,
+        store: &RwLock<S>,
+    ) -> anyhow::Result<Self, anyhow::Error> {
+        if let Ok(store) = store.read() {
+            match store.get_val(&self.nvs) {
+                Ok(val) => Ok(serde_json::from_slice(&val)?),
+                Err(e) => {
+                    eprintln!("{} - Using defaults - Error {}", self.nvs, e);
+                    Err(anyhow!("{} Error {}", self.nvs, e))
+                }
+            }
+        } else {
+            Err(anyhow!("Failed to get read lock"))
+        }
+    }
+
+    fn write_to_nvs<S: NvsStorage>(
+        &mut self,
+        store: &RwLock<S>,
+    ) -> anyhow::Result<&mut Self, anyhow::Error> {
+        let message = serde_json::to_string(&self)?;
+        if let Ok(mut store) = store.write() {
+            store.set_val(&self.nvs, message.as_bytes())?;
+            Ok(self)
+        } else {
+            Err(anyhow!("Failed to get write lock"))
+        }
+    }
+}
+impl NvsStruct for UpdateSettings {
+    fn set_nvs_key(&mut self, key: String) -> &mut Self {
+        info!("Setting nvs key to {key}");
+        self.nvs = key;
+        self
+    }
+    fn read_from_nvs<S: NvsStorage>(
+        &mut self,
+        store: &RwLock<S>,
+    ) -> anyhow::Result<Self, anyhow::Error> {
+        if let Ok(store) = store.read() {
+            match store.get_val(&self.nvs) {
+                Ok(val) => Ok(serde_json::from_slice(&val)?),
+                Err(e) => {
+                    eprintln!("{} - Using defaults - Error {}", self.nvs, e);
+                    Err(anyhow!("{} Error {}", self.nvs, e))
+                }
+            }
+        } else {
+            Err(anyhow!("Failed to get read lock"))
+        }
+    }
+
+    fn write_to_nvs<S: NvsStorage>(
+        &mut self,
+        store: &RwLock<S>,
+    ) -> anyhow::Result<&mut Self, anyhow::Error> {
+        let message = serde_json::to_string(&self)?;
+        if let Ok(mut store) = store.write() {
+            store.set_val(&self.nvs, message.as_bytes())?;
+            Ok(self)
+        } else {
+            Err(anyhow!("Failed to get write lock"))
+        }
+    }
+}
+impl NvsStruct for AuthSettings {
+    fn set_nvs_key(&mut self, key: String) -> &mut Self {
+        info!("Setting nvs key to {key}");
+        self.nvs = key;
+        self
+    }
+    fn read_from_nvs<S: NvsStorage>(
+        &mut self,
+        store: &RwLock<S>,
+    ) -> anyhow::Result<Self, anyhow::Error> {
+        if let Ok(store) = store.read() {
+            match store.get_val(&self.nvs) {
+                Ok(val) => Ok(serde_json::from_slice(&val)?),
+                Err(e) => {
+                    eprintln!("{} - Using defaults - Error {}", self.nvs, e);
+                    Err(anyhow!("{} Error {}", self.nvs, e))
+                }
+            }
+        } else {
+            Err(anyhow!("Failed to get read lock"))
+        }
+    }
+
+    fn write_to_nvs<S: NvsStorage>(
+        &mut self,
+        store: &RwLock<S>,
+    ) -> anyhow::Result<&mut Self, anyhow::Error> {
+        // Keep the unreadable value, writing defaults would reopen setup
+        if self.locked {
+            return Ok(self);
+        }
+        let message = serde_json::to_string(&self)?;
+        if let Ok(mut store) = store.write() {
+            store.set_val(&self.nvs, message.as_bytes())?;
+            Ok(self)
+        } else {
+            Err(anyhow!("Failed to get write lock"))
+        }
+    }
+}
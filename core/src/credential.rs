@@ -0,0 +1,190 @@
+// Admin credential checks, see the firmware's auth.rs
+//
+// The password is stored as PBKDF2-HMAC-SHA256 over a random salt, with the
+// iteration count next to the hash so it can be raised later, bearer tokens
+// as a plain SHA-256 since they are random already. Nothing here touches
+// ESP-IDF, the caller brings the settings and the random bytes.
+
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig};
+use base64::engine::DecodePaddingMode;
+use base64::{alphabet, Engine};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+
+use crate::configuration::AuthSettings;
+use crate::error::OtaError;
+use crate::verify::{parse_hex, to_hex, Sha256Digest};
+
+/// PBKDF2 rounds for a new password. OWASP's 600k would hold a request for
+/// most of a minute on the ESP32-C3's software SHA-256, this is about a second.
+pub const ITERATIONS: u32 = 50_000;
+/// Rounds of a password stored before the count was, see `AuthSettings::iterations`
+pub const LEGACY_ITERATIONS: u32 = 1000;
+pub const SALT_LEN: usize = 16;
+pub const TOKEN_LEN: usize = 32;
+pub const MIN_PASSWORD_LEN: usize = 8;
+/// WWW-Authenticate on 401, lets browsers ask for the credential themselves
+pub const CHALLENGE: &str = "Basic realm=\"ota-test\", charset=\"UTF-8\"";
+
+// Browsers and curl pad, not every script does
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+lazy_static! {
+    // The last Basic credential that verified, so a browser sending it with
+    // every request pays for PBKDF2 once rather than each time
+    static ref VERIFIED: Mutex<Option<Sha256Digest>> = Mutex::new(None);
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Credential {
+    Basic { username: String, password: String },
+    Bearer(String),
+}
+
+impl Credential {
+    /// From an Authorization header, `None` for anything malformed.
+    pub fn parse(header: &str) -> Option<Self> {
+        let (scheme, value) = header.trim().split_once(' ')?;
+        let value = value.trim();
+        if scheme.eq_ignore_ascii_case("bearer") {
+            return Some(Credential::Bearer(value.to_string()));
+        }
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let decoded = String::from_utf8(BASE64.decode(value).ok()?).ok()?;
+        let (username, password) = decoded.split_once(':')?;
+        Some(Credential::Basic {
+            username: username.to_string(),
+            password: password.to_string(),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SetupRequest {
+    pub username: String,
+    pub password: String,
+}
+
+/// Ok when `authorization` carries the admin password or the current bearer token.
+/// Fails closed with `CredentialLocked` when the stored credential could not be read.
+pub fn authorize(auth: &AuthSettings, authorization: Option<&str>) -> Result<(), OtaError> {
+    if auth.locked {
+        return Err(OtaError::CredentialLocked);
+    }
+    let (username, salt, password_hash) = match (&auth.username, &auth.salt, &auth.password_hash) {
+        (Some(username), Some(salt), Some(hash)) => (username, salt, hash),
+        _ => return Err(OtaError::SetupRequired),
+    };
+    let credential = authorization
+        .and_then(Credential::parse)
+        .ok_or(OtaError::Unauthorized)?;
+    let valid = match credential {
+        Credential::Basic {
+            username: given,
+            password,
+        } => {
+            let iterations = auth.iterations.unwrap_or(LEGACY_ITERATIONS);
+            // Ties the cached credential to the stored hash, a new password drops it
+            let seen: Sha256Digest = Sha256::new()
+                .chain_update(password_hash)
+                .chain_update(iterations.to_le_bytes())
+                .chain_update(authorization.unwrap_or_default())
+                .finalize()
+                .into();
+            let mut verified = VERIFIED.lock().unwrap();
+            if matches!(&*verified, Some(digest) if constant_time_eq(digest, &seen)) {
+                return Ok(());
+            }
+            let salt = parse_hex::<SALT_LEN>(salt)?;
+            let valid = &given == username
+                && verify_password(&salt, &parse_hex(password_hash)?, &password, iterations);
+            if valid {
+                *verified = Some(seen);
+            }
+            valid
+        }
+        Credential::Bearer(token) => match &auth.token_hash {
+            Some(hash) => verify_token(&parse_hex(hash)?, &token),
+            None => false,
+        },
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(OtaError::Unauthorized)
+    }
+}
+
+/// Whether setup is closed, also when the stored credential could not be read.
+pub fn configured(auth: &AuthSettings) -> bool {
+    auth.locked || auth.password_hash.is_some()
+}
+
+/// Replaces the admin credential, revoking any bearer token.
+pub fn set_credential(
+    auth: &mut AuthSettings,
+    request: &SetupRequest,
+    salt: [u8; SALT_LEN],
+) -> Result<(), OtaError> {
+    if auth.locked {
+        return Err(OtaError::CredentialLocked);
+    }
+    let username = request.username.trim();
+    if username.is_empty() || username.contains(':') {
+        return Err(OtaError::BadRequest(
+            "Username must not be empty or contain ':'".to_string(),
+        ));
+    }
+    if request.password.chars().count() < MIN_PASSWORD_LEN {
+        return Err(OtaError::BadRequest(format!(
+            "Password must be at least {MIN_PASSWORD_LEN} characters"
+        )));
+    }
+    auth.username = Some(username.to_string());
+    auth.salt = Some(to_hex(&salt));
+    auth.password_hash = Some(to_hex(&hash_password(&salt, &request.password, ITERATIONS)));
+    auth.iterations = Some(ITERATIONS);
+    auth.token_hash = None;
+    Ok(())
+}
+
+/// A bearer token from `random` bytes, shown once. Only its hash is kept.
+pub fn set_token(auth: &mut AuthSettings, random: [u8; TOKEN_LEN]) -> String {
+    let token = to_hex(&random);
+    auth.token_hash = Some(to_hex(&hash_token(&token)));
+    token
+}
+
+pub fn hash_password(salt: &[u8], password: &str, iterations: u32) -> Sha256Digest {
+    let mut hash = Sha256Digest::default();
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut hash);
+    hash
+}
+
+pub fn verify_password(
+    salt: &[u8],
+    expected: &Sha256Digest,
+    password: &str,
+    iterations: u32,
+) -> bool {
+    constant_time_eq(&hash_password(salt, password, iterations), expected)
+}
+
+pub fn hash_token(token: &str) -> Sha256Digest {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+pub fn verify_token(expected: &Sha256Digest, token: &str) -> bool {
+    constant_time_eq(&hash_token(token), expected)
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
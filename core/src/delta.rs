@@ -0,0 +1,361 @@
+// Delta updates, rebuilding the new image from the one in the running slot
+//
+// bsdiff style without the bzip2 stage, so the device can apply it while the
+// patch streams in:
+//
+//   header   "OTADIFF1", old_len u32, new_len u32, SHA-256 of the old image
+//   records  diff_len, extra_len, seek      LEB128 varints, seek zigzag encoded
+//            diff_len bytes of new - old    as runs of <zeros> <literal_len> <literal bytes>
+//            extra_len bytes taken as is
+//
+// After each record the old position moves on by diff_len + seek. Unchanged
+// code leaves the diff bytes mostly zero, the zero runs are what keep a patch
+// small. Patches are written by `tools/ota-sign diff`.
+
+use anyhow::{bail, Result};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+pub const PATCH_MAGIC: &[u8; 8] = b"OTADIFF1";
+pub const PATCH_HEADER_LEN: usize = 8 + 4 + 4 + 32;
+
+// Old image reads and output are done in blocks of this size
+const BLOCK: usize = 4096;
+// Hashing a whole slot takes seconds on the device, give the idle task a turn
+// this often so the task watchdog stays quiet
+const HASH_YIELD: usize = 64 * 1024;
+
+/// Random access to the image a patch was made against, the running slot on the device.
+pub trait SlotReader {
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PatchHeader {
+    pub old_len: usize,
+    pub new_len: usize,
+    pub old_sha256: [u8; 32],
+}
+
+impl PatchHeader {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < PATCH_HEADER_LEN || !data.starts_with(PATCH_MAGIC) {
+            bail!("Not an OTA patch");
+        }
+        let mut old_sha256 = [0u8; 32];
+        old_sha256.copy_from_slice(&data[16..48]);
+        Ok(Self {
+            old_len: u32::from_le_bytes([data[8], data[9], data[10], data[11]]) as usize,
+            new_len: u32::from_le_bytes([data[12], data[13], data[14], data[15]]) as usize,
+            old_sha256,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Header,
+    DiffLen,
+    ExtraLen,
+    Seek,
+    Zeros,
+    LiteralLen,
+    Literal(usize),
+    Extra(usize),
+    Done,
+}
+
+/// Applies a patch pushed in arbitrary chunks, handing the rebuilt image to `out`.
+pub struct DeltaApplier<S> {
+    old: S,
+    state: State,
+    header: Vec<u8>,
+    old_len: usize,
+    new_len: usize,
+    // Partial varint
+    varint: u64,
+    shift: u32,
+    diff_left: usize,
+    extra_len: usize,
+    seek: i64,
+    old_pos: usize,
+    new_pos: usize,
+    old_buf: Vec<u8>,
+    out_buf: Vec<u8>,
+}
+
+impl<S: SlotReader> DeltaApplier<S> {
+    pub fn new(old: S) -> Self {
+        Self {
+            old,
+            state: State::Header,
+            header: Vec::with_capacity(PATCH_HEADER_LEN),
+            old_len: 0,
+            new_len: 0,
+            varint: 0,
+            shift: 0,
+            diff_left: 0,
+            extra_len: 0,
+            seek: 0,
+            old_pos: 0,
+            new_pos: 0,
+            old_buf: vec![0; BLOCK],
+            out_buf: Vec::with_capacity(BLOCK * 2),
+        }
+    }
+
+    pub fn push(
+        &mut self,
+        mut input: &[u8],
+        mut out: impl FnMut(&[u8]) -> Result<()>,
+    ) -> Result<()> {
+        while !input.is_empty() {
+            match self.state {
+                State::Header => {
+                    let take = (PATCH_HEADER_LEN - self.header.len()).min(input.len());
+                    self.header.extend_from_slice(&input[..take]);
+                    input = &input[take..];
+                    if self.header.len() == PATCH_HEADER_LEN {
+                        self.begin()?;
+                    }
+                }
+                State::DiffLen => {
+                    if let Some(v) = self.varint(&mut input)? {
+                        self.diff_left = record_len(v)?;
+                        self.state = State::ExtraLen;
+                    }
+                }
+                State::ExtraLen => {
+                    if let Some(v) = self.varint(&mut input)? {
+                        self.extra_len = record_len(v)?;
+                        self.state = State::Seek;
+                    }
+                }
+                State::Seek => {
+                    if let Some(v) = self.varint(&mut input)? {
+                        self.seek = ((v >> 1) as i64) ^ -((v & 1) as i64);
+                        if self.diff_left.saturating_add(self.extra_len)
+                            > self.new_len - self.new_pos
+                        {
+                            bail!("Patch record runs past the new image at {}", self.new_pos);
+                        }
+                        if self.diff_left > 0 {
+                            self.state = State::Zeros;
+                        } else {
+                            self.start_extra()?;
+                        }
+                    }
+                }
+                State::Zeros => {
+                    if let Some(v) = self.varint(&mut input)? {
+                        let zeros = self.take_diff(v)?;
+                        self.copy_old(zeros, &mut out)?;
+                        if self.diff_left > 0 {
+                            self.state = State::LiteralLen;
+                        } else {
+                            self.start_extra()?;
+                        }
+                    }
+                }
+                State::LiteralLen => {
+                    if let Some(v) = self.varint(&mut input)? {
+                        let len = self.take_diff(v)?;
+                        if len == 0 {
+                            bail!("Empty literal run in patch");
+                        }
+                        self.state = State::Literal(len);
+                    }
+                }
+                State::Literal(left) => {
+                    let n = left.min(input.len());
+                    self.add_old(&input[..n], &mut out)?;
+                    input = &input[n..];
+                    if left > n {
+                        self.state = State::Literal(left - n);
+                    } else if self.diff_left > 0 {
+                        self.state = State::Zeros;
+                    } else {
+                        self.start_extra()?;
+                    }
+                }
+                State::Extra(left) => {
+                    let n = left.min(input.len());
+                    self.emit(&input[..n], &mut out)?;
+                    input = &input[n..];
+                    if left > n {
+                        self.state = State::Extra(left - n);
+                    } else {
+                        self.end_record()?;
+                    }
+                }
+                State::Done => bail!("Data after the end of the patch"),
+            }
+        }
+        self.flush(&mut out)
+    }
+
+    /// Fails unless the whole new image has been produced.
+    pub fn finish(&self) -> Result<()> {
+        if self.state != State::Done {
+            bail!(
+                "Patch ends early, rebuilt {} of {} bytes",
+                self.new_pos,
+                self.new_len
+            );
+        }
+        Ok(())
+    }
+
+    fn begin(&mut self) -> Result<()> {
+        let header = PatchHeader::parse(&self.header)?;
+        // A patch only makes sense against the exact image it was made from
+        let mut hasher = Sha256::new();
+        let mut offset = 0;
+        while offset < header.old_len {
+            let len = BLOCK.min(header.old_len - offset);
+            self.old.read_at(offset, &mut self.old_buf[..len])?;
+            hasher.update(&self.old_buf[..len]);
+            offset += len;
+            if offset % HASH_YIELD == 0 {
+                std::thread::sleep(Duration::from_millis(10)) //wdt
+            }
+        }
+        if hasher.finalize()[..] != header.old_sha256[..] {
+            bail!("Patch was made against a different image than the one running");
+        }
+        self.old_len = header.old_len;
+        self.new_len = header.new_len;
+        self.state = if header.new_len == 0 {
+            State::Done
+        } else {
+            State::DiffLen
+        };
+        Ok(())
+    }
+
+    fn varint(&mut self, input: &mut &[u8]) -> Result<Option<u64>> {
+        while let Some((&byte, rest)) = input.split_first() {
+            *input = rest;
+            // The tenth byte only has room for bit 63
+            if self.shift >= 64 || (self.shift == 63 && byte & 0x7e != 0) {
+                bail!("Bad varint in patch");
+            }
+            self.varint |= u64::from(byte & 0x7f) << self.shift;
+            self.shift += 7;
+            if byte & 0x80 == 0 {
+                let value = self.varint;
+                self.varint = 0;
+                self.shift = 0;
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    fn take_diff(&mut self, len: u64) -> Result<usize> {
+        let len = record_len(len)?;
+        if len > self.diff_left {
+            bail!("Diff run of {len} bytes overruns its record");
+        }
+        self.diff_left -= len;
+        Ok(len)
+    }
+
+    fn start_extra(&mut self) -> Result<()> {
+        if self.extra_len > 0 {
+            self.state = State::Extra(self.extra_len);
+            Ok(())
+        } else {
+            self.end_record()
+        }
+    }
+
+    fn end_record(&mut self) -> Result<()> {
+        let old_pos = match (self.old_pos as i64).checked_add(self.seek) {
+            Some(old_pos) if old_pos >= 0 && old_pos as usize <= self.old_len => old_pos,
+            _ => bail!(
+                "Patch seeks outside the old image to {}{:+}",
+                self.old_pos,
+                self.seek
+            ),
+        };
+        self.old_pos = old_pos as usize;
+        self.state = if self.new_pos == self.new_len {
+            State::Done
+        } else {
+            State::DiffLen
+        };
+        Ok(())
+    }
+
+    fn old_range(&self, len: usize) -> Result<()> {
+        if !matches!(self.old_pos.checked_add(len), Some(end) if end <= self.old_len) {
+            bail!("Patch reads past the old image at {}+{len}", self.old_pos);
+        }
+        Ok(())
+    }
+
+    // Diff byte zero, the old bytes carry over unchanged
+    fn copy_old(
+        &mut self,
+        mut len: usize,
+        out: &mut impl FnMut(&[u8]) -> Result<()>,
+    ) -> Result<()> {
+        self.old_range(len)?;
+        while len > 0 {
+            let n = len.min(BLOCK);
+            self.old.read_at(self.old_pos, &mut self.old_buf[..n])?;
+            self.old_pos += n;
+            len -= n;
+            self.out_buf.extend_from_slice(&self.old_buf[..n]);
+            self.new_pos += n;
+            if self.out_buf.len() >= BLOCK {
+                self.flush(out)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn add_old(&mut self, diff: &[u8], out: &mut impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
+        self.old_range(diff.len())?;
+        for diff in diff.chunks(BLOCK) {
+            let old = &mut self.old_buf[..diff.len()];
+            self.old.read_at(self.old_pos, old)?;
+            for (old, diff) in old.iter_mut().zip(diff) {
+                *old = old.wrapping_add(*diff);
+            }
+            self.old_pos += diff.len();
+            self.new_pos += diff.len();
+            self.out_buf.extend_from_slice(old);
+            if self.out_buf.len() >= BLOCK {
+                self.flush(out)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, data: &[u8], out: &mut impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
+        self.new_pos += data.len();
+        self.out_buf.extend_from_slice(data);
+        if self.out_buf.len() >= BLOCK {
+            self.flush(out)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, out: &mut impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
+        if !self.out_buf.is_empty() {
+            out(&self.out_buf)?;
+            self.out_buf.clear();
+        }
+        Ok(())
+    }
+}
+
+// Lengths are u64 varints, usize is 32 bits on the device
+fn record_len(v: u64) -> Result<usize> {
+    match usize::try_from(v) {
+        Ok(len) => Ok(len),
+        Err(_) => bail!("Patch record length {v} is out of range"),
+    }
+}
@@ -0,0 +1,184 @@
+// Typed OTA failures
+//
+// Every way an update can be rejected maps to an HTTP status and a JSON body
+//   {"code": "signature_invalid", "message": "...", "bytes_written": 524288}
+// so the OTA page and curl scripts can tell a bad image from a flaky link.
+
+use serde::Serialize;
+use std::fmt;
+
+use crate::status::OtaStatus;
+
+#[derive(Debug)]
+pub enum OtaError {
+    /// Upload without a Content-Length
+    MissingLength,
+    /// Malformed request: multipart framing, Content-Range, JSON, hex values
+    BadRequest(String),
+    /// Not an ESP app image, or built for another chip
+    InvalidImage(String),
+    /// Older than the running firmware or below the secure version
+    Downgrade(String),
+    Signature(String),
+    Checksum(String),
+    /// The client connection broke off mid upload
+    Transfer(String),
+    /// Pull mode, the firmware server did not deliver
+    Download(String),
+    /// esp_ota_begin/write/end failed
+    Flash(String),
+    /// No upload session with that id, sessions do not survive a reboot
+    NotFound,
+    /// The chunk does not continue from the current session offset
+    Offset(usize),
+    /// No partition with that label
+    SlotNotFound(String),
+    /// Empty, invalid or failing verification, not selectable for boot
+    SlotNotBootable(String),
+    /// Apply asked for with no update waiting
+    NothingStaged,
+    /// The update server offers nothing this device can install
+    NoUpdate(String),
+    /// Another update holds the update slot, with its progress
+    Busy(Box<OtaStatus>),
+    /// Missing or wrong admin credential
+    Unauthorized,
+    /// No admin credential set yet, see /setup
+    SetupRequired,
+    /// The stored admin credential is unreadable, only erasing NVS clears it
+    CredentialLocked,
+    /// Device side problem unrelated to the image, e.g. NVS or a bad OTA_PUBKEY
+    Internal(String),
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorBody {
+    pub code: &'static str,
+    pub message: String,
+    pub bytes_written: usize,
+    /// The update in the way of a busy one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress: Option<OtaStatus>,
+}
+
+impl OtaError {
+    pub fn status(&self) -> u16 {
+        match self {
+            OtaError::MissingLength => 411,
+            OtaError::BadRequest(_) => 400,
+            OtaError::InvalidImage(_) => 422,
+            OtaError::Downgrade(_) => 409,
+            OtaError::Signature(_) => 403,
+            OtaError::Checksum(_) => 422,
+            OtaError::Transfer(_) => 400,
+            OtaError::Download(_) => 502,
+            OtaError::Flash(_) => 500,
+            OtaError::NotFound => 404,
+            OtaError::Offset(_) => 416,
+            OtaError::SlotNotFound(_) => 404,
+            OtaError::SlotNotBootable(_) => 409,
+            OtaError::NothingStaged => 409,
+            OtaError::NoUpdate(_) => 409,
+            OtaError::Busy(_) => 409,
+            OtaError::Unauthorized => 401,
+            OtaError::SetupRequired => 403,
+            OtaError::CredentialLocked => 403,
+            OtaError::Internal(_) => 500,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            OtaError::MissingLength => "missing_length",
+            OtaError::BadRequest(_) => "bad_request",
+            OtaError::InvalidImage(_) => "invalid_image",
+            OtaError::Downgrade(_) => "downgrade_refused",
+            OtaError::Signature(_) => "signature_invalid",
+            OtaError::Checksum(_) => "checksum_mismatch",
+            OtaError::Transfer(_) => "transfer_failed",
+            OtaError::Download(_) => "download_failed",
+            OtaError::Flash(_) => "flash_failed",
+            OtaError::NotFound => "session_not_found",
+            OtaError::Offset(_) => "offset_mismatch",
+            OtaError::SlotNotFound(_) => "slot_not_found",
+            OtaError::SlotNotBootable(_) => "slot_not_bootable",
+            OtaError::NothingStaged => "nothing_staged",
+            OtaError::NoUpdate(_) => "no_update",
+            OtaError::Busy(_) => "busy",
+            OtaError::Unauthorized => "unauthorized",
+            OtaError::SetupRequired => "setup_required",
+            OtaError::CredentialLocked => "credential_locked",
+            OtaError::Internal(_) => "internal",
+        }
+    }
+
+    /// `bytes_written` is how far the update got, for a session the offset to resume from.
+    pub fn body(&self, bytes_written: usize) -> ErrorBody {
+        let bytes_written = match self {
+            OtaError::Offset(offset) => *offset,
+            _ => bytes_written,
+        };
+        ErrorBody {
+            code: self.code(),
+            message: self.to_string(),
+            bytes_written,
+            progress: match self {
+                OtaError::Busy(current) => Some((**current).clone()),
+                _ => None,
+            },
+        }
+    }
+
+    pub fn json(&self, bytes_written: usize) -> String {
+        serde_json::to_string(&self.body(bytes_written)).unwrap_or_default()
+    }
+}
+
+impl fmt::Display for OtaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OtaError::MissingLength => write!(f, "Upload has no Content-Length"),
+            OtaError::BadRequest(e) => write!(f, "Bad request: {e}"),
+            OtaError::InvalidImage(e) => write!(f, "Invalid image: {e}"),
+            OtaError::Downgrade(e) => write!(f, "{e}"),
+            OtaError::Signature(e) => write!(f, "Signature check failed: {e}"),
+            OtaError::Checksum(e) => write!(f, "{e}"),
+            OtaError::Transfer(e) => write!(f, "Upload failed: {e}"),
+            OtaError::Download(e) => write!(f, "Download failed: {e}"),
+            OtaError::Flash(e) => write!(f, "Flash write failed: {e}"),
+            OtaError::NotFound => write!(
+                f,
+                "No such upload session, sessions end on a reboot so start a new one"
+            ),
+            OtaError::Offset(offset) => write!(f, "Chunk must continue from offset {offset}"),
+            OtaError::SlotNotFound(label) => write!(f, "No partition labelled {label:?}"),
+            OtaError::SlotNotBootable(e) => write!(f, "{e}"),
+            OtaError::NothingStaged => write!(f, "No update is waiting to be applied"),
+            OtaError::NoUpdate(e) => write!(f, "{e}"),
+            OtaError::Busy(current) => write!(
+                f,
+                "Another update is in progress, {:?} at {} bytes",
+                current.state, current.bytes_written
+            ),
+            OtaError::Unauthorized => write!(f, "Admin credential required"),
+            OtaError::SetupRequired => write!(f, "Set the admin credential at /setup first"),
+            OtaError::CredentialLocked => write!(
+                f,
+                "Stored admin credential is unreadable, erase NVS over serial to set a new one"
+            ),
+            OtaError::Internal(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for OtaError {}
+
+// An OtaError passed through anyhow, e.g. out of a multipart callback, keeps its type
+impl From<anyhow::Error> for OtaError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<OtaError>() {
+            Ok(e) => e,
+            Err(e) => OtaError::Internal(format!("{e:#}")),
+        }
+    }
+}
@@ -0,0 +1,88 @@
+// Pull mode, the device downloads the image itself
+//
+// POST /ota/fetch names a URL, or falls back to the configured one. The
+// response body goes through the same FirmwareWriter checks as a POST to
+// /ota; the firmware's ota.rs wraps the HTTP client response as a BodySource.
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use url::Url;
+
+use crate::error::OtaError;
+use crate::image::Chip;
+use crate::upload::{
+    content_encoding, receive_raw, BodySource, FirmwareWriter, SlotWriter, CONTENT_ENCODING,
+};
+use crate::verify::{parse_sha256_hex, Sha256Digest};
+use crate::version::RollbackPolicy;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct FetchRequest {
+    pub url: Option<String>,
+    pub sha256: Option<String>,
+    #[serde(default)]
+    pub save: bool,
+    /// Allow installing an older version
+    #[serde(default)]
+    pub force: bool,
+    /// now, later or at=<time> as for uploads, now when missing
+    pub reboot: Option<String>,
+}
+
+impl FetchRequest {
+    /// Falls back to the configured update URL when the request does not name one.
+    pub fn resolve(&self, configured: Option<&str>) -> Result<(String, Option<Sha256Digest>)> {
+        let url = match self.url.as_deref().or(configured) {
+            Some(url) => url,
+            None => return Err(anyhow!("No url given and no update url configured")),
+        };
+        let parsed = Url::parse(url)?;
+        if !matches!(parsed.scheme(), "http" | "https") {
+            return Err(anyhow!("Unsupported url scheme {}", parsed.scheme()));
+        }
+        let expected_sha256 = self.sha256.as_deref().map(parse_sha256_hex).transpose()?;
+        Ok((url.to_owned(), expected_sha256))
+    }
+}
+
+/// Streams a downloaded image into the update slot. `expected_len` is the file
+/// size an update manifest announced, checked against the decompressed
+/// download whether or not the response gave a length.
+pub fn receive_download<B: BodySource, S: SlotWriter>(
+    response: &mut B,
+    slot: S,
+    chip: Chip,
+    policy: RollbackPolicy,
+    public_key: &[u8; 32],
+    expected_sha256: Option<Sha256Digest>,
+    expected_len: Option<usize>,
+) -> Result<(), OtaError> {
+    let content_len = response.content_len().unwrap_or(0);
+    let compression = content_encoding(response.header(CONTENT_ENCODING))?;
+    // Compressed in transit the length no longer matches the file
+    if let (Some(expected), None) = (expected_len, compression) {
+        if content_len != 0 && content_len != expected {
+            return Err(OtaError::Download(format!(
+                "image is {content_len} bytes, expected {expected}"
+            )));
+        }
+    }
+
+    let mut writer = FirmwareWriter::new(slot, chip, content_len, policy);
+    if let Some(compression) = compression {
+        writer.expect_compression(compression);
+    }
+    let result = receive_raw(response, &mut writer)
+        .and_then(|_| check_len(writer.decoded(), expected_len))
+        .and_then(|_| writer.verify(expected_sha256, public_key));
+    writer.finalise(result)
+}
+
+fn check_len(len: usize, expected_len: Option<usize>) -> Result<(), OtaError> {
+    match expected_len {
+        Some(expected) if len != expected => Err(OtaError::Download(format!(
+            "image is {len} bytes, expected {expected}"
+        ))),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,131 @@
+// ESP application image header parsing
+//
+// Layout from esp_image_format.h / esp_app_format.h: a 24 byte image header,
+// the first segment header, then esp_app_desc_t at the start of the first
+// segment. Everything needed is in the first APP_HEADER_LEN bytes.
+
+use anyhow::{bail, Result};
+use std::fmt;
+
+pub const IMAGE_MAGIC: u8 = 0xE9;
+pub const APP_DESC_MAGIC: u32 = 0xABCD_5432;
+pub const APP_HEADER_LEN: usize = APP_DESC_OFFSET + 256;
+
+const IMAGE_HEADER_LEN: usize = 24;
+const SEGMENT_HEADER_LEN: usize = 8;
+const APP_DESC_OFFSET: usize = IMAGE_HEADER_LEN + SEGMENT_HEADER_LEN;
+const MAX_SEGMENTS: u8 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chip {
+    Esp32,
+    Esp32s2,
+    Esp32c3,
+    Esp32s3,
+    Esp32c2,
+    Esp32c6,
+    Esp32h2,
+    Unknown(u16),
+}
+
+impl From<u16> for Chip {
+    fn from(id: u16) -> Self {
+        match id {
+            0x0000 => Chip::Esp32,
+            0x0002 => Chip::Esp32s2,
+            0x0005 => Chip::Esp32c3,
+            0x0009 => Chip::Esp32s3,
+            0x000C => Chip::Esp32c2,
+            0x000D => Chip::Esp32c6,
+            0x0010 => Chip::Esp32h2,
+            id => Chip::Unknown(id),
+        }
+    }
+}
+
+impl fmt::Display for Chip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Chip::Esp32 => write!(f, "ESP32"),
+            Chip::Esp32s2 => write!(f, "ESP32-S2"),
+            Chip::Esp32c3 => write!(f, "ESP32-C3"),
+            Chip::Esp32s3 => write!(f, "ESP32-S3"),
+            Chip::Esp32c2 => write!(f, "ESP32-C2"),
+            Chip::Esp32c6 => write!(f, "ESP32-C6"),
+            Chip::Esp32h2 => write!(f, "ESP32-H2"),
+            Chip::Unknown(id) => write!(f, "unknown chip 0x{id:04x}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppImageInfo {
+    pub chip: Chip,
+    pub segment_count: u8,
+    pub entry_addr: u32,
+    pub hash_appended: bool,
+    pub secure_version: u32,
+    pub version: String,
+    pub project_name: String,
+    pub time: String,
+    pub date: String,
+    pub idf_ver: String,
+}
+
+impl AppImageInfo {
+    /// Parses the image and app descriptor headers, `data` must hold at least `APP_HEADER_LEN` bytes.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < APP_HEADER_LEN {
+            bail!(
+                "Image too short for an ESP app header: {} of {APP_HEADER_LEN} bytes",
+                data.len()
+            );
+        }
+        if data[0] != IMAGE_MAGIC {
+            bail!(
+                "Not an ESP app image: magic 0x{:02x}, expected 0x{IMAGE_MAGIC:02x}",
+                data[0]
+            );
+        }
+        let segment_count = data[1];
+        if segment_count == 0 || segment_count > MAX_SEGMENTS {
+            bail!("ESP app image has an invalid segment count {segment_count}");
+        }
+        let desc = &data[APP_DESC_OFFSET..APP_HEADER_LEN];
+        let desc_magic = u32_le(desc, 0);
+        if desc_magic != APP_DESC_MAGIC {
+            bail!("ESP app image has no app descriptor (magic 0x{desc_magic:08x})");
+        }
+        Ok(Self {
+            chip: Chip::from(u16::from_le_bytes([data[12], data[13]])),
+            segment_count,
+            entry_addr: u32_le(data, 4),
+            hash_appended: data[23] == 1,
+            secure_version: u32_le(desc, 4),
+            version: c_str(&desc[16..48]),
+            project_name: c_str(&desc[48..80]),
+            time: c_str(&desc[80..96]),
+            date: c_str(&desc[96..112]),
+            idf_ver: c_str(&desc[112..144]),
+        })
+    }
+
+    pub fn check_chip(&self, expected: Chip) -> Result<()> {
+        if self.chip != expected {
+            bail!(
+                "Image is built for {}, this device is {expected}",
+                self.chip
+            );
+        }
+        Ok(())
+    }
+}
+
+fn u32_le(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+fn c_str(field: &[u8]) -> String {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
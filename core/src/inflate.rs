@@ -0,0 +1,224 @@
+// Streaming gzip / zlib / raw deflate decoding for compressed uploads
+//
+// Input arrives in whatever chunks the transport delivers. miniz_oxide does
+// the inflating and keeps its own 32 KiB window, zlib's header and Adler-32
+// included. gzip's header and CRC-32 trailer are handled here around a raw
+// deflate stream, only a partial header or trailer is ever buffered.
+
+use anyhow::{bail, Result};
+use miniz_oxide::inflate::stream::{inflate, InflateState};
+use miniz_oxide::{DataFormat, MZError, MZFlush, MZStatus};
+
+/// Bytes needed to recognise a gzip or zlib header
+pub const DETECT_LEN: usize = 2;
+
+const OUT_BLOCK: usize = 4096;
+// A gzip header with a file name or comment longer than this is refused
+const MAX_HEADER: usize = 4096;
+const GZIP_TRAILER: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zlib,
+    /// Raw deflate, only recognised through Content-Encoding
+    Deflate,
+}
+
+impl Compression {
+    /// Recognises the gzip and zlib headers from the first `DETECT_LEN` bytes.
+    pub fn detect(head: &[u8]) -> Option<Self> {
+        match head {
+            [0x1f, 0x8b, ..] => Some(Compression::Gzip),
+            [cmf, flg, ..]
+                if cmf & 0x0f == 8
+                    && cmf >> 4 <= 7
+                    && (u16::from(*cmf) << 8 | u16::from(*flg)) % 31 == 0 =>
+            {
+                Some(Compression::Zlib)
+            }
+            _ => None,
+        }
+    }
+
+    /// `None` for identity. HTTP's "deflate" is meant to be zlib, raw deflate is taken as well.
+    pub fn from_content_encoding(value: &str) -> Result<Option<Self>> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "identity" => Ok(None),
+            "gzip" | "x-gzip" => Ok(Some(Compression::Gzip)),
+            "deflate" => Ok(Some(Compression::Deflate)),
+            other => bail!("Unsupported Content-Encoding {other:?}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum State {
+    /// gzip header bytes so far
+    Header(Vec<u8>),
+    Body,
+    /// gzip trailer bytes so far
+    Trailer(Vec<u8>),
+    Done,
+}
+
+/// Decompresses a stream pushed in arbitrary chunks, handing the output to `out`.
+pub struct Inflater {
+    format: Compression,
+    state: State,
+    inflate: Box<InflateState>,
+    // For the gzip trailer
+    crc: crc32fast::Hasher,
+    total_out: usize,
+    out_buf: Vec<u8>,
+}
+
+impl Inflater {
+    pub fn new(format: Compression) -> Self {
+        let (state, data_format) = match format {
+            Compression::Gzip => (State::Header(Vec::new()), DataFormat::Raw),
+            Compression::Zlib => (State::Body, DataFormat::Zlib),
+            Compression::Deflate => (State::Body, DataFormat::Raw),
+        };
+        Self {
+            format,
+            state,
+            inflate: InflateState::new_boxed(data_format),
+            crc: crc32fast::Hasher::new(),
+            total_out: 0,
+            out_buf: vec![0; OUT_BLOCK],
+        }
+    }
+
+    /// Decompressed bytes so far
+    pub fn total_out(&self) -> usize {
+        self.total_out
+    }
+
+    pub fn push(
+        &mut self,
+        mut input: &[u8],
+        mut out: impl FnMut(&[u8]) -> Result<()>,
+    ) -> Result<()> {
+        while !input.is_empty() {
+            match &mut self.state {
+                State::Header(header) => {
+                    let take = (MAX_HEADER + 1 - header.len()).min(input.len());
+                    header.extend_from_slice(&input[..take]);
+                    match gzip_header_len(header)? {
+                        Some(len) => {
+                            // Whatever followed the header was taken as well, hand it back
+                            input = &input[take - (header.len() - len)..];
+                            self.state = State::Body;
+                        }
+                        None if header.len() > MAX_HEADER => bail!("Compression header too long"),
+                        None => input = &input[take..],
+                    }
+                }
+                State::Body => input = self.inflate_some(input, &mut out)?,
+                State::Trailer(trailer) => {
+                    let take = (GZIP_TRAILER - trailer.len()).min(input.len());
+                    trailer.extend_from_slice(&input[..take]);
+                    input = &input[take..];
+                    if trailer.len() == GZIP_TRAILER {
+                        let crc =
+                            u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+                        let size =
+                            u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+                        if crc != self.crc.clone().finalize() {
+                            bail!("Bad compressed data: gzip CRC-32 mismatch");
+                        }
+                        if size != self.total_out as u32 {
+                            bail!("Bad compressed data: gzip size mismatch");
+                        }
+                        self.state = State::Done;
+                    }
+                }
+                State::Done => bail!("Data after the end of the compressed image"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails unless the stream and its checksum trailer were complete.
+    pub fn finish(&self) -> Result<()> {
+        if self.state != State::Done {
+            bail!(
+                "Compressed image ends early after {} bytes out",
+                self.total_out
+            );
+        }
+        Ok(())
+    }
+
+    // Inflates until the input is used up or the deflate stream ends, returns what is left
+    fn inflate_some<'a>(
+        &mut self,
+        mut input: &'a [u8],
+        out: &mut impl FnMut(&[u8]) -> Result<()>,
+    ) -> Result<&'a [u8]> {
+        loop {
+            let result = inflate(&mut self.inflate, input, &mut self.out_buf, MZFlush::None);
+            input = &input[result.bytes_consumed..];
+            let written = &self.out_buf[..result.bytes_written];
+            if !written.is_empty() {
+                if self.format == Compression::Gzip {
+                    self.crc.update(written);
+                }
+                self.total_out += written.len();
+                out(written)?;
+            }
+            match result.status {
+                Ok(MZStatus::StreamEnd) => {
+                    self.state = match self.format {
+                        Compression::Gzip => State::Trailer(Vec::with_capacity(GZIP_TRAILER)),
+                        _ => State::Done,
+                    };
+                    return Ok(input);
+                }
+                Ok(_) if result.bytes_consumed + result.bytes_written > 0 => (),
+                // Waiting for more input
+                Ok(_) | Err(MZError::Buf) if input.is_empty() => return Ok(input),
+                _ => bail!(
+                    "Bad compressed data at {} bytes out: not a valid {:?} stream",
+                    self.total_out,
+                    self.format
+                ),
+            }
+        }
+    }
+}
+
+// Length of a complete gzip header, `None` until it is all there
+fn gzip_header_len(header: &[u8]) -> Result<Option<usize>> {
+    if header.len() < 10 {
+        return Ok(None);
+    }
+    let (method, flags) = (header[2], header[3]);
+    if header[..2] != [0x1f, 0x8b] || method != 8 || flags & 0xe0 != 0 {
+        bail!("Bad compressed data: not a gzip deflate stream");
+    }
+    let mut len = 10;
+    if flags & 0x04 != 0 {
+        match header.get(len..len + 2) {
+            Some(extra) => len += 2 + u16::from_le_bytes([extra[0], extra[1]]) as usize,
+            None => return Ok(None),
+        }
+    }
+    // File name and comment, both zero terminated
+    for flag in [0x08, 0x10] {
+        if flags & flag != 0 {
+            match header
+                .get(len..)
+                .and_then(|rest| rest.iter().position(|b| *b == 0))
+            {
+                Some(end) => len += end + 1,
+                None => return Ok(None),
+            }
+        }
+    }
+    if flags & 0x02 != 0 {
+        len += 2;
+    }
+    Ok((header.len() >= len).then_some(len))
+}
@@ -0,0 +1,19 @@
+// Everything about an update that does not touch the hardware, so it builds
+// and tests on any host. The firmware crate wires it to ESP-IDF.
+
+pub mod configuration;
+pub mod credential;
+pub mod delta;
+pub mod error;
+pub mod fetch;
+pub mod image;
+pub mod inflate;
+pub mod manifest;
+pub mod multipart;
+pub mod ota_lock;
+pub mod reboot;
+pub mod session;
+pub mod status;
+pub mod upload;
+pub mod verify;
+pub mod version;
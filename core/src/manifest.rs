@@ -0,0 +1,109 @@
+// Update server manifest, polled by updater.rs
+//
+//   {"version": "0.2.0", "url": "ota.signed.bin", "size": 1173192,
+//    "sha256": "<hex>", "min_version": "0.1.0", "hardware": "ESP32-C3", "channel": "stable"}
+//
+// Only version and url are required, url may be relative to the manifest.
+// min_version is the oldest running version allowed to update straight to it.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::verify::parse_sha256_hex;
+use crate::version::Version;
+
+// A manifest is a few hundred bytes, anything much bigger is not one
+pub const MAX_MANIFEST_LEN: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: String,
+    pub url: String,
+    pub size: Option<usize>,
+    pub sha256: Option<String>,
+    pub min_version: Option<String>,
+    pub hardware: Option<String>,
+    pub channel: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Check {
+    UpToDate,
+    /// A newer build this device cannot take
+    Skipped {
+        reason: String,
+    },
+    Available {
+        manifest: Manifest,
+    },
+}
+
+impl Manifest {
+    /// Parses and validates a manifest, resolving its url against where it came from.
+    pub fn parse(json: &[u8], manifest_url: &str) -> Result<Self> {
+        if json.len() > MAX_MANIFEST_LEN {
+            return Err(anyhow!("Manifest is over {MAX_MANIFEST_LEN} bytes"));
+        }
+        let mut manifest: Manifest = serde_json::from_slice(json)?;
+        let url = Url::parse(manifest_url)?.join(&manifest.url)?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(anyhow!("Unsupported url scheme {}", url.scheme()));
+        }
+        manifest.url = url.to_string();
+        if let Some(sha256) = &manifest.sha256 {
+            parse_sha256_hex(sha256)?;
+        }
+        for version in [Some(&manifest.version), manifest.min_version.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            if Version::parse(version).is_none() {
+                return Err(anyhow!("Bad version {version:?} in manifest"));
+            }
+        }
+        Ok(manifest)
+    }
+
+    pub fn check(&self, running_version: &str, hardware: &str, channel: &str) -> Check {
+        let skipped = |reason: String| Check::Skipped { reason };
+        let newer = match (
+            Version::parse(&self.version),
+            Version::parse(running_version),
+        ) {
+            (Some(offered), Some(running)) => offered > running,
+            _ => false,
+        };
+        if !newer {
+            return Check::UpToDate;
+        }
+        if let Some(wanted) = &self.hardware {
+            if !wanted.eq_ignore_ascii_case(hardware) {
+                return skipped(format!(
+                    "{} is built for {wanted}, this is {hardware}",
+                    self.version
+                ));
+            }
+        }
+        if let Some(wanted) = &self.channel {
+            if wanted != channel {
+                return skipped(format!(
+                    "{} is on channel {wanted}, this device follows {channel}",
+                    self.version
+                ));
+            }
+        }
+        if let Some(min_version) = &self.min_version {
+            if Version::parse(running_version) < Version::parse(min_version) {
+                return skipped(format!(
+                    "{} needs at least {min_version} running, this is {running_version}",
+                    self.version
+                ));
+            }
+        }
+        Check::Available {
+            manifest: self.clone(),
+        }
+    }
+}
@@ -0,0 +1,252 @@
+// Incremental multipart/form-data parser
+//
+// Fed with whatever `req.reader().read()` returns, in any chunk size. Partial
+// boundaries and part headers are carried over between calls so nothing that
+// straddles two reads ends up in the payload.
+
+use anyhow::{anyhow, bail, Result};
+
+const CRLF: &[u8] = b"\r\n";
+const MAX_HEADER_LEN: usize = 2048;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Part {
+    pub name: Option<String>,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+}
+
+impl Part {
+    pub fn is_file(&self) -> bool {
+        self.filename.is_some()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Event<'a> {
+    PartStart(&'a Part),
+    Data(&'a [u8]),
+    PartEnd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Preamble,
+    Boundary,
+    Headers,
+    Body,
+    Done,
+}
+
+pub struct MultipartParser {
+    delimiter: Vec<u8>,
+    state: State,
+    buf: Vec<u8>,
+    part: Part,
+}
+
+impl MultipartParser {
+    pub fn new(boundary: &str) -> Self {
+        let mut delimiter = Vec::with_capacity(boundary.len() + 4);
+        delimiter.extend_from_slice(b"\r\n--");
+        delimiter.extend_from_slice(boundary.as_bytes());
+        Self {
+            delimiter,
+            state: State::Preamble,
+            // The first delimiter is allowed to start the body without a leading CRLF
+            buf: CRLF.to_vec(),
+            part: Part::default(),
+        }
+    }
+
+    pub fn feed(
+        &mut self,
+        input: &[u8],
+        mut on_event: impl FnMut(Event) -> Result<()>,
+    ) -> Result<()> {
+        if self.state == State::Done {
+            return Ok(());
+        }
+        self.buf.extend_from_slice(input);
+
+        let mut pos = 0;
+        loop {
+            let pending = &self.buf[pos..];
+            match self.state {
+                State::Preamble => match twoway::find_bytes(pending, &self.delimiter) {
+                    Some(at) => {
+                        pos += at + self.delimiter.len();
+                        self.state = State::Boundary;
+                    }
+                    None => {
+                        pos += pending.len().saturating_sub(self.delimiter.len() - 1);
+                        break;
+                    }
+                },
+                State::Boundary => {
+                    if pending.len() < 2 {
+                        break;
+                    }
+                    if pending.starts_with(b"--") {
+                        self.state = State::Done;
+                        pos = self.buf.len();
+                        break;
+                    }
+                    // Transport padding is allowed between the boundary and its CRLF. The
+                    // limits hold for the whole line, however the body is chunked.
+                    match twoway::find_bytes(pending, CRLF) {
+                        Some(at)
+                            if at + CRLF.len() <= MAX_HEADER_LEN
+                                && pending[..at].iter().all(|b| *b == b' ' || *b == b'\t') =>
+                        {
+                            pos += at + CRLF.len();
+                            self.state = State::Headers;
+                        }
+                        None if pending.len() < MAX_HEADER_LEN => break,
+                        _ => bail!("Malformed multipart boundary line"),
+                    }
+                }
+                State::Headers => {
+                    // A part without headers is allowed, it is plain text (RFC 2046)
+                    let skip = if pending.starts_with(CRLF) {
+                        self.part = Part::default();
+                        CRLF.len()
+                    } else {
+                        match twoway::find_bytes(pending, b"\r\n\r\n") {
+                            Some(at) if at + 4 <= MAX_HEADER_LEN => {
+                                self.part = parse_part_headers(&pending[..at])?;
+                                at + 4
+                            }
+                            None if pending.len() < MAX_HEADER_LEN => break,
+                            _ => bail!("Multipart part headers exceed {MAX_HEADER_LEN} bytes"),
+                        }
+                    };
+                    pos += skip;
+                    self.state = State::Body;
+                    on_event(Event::PartStart(&self.part))?;
+                }
+                State::Body => match twoway::find_bytes(pending, &self.delimiter) {
+                    Some(at) => {
+                        if at > 0 {
+                            on_event(Event::Data(&pending[..at]))?;
+                        }
+                        on_event(Event::PartEnd)?;
+                        pos += at + self.delimiter.len();
+                        self.state = State::Boundary;
+                    }
+                    None => {
+                        // Hold back anything that could be the start of a delimiter
+                        let safe = pending.len().saturating_sub(self.delimiter.len() - 1);
+                        if safe > 0 {
+                            on_event(Event::Data(&pending[..safe]))?;
+                        }
+                        pos += safe;
+                        break;
+                    }
+                },
+                State::Done => {
+                    pos = self.buf.len();
+                    break;
+                }
+            }
+        }
+        self.buf.drain(..pos);
+        Ok(())
+    }
+
+    pub fn finish(&self) -> Result<()> {
+        match self.state {
+            State::Done => Ok(()),
+            State::Preamble => Err(anyhow!("Multipart body contained no parts")),
+            _ => Err(anyhow!("Multipart body ended before the closing boundary")),
+        }
+    }
+}
+
+pub fn is_multipart(content_type: &str) -> bool {
+    let mime = content_type.trim_start().get(..10);
+    matches!(mime, Some(mime) if mime.eq_ignore_ascii_case("multipart/"))
+}
+
+/// Pulls the boundary parameter out of a `multipart/*` Content-Type header, the
+/// same types `is_multipart` routes here.
+pub fn boundary_from_content_type(content_type: &str) -> Option<&str> {
+    if !is_multipart(content_type) {
+        return None;
+    }
+    let mut params = content_type.split(';');
+    params.next()?;
+    params
+        .filter_map(|p| p.split_once('='))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, v)| v.trim().trim_matches('"'))
+        .filter(|v| !v.is_empty() && v.len() <= 70)
+}
+
+fn parse_part_headers(raw: &[u8]) -> Result<Part> {
+    let raw = std::str::from_utf8(raw).map_err(|_| anyhow!("Multipart headers are not UTF-8"))?;
+    let mut part = Part::default();
+    for line in raw.split("\r\n") {
+        let (name, value) = match line.split_once(':') {
+            Some(v) => v,
+            None => bail!("Malformed multipart header: {line}"),
+        };
+        let value = value.trim();
+        if name.trim().eq_ignore_ascii_case("content-disposition") {
+            for (key, val) in header_params(value) {
+                match key.to_ascii_lowercase().as_str() {
+                    "name" => part.name = Some(val),
+                    "filename" => part.filename = Some(val),
+                    _ => (),
+                }
+            }
+        } else if name.trim().eq_ignore_ascii_case("content-type") {
+            part.content_type = Some(value.to_owned());
+        }
+    }
+    Ok(part)
+}
+
+// `form-data; name="update"; filename="ota.bin"` -> [(name, update), (filename, ota.bin)]
+fn header_params(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut rest = match value.split_once(';') {
+        Some((_, rest)) => rest,
+        None => return params,
+    };
+    loop {
+        rest = rest.trim_start_matches([' ', '\t', ';']);
+        let (key, after) = match rest.split_once('=') {
+            Some(v) => v,
+            None => break,
+        };
+        let key = key.trim().to_owned();
+        let after = after.trim_start();
+        let (val, remaining) = if let Some(quoted) = after.strip_prefix('"') {
+            let mut val = String::new();
+            let mut chars = quoted.char_indices();
+            let mut end = quoted.len();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => {
+                        if let Some((_, escaped)) = chars.next() {
+                            val.push(escaped);
+                        }
+                    }
+                    '"' => {
+                        end = i + 1;
+                        break;
+                    }
+                    _ => val.push(c),
+                }
+            }
+            (val, &quoted[end..])
+        } else {
+            let end = after.find(';').unwrap_or(after.len());
+            (after[..end].trim().to_owned(), &after[end..])
+        };
+        params.push((key, val));
+        rest = remaining;
+    }
+    params
+}
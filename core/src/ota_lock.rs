@@ -0,0 +1,44 @@
+// One update at a time
+//
+// There is a single update slot and a single progress on GET /ota/status, so
+// uploads, pull updates, upload sessions and data images take this lock before
+// touching either. A second update gets 409 busy with the running one's
+// progress. Upload sessions hold it between requests until they are committed
+// or expire, see the firmware's ota_session.rs.
+
+use lazy_static::lazy_static;
+use log::info;
+use std::sync::Mutex;
+
+use crate::error::OtaError;
+use crate::status;
+
+lazy_static! {
+    static ref HOLDER: Mutex<Option<&'static str>> = Mutex::new(None);
+}
+
+/// Released on drop.
+#[derive(Debug)]
+pub struct OtaLock {
+    what: &'static str,
+}
+
+impl OtaLock {
+    /// `what` names the update in the log.
+    pub fn acquire(what: &'static str) -> Result<Self, OtaError> {
+        let mut holder = HOLDER.lock().unwrap();
+        if let Some(current) = *holder {
+            info!("Refusing {what}, {current} in progress");
+            return Err(OtaError::Busy(Box::new(status::snapshot())));
+        }
+        *holder = Some(what);
+        Ok(Self { what })
+    }
+}
+
+impl Drop for OtaLock {
+    fn drop(&mut self) {
+        info!("{} finished, update slot free", self.what);
+        *HOLDER.lock().unwrap() = None;
+    }
+}
@@ -0,0 +1,129 @@
+// When to boot into a flashed image
+//
+// A successful update, rollback or boot selection takes
+// `reboot=now|later|at=<time>`. The main loop polls `due` and reboots through
+// its orderly shutdown, stopping httpd and Wi-Fi first. `later` leaves the
+// image staged until POST /api/ota/apply.
+
+use lazy_static::lazy_static;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::error::OtaError;
+
+pub const REBOOT_PARAM: &str = "reboot";
+
+// Lets the HTTP response go out before httpd is stopped
+const GRACE: Duration = Duration::from_secs(2);
+// Anything earlier means SNTP has not set the clock yet
+const CLOCK_SET_AFTER: u64 = 1_600_000_000;
+/// The furthest ahead a reboot can be scheduled
+pub const MAX_DELAY: Duration = Duration::from_secs(30 * 24 * 3600);
+
+lazy_static! {
+    static ref REBOOT_AT: Mutex<Option<Instant>> = Mutex::new(None);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebootWhen {
+    Now,
+    /// Wait for POST /api/ota/apply
+    Later,
+    At(Instant),
+}
+
+impl RebootWhen {
+    /// `reboot` from a query string, `Now` when absent.
+    pub fn from_query(query: &str) -> Result<Self, OtaError> {
+        match url::form_urlencoded::parse(query.as_bytes()).find(|(k, _)| k == REBOOT_PARAM) {
+            Some((_, value)) => Self::parse(&value),
+            None => Ok(RebootWhen::Now),
+        }
+    }
+
+    /// `now`, `later`, `at=<unix time>` or `at=+<seconds from now>`, at most `MAX_DELAY` ahead
+    pub fn parse(value: &str) -> Result<Self, OtaError> {
+        let unix_now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Self::parse_at(value, Instant::now(), unix_now)
+    }
+
+    pub fn parse_at(value: &str, now: Instant, unix_now: u64) -> Result<Self, OtaError> {
+        let bad = |reason: &str| {
+            OtaError::BadRequest(format!(
+                "reboot={value}: {reason}, use now, later or at=<time>"
+            ))
+        };
+        let time = match value.trim() {
+            "now" => return Ok(RebootWhen::Now),
+            "later" => return Ok(RebootWhen::Later),
+            other => other
+                .strip_prefix("at=")
+                .ok_or_else(|| bad("unknown value"))?,
+        };
+        let delay = match time.strip_prefix('+') {
+            Some(secs) => secs.parse::<u64>().map_err(|_| bad("bad seconds"))?,
+            None => {
+                let at = time.parse::<u64>().map_err(|_| bad("bad unix time"))?;
+                if unix_now < CLOCK_SET_AFTER {
+                    return Err(bad("the device clock is not set yet, give at=+<seconds>"));
+                }
+                at.saturating_sub(unix_now)
+            }
+        };
+        let out_of_range = || OtaError::BadRequest("reboot: time out of range".to_string());
+        let delay = Duration::from_secs(delay);
+        if delay > MAX_DELAY {
+            return Err(out_of_range());
+        }
+        now.checked_add(delay)
+            .map(RebootWhen::At)
+            .ok_or_else(out_of_range)
+    }
+}
+
+impl fmt::Display for RebootWhen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RebootWhen::Now => write!(f, "Rebooting in {} seconds", GRACE.as_secs()),
+            RebootWhen::Later => write!(f, "Reboot pending, POST /api/ota/apply to activate"),
+            RebootWhen::At(at) => write!(
+                f,
+                "Rebooting in {} seconds",
+                at.saturating_duration_since(Instant::now())
+                    .max(GRACE)
+                    .as_secs()
+            ),
+        }
+    }
+}
+
+/// `Now` and `At` replace any earlier schedule, `Later` leaves a pending reboot alone.
+pub fn schedule(when: RebootWhen) {
+    let soonest = Instant::now() + GRACE;
+    let deadline = match when {
+        RebootWhen::Now => soonest,
+        RebootWhen::Later => return,
+        RebootWhen::At(at) => at.max(soonest),
+    };
+    *REBOOT_AT.lock().unwrap() = Some(deadline);
+}
+
+/// Forgets a pending reboot, what a restart does to it.
+pub fn cancel() {
+    *REBOOT_AT.lock().unwrap() = None;
+}
+
+pub fn due() -> bool {
+    matches!(*REBOOT_AT.lock().unwrap(), Some(at) if Instant::now() >= at)
+}
+
+/// Time left until the scheduled reboot, `None` when there is none.
+pub fn reboot_in() -> Option<Duration> {
+    REBOOT_AT
+        .lock()
+        .unwrap()
+        .map(|at| at.saturating_duration_since(Instant::now()))
+}
@@ -0,0 +1,73 @@
+// Resumable upload requests and the byte ranges they carry, see the
+// firmware's ota_session.rs for the routes
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SessionRequest {
+    pub size: Option<usize>,
+    pub sha256: Option<String>,
+    /// Allow installing an older version
+    #[serde(default)]
+    pub force: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub offset: usize,
+    pub size: Option<usize>,
+}
+
+/// `bytes <start>-<end>/<size>`, size may be `*`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentRange {
+    pub start: usize,
+    pub end: usize,
+    pub size: Option<usize>,
+}
+
+impl ContentRange {
+    pub fn parse(header: &str) -> Result<Self, String> {
+        let range = header
+            .trim()
+            .strip_prefix("bytes ")
+            .ok_or_else(|| format!("{header:?} is not a byte range"))?;
+        let (span, size) = range
+            .split_once('/')
+            .ok_or_else(|| format!("{header:?} has no size"))?;
+        let (start, end) = span
+            .split_once('-')
+            .ok_or_else(|| format!("{header:?} has no end"))?;
+        let number = |v: &str| {
+            v.trim()
+                .parse::<usize>()
+                .map_err(|_| format!("{header:?} is not a valid range"))
+        };
+        let range = ContentRange {
+            start: number(start)?,
+            end: number(end)?,
+            size: match size.trim() {
+                "*" => None,
+                size => Some(number(size)?),
+            },
+        };
+        if range.end < range.start || matches!(range.size, Some(size) if range.end >= size) {
+            return Err(format!("{header:?} is not a valid range"));
+        }
+        Ok(range)
+    }
+
+    pub fn byte_count(&self) -> usize {
+        self.end - self.start + 1
+    }
+}
+
+/// Splits `/ota/session/<id>[/<action>][?query]` into id and action.
+pub fn session_route(uri: &str) -> Option<(&str, Option<&str>)> {
+    let path = uri.split('?').next()?;
+    let rest = path.strip_prefix("/ota/session/")?;
+    let mut parts = rest.splitn(2, '/');
+    let id = parts.next().filter(|id| !id.is_empty())?;
+    Some((id, parts.next().filter(|action| !action.is_empty())))
+}
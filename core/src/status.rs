@@ -0,0 +1,117 @@
+// Shared OTA progress, served as JSON on GET /ota/status
+//
+// Updated by the firmware writer whichever way the image arrives. `seq` bumps
+// on every change so a client can wait for the next one instead of spinning.
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::fmt;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    static ref OTA_STATUS: (Mutex<OtaStatus>, Condvar) =
+        (Mutex::new(OtaStatus::default()), Condvar::new());
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OtaState {
+    #[default]
+    Idle,
+    Receiving,
+    Verifying,
+    Complete,
+    Failed,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct OtaStatus {
+    pub seq: u32,
+    pub state: OtaState,
+    pub bytes_written: usize,
+    pub total: Option<usize>,
+    pub percent: Option<f32>,
+    /// Bytes per second since the update started
+    pub throughput: f32,
+    pub eta_secs: Option<f32>,
+    pub last_error: Option<String>,
+    #[serde(skip)]
+    started: Option<Instant>,
+}
+
+impl OtaStatus {
+    pub fn begin(&mut self, total: Option<usize>, now: Instant) {
+        *self = OtaStatus {
+            seq: self.seq,
+            state: OtaState::Receiving,
+            total: total.filter(|t| *t > 0),
+            percent: total.filter(|t| *t > 0).map(|_| 0.0),
+            started: Some(now),
+            ..Default::default()
+        };
+    }
+
+    pub fn progress(&mut self, bytes_written: usize, now: Instant) {
+        self.bytes_written = bytes_written;
+        let elapsed = self
+            .started
+            .map_or(0.0, |started| (now - started).as_secs_f32());
+        if elapsed > 0.0 {
+            self.throughput = bytes_written as f32 / elapsed;
+        }
+        if let Some(total) = self.total {
+            self.percent = Some((bytes_written as f32 / total as f32 * 100.0).min(100.0));
+            if self.throughput > 0.0 {
+                self.eta_secs = Some(total.saturating_sub(bytes_written) as f32 / self.throughput);
+            }
+        }
+    }
+
+    pub fn verifying(&mut self) {
+        self.state = OtaState::Verifying;
+        self.eta_secs = None;
+    }
+
+    pub fn complete(&mut self) {
+        self.state = OtaState::Complete;
+        self.percent = Some(100.0);
+        self.eta_secs = None;
+    }
+
+    pub fn fail(&mut self, error: String) {
+        self.state = OtaState::Failed;
+        self.eta_secs = None;
+        self.last_error = Some(error);
+    }
+}
+
+pub fn update(f: impl FnOnce(&mut OtaStatus)) {
+    let (status, changed) = &*OTA_STATUS;
+    let mut status = status.lock().unwrap();
+    f(&mut status);
+    status.seq = status.seq.wrapping_add(1);
+    changed.notify_all();
+}
+
+pub fn snapshot() -> OtaStatus {
+    OTA_STATUS.0.lock().unwrap().clone()
+}
+
+/// Returns as soon as `seq` has moved on from `seen`, or after `timeout`.
+pub fn wait_for_change(seen: u32, timeout: Duration) -> OtaStatus {
+    let (status, changed) = &*OTA_STATUS;
+    let status = status.lock().unwrap();
+    let (status, _) = changed
+        .wait_timeout_while(status, timeout, |status| status.seq == seen)
+        .unwrap();
+    status.clone()
+}
+
+/// Records how an update ended.
+pub fn finish<T, E: fmt::Display>(result: &Result<T, E>) {
+    match result {
+        Ok(_) => update(|s| s.complete()),
+        Err(e) => update(|s| s.fail(e.to_string())),
+    }
+}
@@ -0,0 +1,540 @@
+// The upload pipeline, free of ESP-IDF
+//
+// A firmware upload comes from a `BodySource` and goes to a `SlotWriter`. On
+// the device those are the HTTP request and the next OTA partition, see the
+// firmware's ota.rs; tools/ota-host runs the same code against files on a PC.
+
+use anyhow::Result;
+use log::info;
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+use crate::delta::{DeltaApplier, SlotReader, PATCH_MAGIC};
+use crate::error::OtaError;
+use crate::image::{AppImageInfo, Chip, APP_HEADER_LEN};
+use crate::inflate::{Compression, Inflater, DETECT_LEN};
+use crate::multipart::{boundary_from_content_type, is_multipart, Event, MultipartParser};
+use crate::status;
+use crate::verify::{
+    parse_sha256_hex, sha256_from_query, to_hex, verify_sha256, verify_signature, FirmwareHasher,
+    Sha256Digest, TrailerSplitter, SHA256_FIELD, SHA256_HEADER,
+};
+use crate::version::RollbackPolicy;
+
+/// An upload as it arrives: headers, query and a body read in chunks.
+pub trait BodySource {
+    fn header(&self, name: &str) -> Option<&str>;
+    fn query_string(&self) -> String;
+    fn content_len(&self) -> Option<usize>;
+    /// 0 at the end of the body
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, OtaError>;
+}
+
+/// The partition an update is written to.
+pub trait SlotWriter {
+    /// The image delta patches are applied against, the running slot on the device
+    type Running: SlotReader;
+
+    /// Size of the slot in bytes
+    fn capacity(&self) -> usize;
+    /// Erases the slot, called once the image header checks out
+    fn begin(&mut self) -> Result<()>;
+    fn write(&mut self, data: &[u8]) -> Result<()>;
+    /// Discards a begun update
+    fn abort(&mut self) -> Result<()>;
+    /// Validates the image and makes it the next boot
+    fn complete(&mut self) -> Result<()>;
+    fn running(&self) -> Result<Self::Running>;
+}
+
+/// Everything a POST to /ota does between taking the request and answering it.
+pub fn receive_upload<B: BodySource, S: SlotWriter>(
+    body: &mut B,
+    slot: S,
+    chip: Chip,
+    mut policy: RollbackPolicy,
+    public_key: &[u8; 32],
+) -> Result<(), OtaError> {
+    let content_len = match body.content_len() {
+        Some(len) if len > 0 => len,
+        _ => return Err(OtaError::MissingLength),
+    };
+
+    let boundary = multipart_boundary(body)?;
+    match &boundary {
+        Some(boundary) => info!("Using boundary: {}", boundary),
+        None => info!("Raw firmware upload"),
+    }
+    let expected_sha256 = match body.header(SHA256_HEADER) {
+        Some(hex) => Some(parse_sha256_hex(hex).map_err(bad_request)?),
+        None => sha256_from_query(&body.query_string())
+            .transpose()
+            .map_err(bad_request)?,
+    };
+    policy.force |= force_from_query(&body.query_string());
+    let compression = content_encoding(body.header(CONTENT_ENCODING))?;
+    if boundary.is_some() && compression.is_some() {
+        return Err(bad_request(
+            "Content-Encoding on a form is not supported, put the compressed file in the form instead",
+        ));
+    }
+    let mut writer = FirmwareWriter::new(slot, chip, content_len, policy);
+    if let Some(compression) = compression {
+        writer.expect_compression(compression);
+    }
+
+    let received = match boundary {
+        Some(boundary) => receive_multipart(body, &boundary, &mut writer),
+        None => receive_raw(body, &mut writer).map(|_| None),
+    };
+    let result = received.and_then(|sha256_field| {
+        let expected_sha256 = match (expected_sha256, sha256_field) {
+            (Some(digest), _) => Some(digest),
+            (None, Some(hex)) => Some(parse_sha256_hex(&hex).map_err(bad_request)?),
+            (None, None) => None,
+        };
+        writer.verify(expected_sha256, public_key)
+    });
+    writer.finalise(result)
+}
+
+pub(crate) fn receive_raw<B: BodySource, S: SlotWriter>(
+    body: &mut B,
+    writer: &mut FirmwareWriter<S>,
+) -> Result<(), OtaError> {
+    let mut last_yield = Instant::now();
+    let mut buf = Box::new([0u8; 1440 * 3]);
+    loop {
+        let bytelen = body.read(&mut *buf)?;
+        if bytelen == 0 {
+            break;
+        }
+        writer.write(&buf[..bytelen])?;
+        // Give the idle task a turn now and then so the task watchdog stays quiet
+        if last_yield.elapsed() > Duration::from_millis(900) {
+            std::thread::sleep(Duration::from_millis(10));
+            last_yield = Instant::now();
+        }
+    }
+    Ok(())
+}
+
+fn receive_multipart<B: BodySource, S: SlotWriter>(
+    body: &mut B,
+    boundary: &str,
+    writer: &mut FirmwareWriter<S>,
+) -> Result<Option<String>, OtaError> {
+    let mut last_yield = Instant::now();
+    let mut parser = MultipartParser::new(boundary);
+    let mut in_firmware = false;
+    let mut firmware_seen = false;
+    let mut in_sha256_field = false;
+    let mut in_force_field = false;
+    let mut sha256_field = Vec::new();
+    let mut force_field = Vec::new();
+    let mut multipart_bytes_counter = 0;
+    let mut buf = Box::new([0u8; 1440 * 3]);
+    loop {
+        let bytelen = body.read(&mut *buf)?;
+        // Give the idle task a turn now and then so the task watchdog stays quiet
+        if last_yield.elapsed() > Duration::from_millis(900) {
+            std::thread::sleep(Duration::from_millis(10));
+            last_yield = Instant::now();
+        }
+        if bytelen == 0 {
+            break;
+        }
+        multipart_bytes_counter += bytelen;
+
+        parser
+            .feed(&buf[..bytelen], |event| {
+                match event {
+                    Event::PartStart(part) => {
+                        info!("Multipart part: {:?}", part);
+                        in_firmware = part.is_file() && !firmware_seen;
+                        in_sha256_field =
+                            !part.is_file() && part.name.as_deref() == Some(SHA256_FIELD);
+                        in_force_field =
+                            !part.is_file() && part.name.as_deref() == Some(FORCE_FIELD);
+                    }
+                    Event::Data(payload) if in_firmware => writer.write(payload)?,
+                    Event::Data(field) if in_force_field => {
+                        append_field(&mut force_field, field, FORCE_FIELD, 16)?
+                    }
+                    Event::Data(field) if in_sha256_field => {
+                        append_field(&mut sha256_field, field, SHA256_FIELD, 128)?
+                    }
+                    Event::Data(_) => (),
+                    Event::PartEnd => {
+                        // Only takes effect when sent ahead of the file
+                        if in_force_field && is_true(&String::from_utf8_lossy(&force_field)) {
+                            writer.allow_downgrade();
+                        }
+                        firmware_seen |= in_firmware;
+                        in_firmware = false;
+                        in_sha256_field = false;
+                        in_force_field = false;
+                    }
+                }
+                Ok(())
+            })
+            .map_err(|e| match e.downcast::<OtaError>() {
+                Ok(e) => e,
+                Err(e) => bad_request(format!(
+                    "multipart at {} bytes: {e}",
+                    multipart_bytes_counter
+                )),
+            })?;
+    }
+    parser.finish().map_err(bad_request)?;
+    if !firmware_seen {
+        return Err(bad_request("No firmware file found in multipart POST"));
+    }
+    Ok(Some(String::from_utf8_lossy(&sha256_field).into_owned()).filter(|s| !s.is_empty()))
+}
+
+// A form field arrives in as many Data events as the chunking makes
+fn append_field(buf: &mut Vec<u8>, data: &[u8], name: &str, max: usize) -> Result<()> {
+    if buf.len() + data.len() > max {
+        return Err(bad_request(format!("{name} form field is too long")).into());
+    }
+    buf.extend_from_slice(data);
+    Ok(())
+}
+
+enum Encoding {
+    /// Too few bytes yet to tell whether the upload is compressed
+    Detect(Vec<u8>),
+    Plain,
+    Compressed(Box<Inflater>),
+}
+
+enum Upload<R> {
+    /// Too few bytes yet to tell an image from a patch
+    Detect(Vec<u8>),
+    Image,
+    Patch(Box<DeltaApplier<R>>),
+}
+
+/// Takes the uploaded file in whatever chunks it arrives and streams the
+/// firmware into the update slot. The slot is only erased once the image
+/// header has been checked, the signature trailer is held back from flash.
+/// A delta patch is recognised by its magic and rebuilt against the running
+/// slot first, the checks then apply to the rebuilt image.
+/// A gzip or zlib compressed upload is inflated before any of that, so size,
+/// signature and SHA-256 checks all see the decompressed image.
+pub struct FirmwareWriter<S: SlotWriter> {
+    encoding: Encoding,
+    // From Content-Encoding, raw deflate has no header to detect
+    expected_compression: Option<Compression>,
+    upload: Upload<S::Running>,
+    slot: S,
+    // The slot is erased and being written
+    begun: bool,
+    chip: Chip,
+    policy: RollbackPolicy,
+    splitter: TrailerSplitter,
+    hasher: FirmwareHasher,
+    // Firmware bytes from the current chunk, or the image header until it is complete
+    pending: Vec<u8>,
+    total: usize,
+    received: usize,
+    written: usize,
+}
+
+impl<S: SlotWriter> FirmwareWriter<S> {
+    pub fn new(slot: S, chip: Chip, total: usize, policy: RollbackPolicy) -> Self {
+        Self {
+            encoding: Encoding::Detect(Vec::with_capacity(DETECT_LEN)),
+            expected_compression: None,
+            upload: Upload::Detect(Vec::with_capacity(PATCH_MAGIC.len())),
+            slot,
+            begun: false,
+            chip,
+            policy,
+            splitter: TrailerSplitter::new(),
+            hasher: FirmwareHasher::new(),
+            pending: Vec::with_capacity(APP_HEADER_LEN + 1440 * 3),
+            total,
+            received: 0,
+            written: 0,
+        }
+    }
+
+    pub fn allow_downgrade(&mut self) {
+        self.policy.force = true;
+    }
+
+    pub fn expect_compression(&mut self, compression: Compression) {
+        self.expected_compression = Some(compression);
+    }
+
+    /// Bytes of the uploaded file taken so far, trailer included.
+    pub fn received(&self) -> usize {
+        self.received
+    }
+
+    /// Bytes of the uploaded file after decompression, the same as `received`
+    /// for an uncompressed upload. Final once the whole upload is written.
+    pub fn decoded(&self) -> usize {
+        match &self.encoding {
+            Encoding::Compressed(inflater) => inflater.total_out(),
+            _ => self.received,
+        }
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> Result<(), OtaError> {
+        self.received += data.len();
+        match &mut self.encoding {
+            Encoding::Detect(head) => {
+                head.extend_from_slice(data);
+                if head.len() >= DETECT_LEN {
+                    let head = std::mem::take(head);
+                    self.start_decoding(&head)?;
+                }
+            }
+            _ => self.decode(data)?,
+        }
+        info!(
+            "Recieved {}b, flashed {}b -> Progeress {}%",
+            self.received,
+            self.written,
+            (self.received as f32 / self.total.max(1) as f32) * 100.0
+        );
+        let received = self.received;
+        status::update(|s| s.progress(received, Instant::now()));
+        Ok(())
+    }
+
+    fn start_decoding(&mut self, head: &[u8]) -> Result<(), OtaError> {
+        let compression = match (Compression::detect(head), self.expected_compression) {
+            (Some(found), _) => Some(found),
+            (None, Some(Compression::Deflate)) => Some(Compression::Deflate),
+            (None, Some(expected)) => {
+                return Err(bad_request(format!(
+                    "Content-Encoding says {expected:?} but the upload is not"
+                )))
+            }
+            (None, None) => None,
+        };
+        self.encoding = match compression {
+            Some(compression) => {
+                info!("{compression:?} compressed upload, inflating");
+                Encoding::Compressed(Box::new(Inflater::new(compression)))
+            }
+            None => Encoding::Plain,
+        };
+        self.decode(head)
+    }
+
+    fn decode(&mut self, data: &[u8]) -> Result<(), OtaError> {
+        match std::mem::replace(&mut self.encoding, Encoding::Plain) {
+            Encoding::Compressed(mut inflater) => {
+                let result = inflater.push(data, |upload| Ok(self.write_upload(upload)?));
+                self.encoding = Encoding::Compressed(inflater);
+                result.map_err(decompress_error)
+            }
+            encoding => {
+                self.encoding = encoding;
+                self.write_upload(data)
+            }
+        }
+    }
+
+    fn write_upload(&mut self, data: &[u8]) -> Result<(), OtaError> {
+        match std::mem::replace(&mut self.upload, Upload::Image) {
+            Upload::Detect(mut head) => {
+                head.extend_from_slice(data);
+                if head.len() < PATCH_MAGIC.len() {
+                    self.upload = Upload::Detect(head);
+                    return Ok(());
+                }
+                if head.starts_with(PATCH_MAGIC) {
+                    info!("Delta update, rebuilding against the running slot");
+                    self.upload = Upload::Patch(Box::new(DeltaApplier::new(self.slot.running()?)));
+                }
+                self.write_upload(&head)
+            }
+            Upload::Patch(mut applier) => {
+                let result = applier.push(data, |image| Ok(self.write_image(image)?));
+                self.upload = Upload::Patch(applier);
+                result.map_err(patch_error)
+            }
+            upload => {
+                self.upload = upload;
+                self.write_image(data)
+            }
+        }
+    }
+
+    fn write_image(&mut self, data: &[u8]) -> Result<(), OtaError> {
+        let pending = &mut self.pending;
+        self.splitter.push(data, |firmware| {
+            pending.extend_from_slice(firmware);
+            Ok(())
+        })?;
+
+        if !self.begun && self.pending.len() >= APP_HEADER_LEN {
+            let image = AppImageInfo::parse(&self.pending).map_err(invalid_image)?;
+            image.check_chip(self.chip).map_err(invalid_image)?;
+            self.policy
+                .check(&image.version, image.secure_version)
+                .map_err(|e| OtaError::Downgrade(e.to_string()))?;
+            info!("Incoming image: {:?}", image);
+            self.slot.begin().map_err(flash_failed)?;
+            self.begun = true;
+        }
+        if self.begun {
+            let capacity = self.slot.capacity();
+            if self.written + self.pending.len() > capacity {
+                return Err(invalid_image(format!(
+                    "Image is larger than the {capacity} byte update slot"
+                )));
+            }
+            if let Err(e) = self.slot.write(&self.pending) {
+                info!("failed to write update with: {:?}", e);
+                return Err(OtaError::Flash(format!("at {} bytes: {e}", self.written)));
+            }
+            self.hasher.update(&self.pending);
+            self.written += self.pending.len();
+            self.pending.clear();
+        }
+        Ok(())
+    }
+
+    pub fn verify(
+        &mut self,
+        expected_sha256: Option<Sha256Digest>,
+        public_key: &[u8; 32],
+    ) -> Result<(), OtaError> {
+        status::update(|s| s.verifying());
+        if let Encoding::Detect(head) = &mut self.encoding {
+            // Too short to be gzip or zlib, Content-Encoding still has to match
+            let head = std::mem::take(head);
+            self.start_decoding(&head)?;
+        }
+        match std::mem::replace(&mut self.encoding, Encoding::Plain) {
+            Encoding::Compressed(inflater) => {
+                inflater.finish().map_err(decompress_error)?;
+                info!(
+                    "Inflated {} bytes to {}",
+                    self.received,
+                    inflater.total_out()
+                );
+            }
+            Encoding::Detect(_) | Encoding::Plain => (),
+        }
+        match std::mem::replace(&mut self.upload, Upload::Image) {
+            // Too short to be either, the image checks below say why
+            Upload::Detect(head) => self.write_image(&head)?,
+            Upload::Patch(applier) => applier.finish().map_err(patch_error)?,
+            Upload::Image => (),
+        }
+        if !self.begun {
+            // Anything shorter than a header never made it to flash
+            AppImageInfo::parse(&self.pending).map_err(invalid_image)?;
+        }
+        let trailer = std::mem::take(&mut self.splitter).into_trailer();
+
+        // The signature covers the firmware, the SHA-256 the signed file as built
+        verify_signature(public_key, &self.hasher.digest(), &trailer)
+            .map_err(|e| OtaError::Signature(e.to_string()))?;
+        info!("Firmware signature verified");
+
+        let mut file_hasher = self.hasher.clone();
+        file_hasher.update(&trailer);
+        let file_digest = file_hasher.finalize();
+        match expected_sha256 {
+            Some(expected) => {
+                verify_sha256(&expected, &file_digest)
+                    .map_err(|e| OtaError::Checksum(e.to_string()))?;
+                info!("SHA-256 verified: {}", to_hex(&file_digest));
+            }
+            None => info!(
+                "No SHA-256 supplied, firmware digest {}",
+                to_hex(&file_digest)
+            ),
+        }
+        Ok(())
+    }
+
+    /// Completes the update if everything checked out, otherwise aborts it.
+    pub fn finalise(mut self, result: Result<(), OtaError>) -> Result<(), OtaError> {
+        match result {
+            Ok(()) if !self.begun => return Err(invalid_image("No firmware written")),
+            Ok(()) => (),
+            Err(e) => {
+                eprintln!("OTA aborted: {e}");
+                if self.begun {
+                    self.slot.abort().map_err(flash_failed)?;
+                }
+                return Err(e);
+            }
+        }
+        if let Err(e) = self.slot.complete() {
+            eprintln!("OTA Error at completion {e}");
+            return Err(OtaError::Flash(format!("completion stage: {e}")));
+        };
+        Ok(())
+    }
+}
+
+const FORCE_FIELD: &str = "force";
+pub const CONTENT_ENCODING: &str = "Content-Encoding";
+
+fn bad_request(e: impl Display) -> OtaError {
+    OtaError::BadRequest(e.to_string())
+}
+
+fn invalid_image(e: impl Display) -> OtaError {
+    OtaError::InvalidImage(e.to_string())
+}
+
+// Keeps the image check that failed, anything else is the patch itself
+fn patch_error(e: anyhow::Error) -> OtaError {
+    match e.downcast::<OtaError>() {
+        Ok(e) => e,
+        Err(e) => invalid_image(format!("Delta patch: {e}")),
+    }
+}
+
+// Same for the compression layer around an image or patch
+fn decompress_error(e: anyhow::Error) -> OtaError {
+    match e.downcast::<OtaError>() {
+        Ok(e) => e,
+        Err(e) => invalid_image(format!("Compressed image: {e}")),
+    }
+}
+
+fn flash_failed(e: impl Display) -> OtaError {
+    OtaError::Flash(e.to_string())
+}
+
+pub fn content_encoding(header: Option<&str>) -> Result<Option<Compression>, OtaError> {
+    match header {
+        Some(value) => Compression::from_content_encoding(value).map_err(bad_request),
+        None => Ok(None),
+    }
+}
+
+fn is_true(value: &str) -> bool {
+    matches!(value.trim(), "true" | "1" | "on" | "yes")
+}
+
+fn force_from_query(query: &str) -> bool {
+    url::form_urlencoded::parse(query.as_bytes()).any(|(k, v)| k == FORCE_FIELD && is_true(&v))
+}
+
+/// `None` for a raw upload, anything that is not multipart/form-data.
+fn multipart_boundary(body: &impl BodySource) -> Result<Option<String>, OtaError> {
+    match body.header("Content-Type") {
+        Some(b) if is_multipart(b) => match boundary_from_content_type(b) {
+            Some(boundary) => Ok(Some(boundary.to_string())),
+            None => {
+                eprint!("Error: Boundary string = {b}");
+                Err(bad_request("No boundary string, check multipart form POST"))
+            }
+        },
+        _ => Ok(None),
+    }
+}
@@ -0,0 +1,140 @@
+// Firmware integrity checks
+//
+// Kept free of ESP types so the same code can be run against `ota.bin` on a PC.
+
+use anyhow::{anyhow, bail, Result};
+use ed25519_compact::{PublicKey, Signature};
+use sha2::{Digest, Sha256};
+
+pub const SHA256_HEADER: &str = "X-Firmware-SHA256";
+pub const SHA256_FIELD: &str = "sha256";
+
+// Signed image layout: firmware | ed25519(sha256(firmware)) | SIGNATURE_MAGIC
+pub const SIGNATURE_MAGIC: &[u8; 8] = b"OTASIGv1";
+pub const SIGNATURE_LEN: usize = 64;
+pub const SIGNATURE_TRAILER_LEN: usize = SIGNATURE_LEN + SIGNATURE_MAGIC.len();
+
+pub type Sha256Digest = [u8; 32];
+
+/// Hashes the firmware as it is written so no second pass over flash is needed.
+#[derive(Clone, Default)]
+pub struct FirmwareHasher {
+    hasher: Sha256,
+}
+
+impl FirmwareHasher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    /// Digest of everything hashed so far, hashing can carry on afterwards.
+    pub fn digest(&self) -> Sha256Digest {
+        self.hasher.clone().finalize().into()
+    }
+
+    pub fn finalize(self) -> Sha256Digest {
+        self.hasher.finalize().into()
+    }
+}
+
+/// Passes an upload through while holding back the last `SIGNATURE_TRAILER_LEN`
+/// bytes, so the signature trailer never reaches flash.
+#[derive(Default)]
+pub struct TrailerSplitter {
+    tail: Vec<u8>,
+}
+
+impl TrailerSplitter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, data: &[u8], mut out: impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
+        let total = self.tail.len() + data.len();
+        if total <= SIGNATURE_TRAILER_LEN {
+            self.tail.extend_from_slice(data);
+            return Ok(());
+        }
+        let emit = total - SIGNATURE_TRAILER_LEN;
+        let from_tail = emit.min(self.tail.len());
+        let from_data = emit - from_tail;
+        if from_tail > 0 {
+            out(&self.tail[..from_tail])?;
+        }
+        if from_data > 0 {
+            out(&data[..from_data])?;
+        }
+        self.tail.drain(..from_tail);
+        self.tail.extend_from_slice(&data[from_data..]);
+        Ok(())
+    }
+
+    pub fn into_trailer(self) -> Vec<u8> {
+        self.tail
+    }
+}
+
+pub fn parse_hex<const N: usize>(hex: &str) -> Result<[u8; N]> {
+    let hex = hex.trim();
+    if hex.len() != N * 2 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        bail!("Expected {} hex characters, got {hex:?}", N * 2);
+    }
+    let mut bytes = [0u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| anyhow!("Non hex characters in {hex:?}"))?;
+    }
+    Ok(bytes)
+}
+
+pub fn parse_sha256_hex(hex: &str) -> Result<Sha256Digest> {
+    parse_hex(hex).map_err(|e| anyhow!("Bad SHA-256: {e}"))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn verify_sha256(expected: &Sha256Digest, actual: &Sha256Digest) -> Result<()> {
+    if expected != actual {
+        bail!(
+            "SHA-256 mismatch: expected {} got {}",
+            to_hex(expected),
+            to_hex(actual)
+        );
+    }
+    Ok(())
+}
+
+/// Finds `sha256=<hex>` in a request query string.
+pub fn sha256_from_query(query: &str) -> Option<Result<Sha256Digest>> {
+    url::form_urlencoded::parse(query.as_bytes())
+        .find(|(k, _)| k == SHA256_FIELD)
+        .map(|(_, v)| parse_sha256_hex(&v))
+}
+
+pub fn parse_public_key_hex(hex: &str) -> Result<[u8; 32]> {
+    parse_hex(hex).map_err(|e| anyhow!("Bad Ed25519 public key: {e}"))
+}
+
+/// Checks the trailer held back by `TrailerSplitter` against the firmware digest.
+pub fn verify_signature(
+    public_key: &[u8; 32],
+    firmware_digest: &Sha256Digest,
+    trailer: &[u8],
+) -> Result<()> {
+    if trailer.len() != SIGNATURE_TRAILER_LEN || !trailer.ends_with(SIGNATURE_MAGIC) {
+        bail!("Firmware is not signed");
+    }
+    let public_key =
+        PublicKey::from_slice(public_key).map_err(|e| anyhow!("Bad Ed25519 public key: {e}"))?;
+    let signature = Signature::from_slice(&trailer[..SIGNATURE_LEN])
+        .map_err(|e| anyhow!("Bad Ed25519 signature: {e}"))?;
+    public_key
+        .verify(firmware_digest, &signature)
+        .map_err(|_| anyhow!("Firmware signature does not verify"))
+}
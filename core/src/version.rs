@@ -0,0 +1,145 @@
+// Firmware version comparison for the anti-rollback check
+//
+// App descriptors carry whatever PROJECT_VER was at build time, so parsing is
+// lenient: "v1.2", "1.2.3-rc.1+build" and "1" are all accepted.
+
+use anyhow::{bail, Result};
+use std::cmp::Ordering;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    pub pre: Option<String>,
+}
+
+impl Version {
+    pub fn parse(version: &str) -> Option<Self> {
+        let version = version.trim();
+        let version = version.strip_prefix(['v', 'V']).unwrap_or(version);
+        let version = version.split('+').next()?;
+        let (core, pre) = match version.split_once('-') {
+            Some((core, pre)) => (core, Some(pre.to_owned()).filter(|p| !p.is_empty())),
+            None => (version, None),
+        };
+        let mut numbers = core.split('.');
+        let mut next = || -> Option<u64> {
+            match numbers.next() {
+                Some(n) => n.parse().ok(),
+                None => Some(0),
+            }
+        };
+        let parsed = Version {
+            major: next()?,
+            minor: next()?,
+            patch: next()?,
+            pre,
+        };
+        if numbers.next().is_some() {
+            return None;
+        }
+        Some(parsed)
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.major, self.minor, self.patch)
+            .cmp(&(other.major, other.minor, other.patch))
+            .then_with(|| match (&self.pre, &other.pre) {
+                // A release sorts after its pre-releases
+                (None, None) => Ordering::Equal,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(_), None) => Ordering::Less,
+                (Some(a), Some(b)) => compare_pre(a, b),
+            })
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+        if let Some(pre) = &self.pre {
+            write!(f, "-{pre}")?;
+        }
+        Ok(())
+    }
+}
+
+// semver rules: numeric identifiers compare numerically and sort before alphanumeric ones
+fn compare_pre(a: &str, b: &str) -> Ordering {
+    let mut a_ids = a.split('.');
+    let mut b_ids = b.split('.');
+    loop {
+        match (a_ids.next(), b_ids.next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(a), Some(b)) => {
+                let ordering = match (a.parse::<u64>(), b.parse::<u64>()) {
+                    (Ok(a), Ok(b)) => a.cmp(&b),
+                    (Ok(_), Err(_)) => Ordering::Less,
+                    (Err(_), Ok(_)) => Ordering::Greater,
+                    (Err(_), Err(_)) => a.cmp(b),
+                };
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RollbackPolicy {
+    /// Version of the running firmware
+    pub running_version: String,
+    /// Lowest secure_version an image may carry, from the NVS counter
+    pub min_secure_version: Option<u32>,
+    /// Explicitly allow installing an older version
+    pub force: bool,
+}
+
+impl RollbackPolicy {
+    pub fn check(&self, incoming_version: &str, incoming_secure_version: u32) -> Result<()> {
+        // The secure version counter cannot be forced past
+        if let Some(min) = self.min_secure_version {
+            if incoming_secure_version < min {
+                bail!("Image secure version {incoming_secure_version} is below the device minimum {min}");
+            }
+        }
+        match (
+            Version::parse(incoming_version),
+            Version::parse(&self.running_version),
+        ) {
+            (Some(incoming), Some(running)) if incoming < running => {
+                if self.force {
+                    log::warn!("Forced downgrade from {running} to {incoming}");
+                } else {
+                    bail!("Refusing downgrade from {running} to {incoming}, set force=true to override");
+                }
+            }
+            (Some(_), Some(_)) => (),
+            // Could be a downgrade, it takes force like one
+            _ if self.force => log::warn!(
+                "Cannot compare versions {:?} and {:?}, forced",
+                incoming_version,
+                self.running_version
+            ),
+            _ => bail!(
+                "Cannot compare versions {:?} and {:?}, set force=true to install anyway",
+                incoming_version,
+                self.running_version
+            ),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+use ota_core::configuration::AuthSettings;
+use ota_core::credential::{
+    authorize, configured, hash_password, hash_token, set_credential, set_token, verify_password,
+    verify_token, Credential, SetupRequest, ITERATIONS, LEGACY_ITERATIONS,
+};
+use ota_core::error::OtaError;
+use ota_core::verify::to_hex;
+
+#[test]
+fn password_hash_is_pbkdf2_sha256() {
+    // python3 -c "import hashlib; print(hashlib.pbkdf2_hmac('sha256', b'correct horse', b'0123456789abcdef', 1000).hex())"
+    assert_eq!(
+        to_hex(&hash_password(b"0123456789abcdef", "correct horse", 1000)),
+        "70183c0f60ee9e0441f64efab334e17f97a17f2073f7dd5acba3d3f12af09383"
+    );
+    // Longer than a SHA-256 block, the key gets hashed first
+    assert_eq!(
+        to_hex(&hash_password(b"salt", &"k".repeat(100), 1000)),
+        "d89a1db51dac010ecb9e641c6c0e01b06f4e3b9743f8c37c94636cf75aff2dfb"
+    );
+}
+
+#[test]
+fn passwords_and_tokens_verify() {
+    let hash = hash_password(b"salt", "pw123456", 10);
+    assert!(verify_password(b"salt", &hash, "pw123456", 10));
+    assert!(!verify_password(b"salt", &hash, "pw123457", 10));
+    assert!(!verify_password(b"salt", &hash, "pw123456", 11));
+    let hash = hash_token("token");
+    assert!(verify_token(&hash, "token"));
+    assert!(!verify_token(&hash, "token2"));
+}
+
+#[test]
+fn authorization_header_parses() {
+    assert_eq!(
+        Credential::parse("Basic YWRtaW46cMOkc3M6d29yZA=="),
+        Some(Credential::Basic {
+            username: "admin".into(),
+            password: "päss:word".into()
+        })
+    );
+    assert_eq!(
+        Credential::parse("bearer abc"),
+        Some(Credential::Bearer("abc".into()))
+    );
+    // Unpadded, as some scripts send it
+    assert_eq!(
+        Credential::parse("Basic YWRtaW46cHc"),
+        Some(Credential::Basic {
+            username: "admin".into(),
+            password: "pw".into()
+        })
+    );
+    for header in ["Basic !!!", "Digest x", "Basic YWRtaW4="] {
+        assert_eq!(Credential::parse(header), None, "{header}");
+    }
+}
+
+fn setup(username: &str, password: &str) -> SetupRequest {
+    SetupRequest {
+        username: username.to_string(),
+        password: password.to_string(),
+    }
+}
+
+#[test]
+fn authorize_checks_password_and_token() {
+    let mut auth = AuthSettings::default();
+    assert!(matches!(
+        authorize(&auth, None),
+        Err(OtaError::SetupRequired)
+    ));
+    set_credential(&mut auth, &setup(" admin ", "correct horse"), [1; 16]).unwrap();
+    assert_eq!(auth.username.as_deref(), Some("admin"));
+    assert_eq!(auth.iterations, Some(ITERATIONS));
+    // "admin:correct horse", "admin:wrong horse"
+    assert!(authorize(&auth, Some("Basic YWRtaW46Y29ycmVjdCBob3JzZQ==")).is_ok());
+    assert!(matches!(
+        authorize(&auth, Some("Basic YWRtaW46d3JvbmcgaG9yc2U=")),
+        Err(OtaError::Unauthorized)
+    ));
+    assert!(matches!(
+        authorize(&auth, None),
+        Err(OtaError::Unauthorized)
+    ));
+
+    let token = set_token(&mut auth, [2; 32]);
+    assert!(authorize(&auth, Some(&format!("Bearer {token}"))).is_ok());
+    // A new credential revokes the token
+    set_credential(&mut auth, &setup("admin", "correct horse"), [3; 16]).unwrap();
+    assert!(authorize(&auth, Some(&format!("Bearer {token}"))).is_err());
+}
+
+#[test]
+fn weak_credentials_are_refused() {
+    let mut auth = AuthSettings::default();
+    for (username, password) in [
+        ("", "correct horse"),
+        ("ad:min", "correct horse"),
+        ("admin", "short"),
+    ] {
+        let result = set_credential(&mut auth, &setup(username, password), [1; 16]);
+        assert!(matches!(result, Err(OtaError::BadRequest(_))), "{username}");
+    }
+    assert!(auth.password_hash.is_none());
+}
+
+#[test]
+fn credential_without_iterations_uses_the_legacy_count() {
+    let salt = [4; 16];
+    let mut auth = AuthSettings {
+        username: Some("admin".into()),
+        salt: Some(to_hex(&salt)),
+        password_hash: Some(to_hex(&hash_password(
+            &salt,
+            "correct horse",
+            LEGACY_ITERATIONS,
+        ))),
+        ..Default::default()
+    };
+    assert!(authorize(&auth, Some("Basic YWRtaW46Y29ycmVjdCBob3JzZQ==")).is_ok());
+    auth.iterations = Some(ITERATIONS);
+    assert!(matches!(
+        authorize(&auth, Some("Basic YWRtaW46Y29ycmVjdCBob3JzZQ==")),
+        Err(OtaError::Unauthorized)
+    ));
+}
+
+#[test]
+fn locked_credential_refuses_everything() {
+    let mut auth = AuthSettings::default();
+    assert!(!configured(&auth));
+    auth.locked = true;
+    assert!(configured(&auth));
+    assert!(matches!(
+        authorize(&auth, Some("Basic YWRtaW46Y29ycmVjdCBob3JzZQ==")),
+        Err(OtaError::CredentialLocked)
+    ));
+    assert!(matches!(
+        set_credential(&mut auth, &setup("admin", "correct horse"), [1; 16]),
+        Err(OtaError::CredentialLocked)
+    ));
+    assert!(auth.password_hash.is_none());
+}
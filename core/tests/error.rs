@@ -0,0 +1,78 @@
+use anyhow::anyhow;
+use serde_json::{json, Value};
+
+use ota_core::error::OtaError;
+use ota_core::status::OtaStatus;
+
+fn text() -> String {
+    "x".to_string()
+}
+
+#[test]
+fn every_error_has_its_status_and_code() {
+    let mut busy = OtaStatus::default();
+    busy.bytes_written = 4096;
+    for (error, status, code) in [
+        (OtaError::MissingLength, 411, "missing_length"),
+        (OtaError::BadRequest(text()), 400, "bad_request"),
+        (OtaError::InvalidImage(text()), 422, "invalid_image"),
+        (OtaError::Downgrade(text()), 409, "downgrade_refused"),
+        (OtaError::Signature(text()), 403, "signature_invalid"),
+        (OtaError::Checksum(text()), 422, "checksum_mismatch"),
+        (OtaError::Transfer(text()), 400, "transfer_failed"),
+        (OtaError::Download(text()), 502, "download_failed"),
+        (OtaError::Flash(text()), 500, "flash_failed"),
+        (OtaError::NotFound, 404, "session_not_found"),
+        (OtaError::Offset(10), 416, "offset_mismatch"),
+        (OtaError::SlotNotFound(text()), 404, "slot_not_found"),
+        (OtaError::SlotNotBootable(text()), 409, "slot_not_bootable"),
+        (OtaError::NothingStaged, 409, "nothing_staged"),
+        (OtaError::NoUpdate(text()), 409, "no_update"),
+        (OtaError::Busy(Box::new(busy)), 409, "busy"),
+        (OtaError::Unauthorized, 401, "unauthorized"),
+        (OtaError::SetupRequired, 403, "setup_required"),
+        (OtaError::CredentialLocked, 403, "credential_locked"),
+        (OtaError::Internal(text()), 500, "internal"),
+    ] {
+        assert_eq!((error.status(), error.code()), (status, code), "{error:?}");
+    }
+}
+
+#[test]
+fn json_body() {
+    let error = OtaError::Flash("esp_ota_write failed".to_string());
+    let body: Value = serde_json::from_str(&error.json(524288)).unwrap();
+    assert_eq!(
+        body,
+        json!({
+            "code": "flash_failed",
+            "message": "Flash write failed: esp_ota_write failed",
+            "bytes_written": 524288,
+        })
+    );
+}
+
+#[test]
+fn offset_mismatch_reports_the_resume_offset() {
+    let body: Value = serde_json::from_str(&OtaError::Offset(65536).json(1000)).unwrap();
+    assert_eq!(body["bytes_written"], 65536);
+}
+
+#[test]
+fn busy_carries_the_running_update() {
+    let mut current = OtaStatus::default();
+    current.bytes_written = 4096;
+    let body: Value = serde_json::from_str(&OtaError::Busy(Box::new(current)).json(0)).unwrap();
+    assert_eq!(body["code"], "busy");
+    assert_eq!(body["progress"]["bytes_written"], 4096);
+    assert_eq!(body["progress"]["state"], "idle");
+}
+
+#[test]
+fn type_survives_anyhow() {
+    let error = OtaError::from(anyhow::Error::from(OtaError::Checksum(text())));
+    assert!(matches!(error, OtaError::Checksum(_)), "{error:?}");
+    let error = OtaError::from(anyhow!("nvs full").context("storing settings"));
+    assert_eq!(error.code(), "internal");
+    assert_eq!(error.to_string(), "storing settings: nvs full");
+}
@@ -0,0 +1,79 @@
+use ota_core::image::{AppImageInfo, Chip, APP_HEADER_LEN};
+
+fn firmware() -> Vec<u8> {
+    std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/../ota.bin")).unwrap()
+}
+
+#[test]
+fn app_descriptor_of_a_real_image() {
+    let info = AppImageInfo::parse(&firmware()).unwrap();
+    assert_eq!(
+        info,
+        AppImageInfo {
+            chip: Chip::Esp32c3,
+            segment_count: 6,
+            entry_addr: 0x4038_1a70,
+            hash_appended: true,
+            secure_version: 0,
+            version: "1".to_string(),
+            project_name: "libespidf".to_string(),
+            time: "12:57:09".to_string(),
+            date: "Oct 23 2022".to_string(),
+            idf_ver: "9269a53-dirty".to_string(),
+        }
+    );
+    assert!(info.check_chip(Chip::Esp32c3).is_ok());
+}
+
+#[test]
+fn header_alone_is_enough() {
+    let firmware = firmware();
+    assert_eq!(
+        AppImageInfo::parse(&firmware[..APP_HEADER_LEN]).unwrap(),
+        AppImageInfo::parse(&firmware).unwrap()
+    );
+}
+
+#[test]
+fn truncated_header_is_rejected() {
+    let firmware = firmware();
+    for len in [0, 1, 24, APP_HEADER_LEN - 1] {
+        assert!(AppImageInfo::parse(&firmware[..len]).is_err(), "{len}");
+    }
+}
+
+#[test]
+fn text_file_is_rejected() {
+    let text = "Not firmware at all. ".repeat(20);
+    let error = AppImageInfo::parse(text.as_bytes()).unwrap_err();
+    assert!(error.to_string().contains("magic"), "{error}");
+}
+
+#[test]
+fn broken_headers_are_rejected() {
+    let firmware = firmware();
+    for (at, value) in [(1, 0), (1, 17), (32, 0)] {
+        let mut broken = firmware.clone();
+        broken[at] = value;
+        assert!(AppImageInfo::parse(&broken).is_err(), "byte {at} = {value}");
+    }
+}
+
+#[test]
+fn wrong_chip_is_rejected() {
+    let mut esp32s3 = firmware();
+    esp32s3[12] = 0x09;
+    let info = AppImageInfo::parse(&esp32s3).unwrap();
+    assert_eq!(info.chip, Chip::Esp32s3);
+    let error = info.check_chip(Chip::Esp32c3).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Image is built for ESP32-S3, this device is ESP32-C3"
+    );
+
+    esp32s3[12] = 0x42;
+    assert_eq!(
+        AppImageInfo::parse(&esp32s3).unwrap().chip,
+        Chip::Unknown(0x42)
+    );
+}
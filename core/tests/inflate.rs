@@ -0,0 +1,227 @@
+use flate2::write::{DeflateEncoder, GzEncoder, ZlibEncoder};
+use flate2::{Compression as Level, GzBuilder};
+use std::io::Write;
+
+use ota_core::inflate::{Compression, Inflater};
+
+fn firmware() -> Vec<u8> {
+    std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/../ota.bin")).unwrap()
+}
+
+fn compress(format: Compression, level: Level, data: &[u8]) -> Vec<u8> {
+    match format {
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), level);
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        }
+        Compression::Zlib => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), level);
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        }
+        Compression::Deflate => {
+            let mut encoder = DeflateEncoder::new(Vec::new(), level);
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        }
+    }
+}
+
+/// Pushes `data` in `chunk` sized pieces, the output or the first error
+fn inflate(format: Compression, data: &[u8], chunk: usize) -> anyhow::Result<Vec<u8>> {
+    let mut inflater = Inflater::new(format);
+    let mut out = Vec::new();
+    for piece in data.chunks(chunk) {
+        inflater.push(piece, |data| {
+            out.extend_from_slice(data);
+            Ok(())
+        })?;
+    }
+    inflater.finish()?;
+    assert_eq!(inflater.total_out(), out.len());
+    Ok(out)
+}
+
+const FORMATS: [Compression; 3] = [Compression::Gzip, Compression::Zlib, Compression::Deflate];
+
+#[test]
+fn firmware_round_trips() {
+    let firmware = firmware();
+    for format in FORMATS {
+        let compressed = compress(format, Level::best(), &firmware);
+        assert!(compressed.len() < firmware.len() * 3 / 4, "{format:?}");
+        for chunk in [1, 1440 * 3, compressed.len()] {
+            let out = inflate(format, &compressed, chunk).unwrap();
+            assert!(out == firmware, "{format:?} in {chunk} byte chunks");
+        }
+    }
+}
+
+#[test]
+fn detects_gzip_and_zlib_headers() {
+    let firmware = firmware();
+    assert_eq!(
+        Compression::detect(&compress(Compression::Gzip, Level::fast(), b"x")),
+        Some(Compression::Gzip)
+    );
+    for level in [Level::none(), Level::fast(), Level::best()] {
+        let zlib = compress(Compression::Zlib, level, b"x");
+        assert_eq!(Compression::detect(&zlib), Some(Compression::Zlib));
+    }
+    assert_eq!(Compression::detect(&firmware), None);
+    assert_eq!(Compression::detect(b"OTADIFF1"), None);
+    assert_eq!(Compression::detect(&[0x1f]), None);
+}
+
+#[test]
+fn content_encodings() {
+    for (value, expected) in [
+        ("", None),
+        ("identity", None),
+        ("gzip", Some(Compression::Gzip)),
+        (" X-GZIP ", Some(Compression::Gzip)),
+        ("deflate", Some(Compression::Deflate)),
+    ] {
+        assert_eq!(
+            Compression::from_content_encoding(value).unwrap(),
+            expected,
+            "{value:?}"
+        );
+    }
+    assert!(Compression::from_content_encoding("br").is_err());
+}
+
+// BTYPE of the first block, 0 stored, 1 fixed, 2 dynamic
+fn first_block_type(format: Compression, compressed: &[u8]) -> u8 {
+    let skip = match format {
+        Compression::Gzip => 10,
+        Compression::Zlib => 2,
+        Compression::Deflate => 0,
+    };
+    (compressed[skip] >> 1) & 3
+}
+
+#[test]
+fn stored_fixed_and_dynamic_blocks() {
+    let text = b"hello hello hello";
+    let firmware = firmware();
+    for format in FORMATS {
+        for (level, data, block_type) in [
+            (Level::none(), &firmware[..100_000], 0),
+            (Level::best(), &text[..], 1),
+            (Level::best(), &firmware[..100_000], 2),
+        ] {
+            let compressed = compress(format, level, data);
+            assert_eq!(first_block_type(format, &compressed), block_type);
+            for chunk in [1, 3, compressed.len()] {
+                assert_eq!(
+                    inflate(format, &compressed, chunk).unwrap(),
+                    data,
+                    "{format:?} block type {block_type} in {chunk} byte chunks"
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn gzip_header_fields_are_skipped() {
+    let data = b"firmware image".repeat(100);
+    let mut encoder = GzBuilder::new()
+        .filename("ota.signed.bin")
+        .comment("built today")
+        .extra(vec![1, 2, 3, 4])
+        .write(Vec::new(), Level::best());
+    encoder.write_all(&data).unwrap();
+    let compressed = encoder.finish().unwrap();
+    for chunk in [1, 5, compressed.len()] {
+        assert_eq!(
+            inflate(Compression::Gzip, &compressed, chunk).unwrap(),
+            data
+        );
+    }
+
+    // A file name that never ends
+    let mut endless = compressed[..10].to_vec();
+    endless[3] = 0x08;
+    endless.extend_from_slice(&[b'a'; 5000]);
+    let err = inflate(Compression::Gzip, &endless, 100).unwrap_err();
+    assert!(err.to_string().contains("header too long"), "{err}");
+}
+
+#[test]
+fn truncated_input_errors() {
+    let firmware = firmware();
+    for format in FORMATS {
+        let compressed = compress(format, Level::best(), &firmware[..200_000]);
+        for len in [0, 1, 5, compressed.len() / 2, compressed.len() - 1] {
+            let err = inflate(format, &compressed[..len], 997).unwrap_err();
+            assert!(
+                err.to_string().contains("ends early"),
+                "{format:?} {len}: {err}"
+            );
+        }
+    }
+}
+
+#[test]
+fn corrupt_input_errors_without_panic() {
+    let firmware = firmware();
+    for format in FORMATS {
+        let compressed = compress(format, Level::best(), &firmware[..200_000]);
+        // Every byte position and a few bit patterns, none may panic
+        for at in (0..compressed.len()).step_by(251) {
+            for flip in [0x01, 0x80, 0xff] {
+                let mut corrupt = compressed.clone();
+                corrupt[at] ^= flip;
+                // Raw deflate has no checksum, the others never inflate to something else
+                if let Ok(out) = inflate(format, &corrupt, 1440) {
+                    if format != Compression::Deflate {
+                        assert!(out == firmware[..200_000], "{format:?} {at} {flip:#x}");
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn checksums_are_checked() {
+    let data = firmware()[..50_000].to_vec();
+    for (format, at) in [
+        (Compression::Gzip, 8),
+        (Compression::Gzip, 4),
+        (Compression::Zlib, 1),
+    ] {
+        let mut compressed = compress(format, Level::best(), &data);
+        let len = compressed.len();
+        compressed[len - at] ^= 1;
+        let err = inflate(format, &compressed, 4096).unwrap_err();
+        assert!(
+            err.to_string().contains("Bad compressed data"),
+            "{format:?}: {err}"
+        );
+    }
+}
+
+#[test]
+fn data_after_the_end_is_refused() {
+    for format in FORMATS {
+        let mut compressed = compress(format, Level::best(), b"firmware");
+        compressed.push(0);
+        let err = inflate(format, &compressed, 1).unwrap_err();
+        assert!(
+            err.to_string().contains("after the end"),
+            "{format:?}: {err}"
+        );
+    }
+}
+
+#[test]
+fn wrong_format_is_refused() {
+    let zlib = compress(Compression::Zlib, Level::best(), b"firmware");
+    assert!(inflate(Compression::Gzip, &zlib, 64).is_err());
+    let gzip = compress(Compression::Gzip, Level::best(), b"firmware");
+    assert!(inflate(Compression::Zlib, &gzip, 64).is_err());
+}
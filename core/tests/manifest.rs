@@ -0,0 +1,203 @@
+use serde_json::json;
+use std::time::Duration;
+
+use ota_core::configuration::{UpdatePolicy, UpdateSettings, MIN_POLL_INTERVAL_SECS};
+use ota_core::error::OtaError;
+use ota_core::manifest::{Check, Manifest, MAX_MANIFEST_LEN};
+
+const MANIFEST_URL: &str = "http://192.168.1.10:8000/fw/manifest.json";
+
+fn manifest() -> Manifest {
+    let json = format!(
+        r#"{{"version":"0.2.0","url":"ota.bin","size":10,"sha256":"{}","min_version":"0.1.0","hardware":"esp32-c3","channel":"stable"}}"#,
+        "a".repeat(64)
+    );
+    Manifest::parse(json.as_bytes(), MANIFEST_URL).unwrap()
+}
+
+#[test]
+fn relative_url_is_resolved() {
+    assert_eq!(manifest().url, "http://192.168.1.10:8000/fw/ota.bin");
+    let absolute = Manifest::parse(
+        br#"{"version":"1.0.0","url":"https://x/y.bin"}"#,
+        MANIFEST_URL,
+    )
+    .unwrap();
+    assert_eq!(absolute.url, "https://x/y.bin");
+}
+
+#[test]
+fn newer_build_for_this_device_is_offered() {
+    let manifest = manifest();
+    assert!(matches!(
+        manifest.check("0.1.0", "ESP32-C3", "stable"),
+        Check::Available { .. }
+    ));
+    assert_eq!(
+        manifest.check("0.2.0", "ESP32-C3", "stable"),
+        Check::UpToDate
+    );
+    assert_eq!(
+        manifest.check("0.3.0", "ESP32-C3", "stable"),
+        Check::UpToDate
+    );
+}
+
+#[test]
+fn other_devices_and_channels_are_skipped() {
+    let manifest = manifest();
+    for (running, hardware, channel) in [
+        ("0.0.9", "ESP32-C3", "stable"),
+        ("0.1.0", "ESP32", "stable"),
+        ("0.1.0", "ESP32-C3", "beta"),
+    ] {
+        assert!(
+            matches!(
+                manifest.check(running, hardware, channel),
+                Check::Skipped { .. }
+            ),
+            "{running} {hardware} {channel}"
+        );
+    }
+}
+
+#[test]
+fn invalid_manifests_are_rejected() {
+    for json in [
+        r#"{"version":"x.y","url":"a"}"#,
+        r#"{"version":"1.0.0","url":"ftp://a/b"}"#,
+        r#"{"version":"1.0.0","url":"a","sha256":"zz"}"#,
+        r#"{"url":"a"}"#,
+    ] {
+        assert!(
+            Manifest::parse(json.as_bytes(), MANIFEST_URL).is_err(),
+            "{json}"
+        );
+    }
+}
+
+#[test]
+fn oversized_manifest_is_rejected() {
+    let json = format!(
+        r#"{{"version":"1.0.0","url":"a","channel":"{}"}}"#,
+        "x".repeat(MAX_MANIFEST_LEN)
+    );
+    let err = Manifest::parse(json.as_bytes(), MANIFEST_URL).unwrap_err();
+    assert!(err.to_string().contains("bytes"), "{err}");
+}
+
+#[test]
+fn check_edges() {
+    let manifest = manifest();
+    // min_version is inclusive, the hardware id is not case sensitive
+    assert!(matches!(
+        manifest.check("0.1.0", "esp32-C3", "stable"),
+        Check::Available { .. }
+    ));
+    // A running version that does not compare is never offered anything
+    assert_eq!(
+        manifest.check("9269a53-dirty", "ESP32-C3", "stable"),
+        Check::UpToDate
+    );
+    let bare = Manifest::parse(br#"{"version":"0.2.0","url":"a"}"#, MANIFEST_URL).unwrap();
+    assert!(matches!(
+        bare.check("0.0.1", "anything", "beta"),
+        Check::Available { .. }
+    ));
+}
+
+#[test]
+fn check_serializes_with_a_status() {
+    assert_eq!(
+        serde_json::to_value(Check::UpToDate).unwrap(),
+        json!({"status": "up_to_date"})
+    );
+    let skipped = manifest().check("0.1.0", "ESP32", "stable");
+    let value = serde_json::to_value(skipped).unwrap();
+    assert_eq!(value["status"], "skipped");
+    assert!(value["reason"].as_str().unwrap().contains("ESP32"));
+    let available = serde_json::to_value(manifest().check("0.1.0", "ESP32-C3", "stable")).unwrap();
+    assert_eq!(available["status"], "available");
+    assert_eq!(available["manifest"]["version"], "0.2.0");
+}
+
+#[test]
+fn update_settings_take_defaults_for_missing_fields() {
+    let settings: UpdateSettings =
+        serde_json::from_str(r#"{"manifest_url": "http://x/m.json", "policy": "notify"}"#).unwrap();
+    assert_eq!(settings.policy, UpdatePolicy::Notify);
+    assert_eq!(settings.channel, "stable");
+    assert_eq!(settings.hardware_id, None);
+    assert_eq!(settings.poll_interval_secs, 3600);
+
+    assert_eq!(UpdateSettings::default().policy, UpdatePolicy::Manual);
+    for (json, policy) in [
+        ("\"auto\"", UpdatePolicy::Auto),
+        ("\"notify\"", UpdatePolicy::Notify),
+        ("\"manual\"", UpdatePolicy::Manual),
+    ] {
+        assert_eq!(serde_json::from_str::<UpdatePolicy>(json).unwrap(), policy);
+        assert_eq!(serde_json::to_string(&policy).unwrap(), json);
+    }
+    assert!(serde_json::from_str::<UpdatePolicy>("\"Auto\"").is_err());
+}
+
+#[test]
+fn update_settings_are_validated() {
+    let mut settings = UpdateSettings {
+        manifest_url: Some("https://updates.local/manifest.json".to_string()),
+        poll_interval_secs: 5,
+        ..Default::default()
+    };
+    settings.validate().unwrap();
+    assert_eq!(settings.poll_interval_secs, MIN_POLL_INTERVAL_SECS);
+
+    for url in ["ftp://updates.local/manifest.json", "manifest.json"] {
+        let mut settings = UpdateSettings {
+            manifest_url: Some(url.to_string()),
+            ..Default::default()
+        };
+        assert!(
+            matches!(settings.validate(), Err(OtaError::BadRequest(_))),
+            "{url}"
+        );
+    }
+    // No url is fine, checks then fail until one is set
+    UpdateSettings::default().validate().unwrap();
+}
+
+#[test]
+fn polling_follows_the_policy() {
+    let settings = |policy| UpdateSettings {
+        manifest_url: Some(MANIFEST_URL.to_string()),
+        policy,
+        poll_interval_secs: 600,
+        ..Default::default()
+    };
+    let secs = Duration::from_secs;
+
+    let auto = settings(UpdatePolicy::Auto);
+    assert!(auto.poll_due(None));
+    assert!(!auto.poll_due(Some(secs(599))));
+    assert!(auto.poll_due(Some(secs(600))));
+    assert!(settings(UpdatePolicy::Notify).poll_due(Some(secs(600))));
+    assert!(!settings(UpdatePolicy::Manual).poll_due(None));
+    let no_url = UpdateSettings {
+        manifest_url: None,
+        ..auto.clone()
+    };
+    assert!(!no_url.poll_due(None));
+    // A stored interval below the floor still waits for the floor
+    let eager = UpdateSettings {
+        poll_interval_secs: 1,
+        ..auto.clone()
+    };
+    assert!(!eager.poll_due(Some(secs(MIN_POLL_INTERVAL_SECS - 1))));
+
+    let available = manifest().check("0.1.0", "ESP32-C3", "stable");
+    assert!(auto.installs(&available));
+    assert!(!settings(UpdatePolicy::Notify).installs(&available));
+    assert!(!settings(UpdatePolicy::Manual).installs(&available));
+    assert!(!auto.installs(&Check::UpToDate));
+    assert!(!auto.installs(&manifest().check("0.1.0", "ESP32", "stable")));
+}
@@ -0,0 +1,241 @@
+use ota_core::multipart::{boundary_from_content_type, is_multipart, Event, MultipartParser, Part};
+
+use anyhow::Result;
+
+const BOUNDARY: &str = "XYZ";
+
+fn firmware() -> Vec<u8> {
+    let mut firmware = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/../ota.bin")).unwrap();
+    // Near misses of the delimiter inside the file
+    firmware.extend_from_slice(b"\r\n--X\r\n--XY\r\n-\r\n--");
+    firmware
+}
+
+fn form(file: &[u8]) -> Vec<u8> {
+    let mut body = format!(
+        "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"sha256\"\r\n\r\nabcd\r\n\
+         --{BOUNDARY}\r\nContent-Disposition: form-data; name=\"update\"; filename=\"ota.bin\"\r\n\
+         Content-Type: application/octet-stream\r\n\r\n"
+    )
+    .into_bytes();
+    body.extend_from_slice(file);
+    body.extend_from_slice(format!("\r\n--{BOUNDARY}--\r\n").as_bytes());
+    body
+}
+
+// The file part's bytes and every part header, fed in the given chunks
+fn parse(body: &[u8], chunk: usize) -> (Vec<u8>, Vec<Part>) {
+    parse_reads(body.chunks(chunk))
+}
+
+// The same, fed as the given reads
+fn parse_reads<'a>(reads: impl IntoIterator<Item = &'a [u8]>) -> (Vec<u8>, Vec<Part>) {
+    let mut parser = MultipartParser::new(BOUNDARY);
+    let mut file = Vec::new();
+    let mut parts = Vec::new();
+    let mut in_file = false;
+    for data in reads {
+        parser
+            .feed(data, |event| {
+                match event {
+                    Event::PartStart(part) => {
+                        in_file = part.is_file();
+                        parts.push(part.clone());
+                    }
+                    Event::Data(data) if in_file => file.extend_from_slice(data),
+                    Event::Data(_) => (),
+                    Event::PartEnd => in_file = false,
+                }
+                Ok(())
+            })
+            .unwrap();
+    }
+    parser.finish().unwrap();
+    (file, parts)
+}
+
+#[test]
+fn file_part_is_extracted_whole() {
+    let firmware = firmware();
+    let (file, parts) = parse(&form(&firmware), usize::MAX);
+    assert_eq!(file, firmware);
+    assert_eq!(parts.len(), 2);
+    assert_eq!(parts[0].name.as_deref(), Some("sha256"));
+    assert_eq!(parts[1].filename.as_deref(), Some("ota.bin"));
+}
+
+#[test]
+fn any_chunking_gives_the_same_file() {
+    let firmware = firmware();
+    let body = form(&firmware);
+    for chunk in [1, 2, 3, 7, 1440, 4320] {
+        assert_eq!(parse(&body, chunk).0, firmware, "chunk {chunk}");
+    }
+}
+
+#[test]
+fn any_split_gives_the_same_file() {
+    // Every offset of a small image, the whole form fits in a few reads
+    let small = [&firmware()[..8192], b"\r\n--X\r\n--XY\r\n-\r\n--"].concat();
+    let body = form(&small);
+    for at in 0..=body.len() {
+        let (head, tail) = body.split_at(at);
+        let (file, parts) = parse_reads([head, tail]);
+        assert!(file == small, "split at {at}");
+        assert_eq!(parts.len(), 2, "split at {at}");
+    }
+
+    // ota.bin itself, every offset through the headers, the delimiters and
+    // the near misses at the end of the file, a stride through the rest
+    let firmware = firmware();
+    let body = form(&firmware);
+    let file_start = body.len() - firmware.len() - format!("\r\n--{BOUNDARY}--\r\n").len();
+    let near_end = body.len() - 64;
+    let splits = (0..file_start + 64)
+        .chain((file_start..near_end).step_by(4099))
+        .chain(near_end..=body.len());
+    for at in splits {
+        let (head, tail) = body.split_at(at);
+        let (file, parts) = parse_reads([head, tail]);
+        assert!(file == firmware, "split at {at}");
+        assert_eq!(
+            parts[1].filename.as_deref(),
+            Some("ota.bin"),
+            "split at {at}"
+        );
+    }
+}
+
+#[test]
+fn part_without_headers_is_plain_data() {
+    let body = format!("--{BOUNDARY}\r\n\r\nvalue\r\n--{BOUNDARY}--\r\n");
+    for chunk in [1, 2, usize::MAX] {
+        let mut parser = MultipartParser::new(BOUNDARY);
+        let mut events = Vec::new();
+        for data in body.as_bytes().chunks(chunk) {
+            parser
+                .feed(data, |event| {
+                    events.push(match event {
+                        Event::PartStart(part) => format!("start {part:?}"),
+                        Event::Data(data) => String::from_utf8(data.to_vec()).unwrap(),
+                        Event::PartEnd => "end".to_string(),
+                    });
+                    Ok(())
+                })
+                .unwrap();
+        }
+        parser.finish().unwrap();
+        let data: String = events[1..events.len() - 1].concat();
+        assert_eq!(
+            events[0],
+            format!("start {:?}", Part::default()),
+            "chunk {chunk}"
+        );
+        assert_eq!(data, "value", "chunk {chunk}");
+        assert_eq!(events.last().unwrap(), "end");
+    }
+}
+
+#[test]
+fn truncated_form_is_an_error() {
+    let body = form(b"firmware");
+    let mut parser = MultipartParser::new(BOUNDARY);
+    parser.feed(&body[..body.len() - 10], |_| Ok(())).unwrap();
+    assert!(parser.finish().is_err());
+}
+
+#[test]
+fn boundary_from_header() {
+    assert_eq!(
+        boundary_from_content_type("multipart/form-data; boundary=\"abc\"; x=1"),
+        Some("abc")
+    );
+    assert_eq!(
+        boundary_from_content_type("multipart/form-data; boundary=----abc"),
+        Some("----abc")
+    );
+    assert_eq!(boundary_from_content_type("multipart/form-data"), None);
+    // Whatever is_multipart sends here gets its boundary
+    assert_eq!(
+        boundary_from_content_type("multipart/mixed; boundary=abc"),
+        Some("abc")
+    );
+    assert_eq!(
+        boundary_from_content_type("application/octet-stream; boundary=abc"),
+        None
+    );
+}
+
+#[test]
+fn only_multipart_types_are_multipart() {
+    assert!(is_multipart("multipart/form-data; boundary=abc"));
+    assert!(is_multipart(" Multipart/Mixed"));
+    for raw in ["application/octet-stream", "text/plain", "multipart", ""] {
+        assert!(!is_multipart(raw), "{raw}");
+    }
+}
+
+// Whether the whole body parses, fed in `chunk` sized reads
+fn parses(body: &[u8], chunk: usize) -> Result<()> {
+    let mut parser = MultipartParser::new(BOUNDARY);
+    for data in body.chunks(chunk) {
+        parser.feed(data, |_| Ok(()))?;
+    }
+    parser.finish()
+}
+
+fn with_headers(padding: &str, headers: &str) -> Vec<u8> {
+    format!("--{BOUNDARY}{padding}\r\n{headers}\r\n\r\nvalue\r\n--{BOUNDARY}--\r\n").into_bytes()
+}
+
+// Found by fuzzing, the limits used to be checked only on partial reads
+#[test]
+fn header_limits_do_not_depend_on_chunking() {
+    let disposition = "Content-Disposition: form-data; name=\"a\"";
+    // The header block and its blank line take exactly 2048 bytes
+    let filler = 2048 - 4 - disposition.len() - "\r\nX-Fill: ".len();
+    let fits = format!("{disposition}\r\nX-Fill: {}", "a".repeat(filler));
+    let too_long = format!("{fits}a");
+    for chunk in [1, 7, 100, 1440, usize::MAX] {
+        assert!(
+            parses(&with_headers("", &fits), chunk).is_ok(),
+            "chunk {chunk}"
+        );
+        assert!(
+            parses(&with_headers("", &too_long), chunk).is_err(),
+            "chunk {chunk}"
+        );
+        assert!(
+            parses(&with_headers(&" ".repeat(2100), disposition), chunk).is_err(),
+            "chunk {chunk}"
+        );
+        assert!(
+            parses(&with_headers("  \t", disposition), chunk).is_ok(),
+            "chunk {chunk}"
+        );
+    }
+}
+
+#[test]
+fn odd_part_headers_do_not_panic() {
+    for (headers, ok) in [
+        ("", true),
+        ("no colon", false),
+        ("Content-Disposition: form-data; name=\"unterminated", true),
+        ("Content-Disposition: form-data; name=\"trailing\\", true),
+        (
+            "Content-Disposition: form-data; name=\"\u{e9}\"; =; ;;",
+            true,
+        ),
+    ] {
+        for chunk in [1, usize::MAX] {
+            let result = parses(&with_headers("", headers), chunk);
+            assert_eq!(result.is_ok(), ok, "{headers:?} chunk {chunk}");
+        }
+    }
+    // Not UTF-8
+    let mut body = with_headers("", "Content-Disposition: form-data; name=\"a\"");
+    let at = body.iter().position(|b| *b == b'a').unwrap();
+    body[at] = 0xff;
+    assert!(parses(&body, usize::MAX).is_err());
+}
@@ -0,0 +1,74 @@
+use std::time::{Duration, Instant};
+
+use ota_core::error::OtaError;
+use ota_core::reboot::{self, RebootWhen, MAX_DELAY};
+
+// 2023-11-14
+const UNIX_NOW: u64 = 1_700_000_000;
+
+fn parse(value: &str, now: Instant) -> Result<RebootWhen, OtaError> {
+    RebootWhen::parse_at(value, now, UNIX_NOW)
+}
+
+#[test]
+fn reboot_values_parse() {
+    let now = Instant::now();
+    assert_eq!(parse("now", now).unwrap(), RebootWhen::Now);
+    assert_eq!(parse(" later ", now).unwrap(), RebootWhen::Later);
+    assert_eq!(
+        parse("at=+60", now).unwrap(),
+        RebootWhen::At(now + Duration::from_secs(60))
+    );
+    assert_eq!(
+        parse("at=1700000300", now).unwrap(),
+        RebootWhen::At(now + Duration::from_secs(300))
+    );
+    // Already past, as soon as possible
+    assert_eq!(parse("at=1600000000", now).unwrap(), RebootWhen::At(now));
+    for value in ["soon", "at=", "at=+x", "at=-5", "at=tomorrow"] {
+        assert!(
+            matches!(parse(value, now), Err(OtaError::BadRequest(_))),
+            "{value}"
+        );
+    }
+}
+
+#[test]
+fn absolute_time_needs_the_clock() {
+    let result = RebootWhen::parse_at("at=1700000300", Instant::now(), 0);
+    assert!(matches!(result, Err(OtaError::BadRequest(_))));
+    assert!(RebootWhen::parse_at("at=+300", Instant::now(), 0).is_ok());
+}
+
+#[test]
+fn far_off_times_are_refused_without_panic() {
+    let now = Instant::now();
+    let max = MAX_DELAY.as_secs();
+    assert!(parse(&format!("at=+{max}"), now).is_ok());
+    for value in [
+        format!("at=+{}", max + 1),
+        format!("at=+{}", u64::MAX),
+        format!("at={}", UNIX_NOW + max + 1),
+        format!("at={}", u64::MAX),
+    ] {
+        match parse(&value, now) {
+            Err(OtaError::BadRequest(message)) => {
+                assert_eq!(message, "reboot: time out of range", "{value}")
+            }
+            other => panic!("{value}: {other:?}"),
+        }
+    }
+}
+
+// The only test touching the global schedule
+#[test]
+fn later_keeps_a_pending_reboot() {
+    let at = Instant::now() + Duration::from_secs(600);
+    reboot::schedule(RebootWhen::At(at));
+    reboot::schedule(RebootWhen::Later);
+    let left = reboot::reboot_in().expect("the restart was cancelled");
+    assert!(left > Duration::from_secs(590), "{left:?}");
+
+    reboot::schedule(RebootWhen::Now);
+    assert!(reboot::reboot_in().unwrap() <= Duration::from_secs(2));
+}
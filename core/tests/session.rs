@@ -0,0 +1,54 @@
+use ota_core::error::OtaError;
+use ota_core::session::{session_route, ContentRange};
+
+#[test]
+fn content_range_parses() {
+    assert_eq!(
+        ContentRange::parse("bytes 0-1023/4096"),
+        Ok(ContentRange {
+            start: 0,
+            end: 1023,
+            size: Some(4096)
+        })
+    );
+    let range = ContentRange::parse("bytes 1024-2047/*").unwrap();
+    assert_eq!(range.size, None);
+    assert_eq!(range.byte_count(), 1024);
+}
+
+#[test]
+fn bad_content_ranges_are_rejected() {
+    for header in [
+        "",
+        "0-1/2",
+        "bytes 0-1",
+        "bytes 5-4/10",
+        "bytes 0-10/10",
+        "bytes a-b/c",
+        "bytes -1-2/3",
+    ] {
+        assert!(ContentRange::parse(header).is_err(), "{header:?}");
+    }
+}
+
+#[test]
+fn session_routes() {
+    assert_eq!(session_route("/ota/session/ab12"), Some(("ab12", None)));
+    assert_eq!(
+        session_route("/ota/session/ab12/commit?reboot=later"),
+        Some(("ab12", Some("commit")))
+    );
+    assert_eq!(session_route("/ota/session/ab12/"), Some(("ab12", None)));
+    assert_eq!(session_route("/ota/session/"), None);
+    assert_eq!(session_route("/ota/status"), None);
+}
+
+#[test]
+fn unknown_session_says_to_start_over() {
+    let body = OtaError::NotFound.body(0);
+    assert_eq!(
+        (body.code, OtaError::NotFound.status()),
+        ("session_not_found", 404)
+    );
+    assert!(body.message.contains("reboot"), "{}", body.message);
+}
@@ -0,0 +1,48 @@
+use ota_core::configuration::{WifiForm, MAX_PASS_LEN, MAX_SSID_LEN};
+use ota_core::error::OtaError;
+
+fn parse(body: &str) -> Result<WifiForm, OtaError> {
+    WifiForm::parse(body.as_bytes())
+}
+
+#[test]
+fn form_fields_are_decoded() {
+    assert_eq!(
+        parse("ssid=My+Home%21&pass=p%C3%A4ss%26word").unwrap(),
+        WifiForm {
+            ssid: "My Home!".into(),
+            pass: "päss&word".into()
+        }
+    );
+    // Open networks have no passphrase, the first of a repeated field wins
+    assert_eq!(parse("pass=&ssid=cafe&ssid=other").unwrap().ssid, "cafe");
+}
+
+// These used to panic the handler on an unwrap
+#[test]
+fn missing_fields_are_bad_requests() {
+    for body in ["", "ssid=home", "pass=secret", "&&=", "ssid", "%ff=%ff"] {
+        let result = parse(body);
+        assert!(matches!(result, Err(OtaError::BadRequest(_))), "{body:?}");
+    }
+}
+
+// The Wi-Fi driver panics at the next boot on anything longer
+#[test]
+fn oversized_values_are_refused() {
+    let ssid = "s".repeat(MAX_SSID_LEN);
+    let pass = "p".repeat(MAX_PASS_LEN);
+    assert!(parse(&format!("ssid={ssid}&pass={pass}")).is_ok());
+    for body in [
+        format!("ssid={ssid}s&pass=x"),
+        format!("ssid=home&pass={pass}p"),
+        // Counted in bytes, é is two
+        format!("ssid={}&pass=x", "%C3%A9".repeat(MAX_SSID_LEN / 2 + 1)),
+        "ssid=&pass=x".to_string(),
+    ] {
+        assert!(
+            matches!(parse(&body), Err(OtaError::BadRequest(_))),
+            "{body}"
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use ota_core::verify::{
+    parse_sha256_hex, sha256_from_query, to_hex, verify_sha256, FirmwareHasher, TrailerSplitter,
+    SIGNATURE_TRAILER_LEN,
+};
+use sha2::{Digest, Sha256};
+
+fn firmware() -> Vec<u8> {
+    std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/../ota.bin")).unwrap()
+}
+
+#[test]
+fn hashing_in_chunks_matches_the_whole_file() {
+    let firmware = firmware();
+    let whole: [u8; 32] = Sha256::digest(&firmware).into();
+    for chunk in [1, 7, 1440, 4320] {
+        let mut hasher = FirmwareHasher::new();
+        for data in firmware.chunks(chunk) {
+            hasher.update(data);
+        }
+        assert_eq!(hasher.finalize(), whole, "chunk {chunk}");
+    }
+}
+
+#[test]
+fn digest_does_not_end_hashing() {
+    let firmware = firmware();
+    let (head, tail) = firmware.split_at(1000);
+    let mut hasher = FirmwareHasher::new();
+    hasher.update(head);
+    assert_eq!(hasher.digest(), <[u8; 32]>::from(Sha256::digest(head)));
+    hasher.update(tail);
+    assert_eq!(
+        hasher.finalize(),
+        <[u8; 32]>::from(Sha256::digest(&firmware))
+    );
+}
+
+#[test]
+fn sha256_hex_is_checked() {
+    let digest: [u8; 32] = Sha256::digest(firmware()).into();
+    let hex = to_hex(&digest);
+    assert_eq!(parse_sha256_hex(&hex).unwrap(), digest);
+    assert_eq!(
+        parse_sha256_hex(&format!(" {} ", hex.to_uppercase())).unwrap(),
+        digest
+    );
+    assert!(parse_sha256_hex(&hex[1..]).is_err());
+    assert!(parse_sha256_hex(&format!("{}zz", &hex[2..])).is_err());
+    assert!(parse_sha256_hex("").is_err());
+}
+
+#[test]
+fn sha256_from_query_string() {
+    let hex = "ab".repeat(32);
+    assert_eq!(
+        sha256_from_query(&format!("reboot=later&sha256={hex}"))
+            .unwrap()
+            .unwrap(),
+        [0xab; 32]
+    );
+    assert!(sha256_from_query("reboot=later").is_none());
+    assert!(sha256_from_query("sha256=abc").unwrap().is_err());
+}
+
+#[test]
+fn mismatch_names_both_digests() {
+    let error = verify_sha256(&[1; 32], &[2; 32]).unwrap_err().to_string();
+    assert!(error.contains(&"01".repeat(32)), "{error}");
+    assert!(error.contains(&"02".repeat(32)), "{error}");
+    assert!(verify_sha256(&[1; 32], &[1; 32]).is_ok());
+}
+
+#[test]
+fn trailer_is_held_back_whatever_the_chunking() {
+    let firmware = firmware();
+    let (body, trailer) = firmware.split_at(firmware.len() - SIGNATURE_TRAILER_LEN);
+    for chunk in [1, 71, 72, 73, 1440] {
+        let mut splitter = TrailerSplitter::new();
+        let mut out = Vec::new();
+        for data in firmware.chunks(chunk) {
+            splitter
+                .push(data, |passed| {
+                    out.extend_from_slice(passed);
+                    Ok(())
+                })
+                .unwrap();
+        }
+        assert_eq!(out, body, "chunk {chunk}");
+        assert_eq!(splitter.into_trailer(), trailer, "chunk {chunk}");
+    }
+}
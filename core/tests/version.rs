@@ -0,0 +1,43 @@
+use ota_core::version::{RollbackPolicy, Version};
+
+fn version(v: &str) -> Version {
+    Version::parse(v).unwrap()
+}
+
+#[test]
+fn versions_order() {
+    assert!(version("1.2.3") > version("1.2.3-rc.1"));
+    assert!(version("1.2.3-rc.2") > version("1.2.3-rc.1"));
+    assert!(version("1.2.3-rc.10") > version("1.2.3-rc.2"));
+    assert!(version("v2") > version("1.9.9"));
+    assert_eq!(version("1.0+build"), version("1.0.0"));
+    assert!(Version::parse("9269a53-dirty").is_none());
+}
+
+#[test]
+fn rollback_policy() {
+    let policy = RollbackPolicy {
+        running_version: "1.2.0".into(),
+        min_secure_version: Some(3),
+        force: false,
+    };
+    assert!(policy.check("1.1.0", 3).is_err());
+    assert!(policy.check("1.3.0", 2).is_err());
+    assert!(policy.check("1.3.0", 3).is_ok());
+    // Not comparable either way, refused
+    assert!(policy.check("garbage", 3).is_err());
+    let unknown_running = RollbackPolicy {
+        running_version: "9269a53-dirty".into(),
+        ..policy.clone()
+    };
+    assert!(unknown_running.check("1.3.0", 3).is_err());
+
+    let forced = RollbackPolicy {
+        force: true,
+        ..policy
+    };
+    assert!(forced.check("1.1.0", 3).is_ok());
+    assert!(forced.check("garbage", 3).is_ok());
+    // The secure version cannot be forced past
+    assert!(forced.check("1.1.0", 2).is_err());
+}
@@ -0,0 +1,59 @@
+// Admin authentication for the mutating routes
+//
+// Requests carry HTTP Basic (`curl -u admin:...`) or a bearer token from
+// POST /api/auth/token. Until the first credential is set every protected
+// route answers 403 setup_required and the pages send the browser to /setup.
+// Forgotten credentials can only be cleared by erasing NVS over serial.
+
+use embedded_svc::http::Headers;
+use esp_idf_svc::http::server::EspHttpRequest;
+use log::info;
+
+use crate::credential::{self, SetupRequest, SALT_LEN, TOKEN_LEN};
+use crate::error::OtaError;
+use crate::APP_CONFIG;
+
+pub fn configured() -> bool {
+    APP_CONFIG
+        .read()
+        .map_or(false, |app_config| credential::configured(&app_config.auth))
+}
+
+/// Ok when the request carries the admin password or the current bearer token.
+pub fn authorize(req: &EspHttpRequest) -> Result<(), OtaError> {
+    let auth = APP_CONFIG
+        .read()
+        .map_err(|_| OtaError::Internal("Failed to get read lock".to_string()))?
+        .auth
+        .clone();
+    credential::authorize(&auth, req.header("Authorization"))
+}
+
+/// Replaces the admin credential, revoking any bearer token.
+pub fn set_credential(request: SetupRequest) -> Result<(), OtaError> {
+    let mut app_config = APP_CONFIG
+        .write()
+        .map_err(|_| OtaError::Internal("Failed to get write lock".to_string()))?;
+    credential::set_credential(&mut app_config.auth, &request, random::<SALT_LEN>())?;
+    app_config.store_values_to_nvs()?;
+    info!("Admin credential set for {}", request.username.trim());
+    Ok(())
+}
+
+/// A new bearer token, shown once. Only its hash is kept.
+pub fn new_token() -> Result<String, OtaError> {
+    let mut app_config = APP_CONFIG
+        .write()
+        .map_err(|_| OtaError::Internal("Failed to get write lock".to_string()))?;
+    let token = credential::set_token(&mut app_config.auth, random::<TOKEN_LEN>());
+    app_config.store_values_to_nvs()?;
+    info!("New API token issued");
+    Ok(token)
+}
+
+// Hardware RNG, true random once Wi-Fi is up
+fn random<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    unsafe { esp_idf_sys::esp_fill_random(bytes.as_mut_ptr() as *mut _, N as _) };
+    bytes
+}
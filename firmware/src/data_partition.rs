@@ -0,0 +1,250 @@
+// Updates for data partitions, e.g. the `test` spiffs partition
+//
+// PUT /api/data/{label} with a blob or filesystem image, signed like firmware.
+// Like an app slot it is erased up front and written as the upload streams
+// in, but there is no second copy to boot from meanwhile. The final sector is
+// held in RAM and only written once the image has been verified, and a
+// rejected image is erased again, so readers never find a complete looking
+// unverified image. The spare app slot and its rollback image stay untouched.
+
+use esp_idf_svc::http::server::EspHttpRequest;
+use esp_idf_sys::{
+    esp, esp_partition_erase_range, esp_partition_find_first, esp_partition_subtype_t,
+    esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY,
+    esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_COREDUMP,
+    esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_EFUSE_EM,
+    esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_NVS,
+    esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_NVS_KEYS,
+    esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_OTA,
+    esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_PHY, esp_partition_t,
+    esp_partition_type_t_ESP_PARTITION_TYPE_DATA, esp_partition_write,
+};
+use log::info;
+use std::ffi::CString;
+use std::time::{Duration, Instant};
+
+use embedded_svc::http::server::Request;
+use embedded_svc::http::Headers;
+use embedded_svc::io::Read;
+
+use crate::error::OtaError;
+use crate::ota_lock::OtaLock;
+use crate::status;
+use crate::verify::{
+    parse_public_key_hex, parse_sha256_hex, sha256_from_query, to_hex, verify_sha256,
+    verify_signature, FirmwareHasher, Sha256Digest, TrailerSplitter, SHA256_HEADER,
+    SIGNATURE_TRAILER_LEN,
+};
+use crate::OTA_PUBLIC_KEY;
+
+// Flash erase granularity
+const SECTOR: usize = 4096;
+
+// Partitions ESP-IDF itself depends on
+const SYSTEM_SUBTYPES: [esp_partition_subtype_t; 6] = [
+    esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_OTA,
+    esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_PHY,
+    esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_NVS,
+    esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_COREDUMP,
+    esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_NVS_KEYS,
+    esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_EFUSE_EM,
+];
+
+/// Partition label from /api/data/{label}
+pub fn data_route(uri: &str) -> Option<&str> {
+    let path = uri.split('?').next()?;
+    path.strip_prefix("/api/data/")
+        .filter(|label| !label.is_empty() && !label.contains('/'))
+}
+
+pub fn data_upload(req: &mut EspHttpRequest, label: &str) -> Result<Instant, OtaError> {
+    let _lock = OtaLock::acquire("data upload")?;
+    let start_time = Instant::now();
+    status::update(|s| s.begin(req.content_len(), start_time));
+    let result = receive_data(req, label);
+    status::finish(&result);
+    result.map(|_| start_time)
+}
+
+fn receive_data(req: &mut EspHttpRequest, label: &str) -> Result<(), OtaError> {
+    let content_len = match req.content_len() {
+        Some(len) if len > 0 => len,
+        _ => return Err(OtaError::MissingLength),
+    };
+    let expected_sha256 = match req.header(SHA256_HEADER) {
+        Some(hex) => Some(parse_sha256_hex(hex).map_err(|e| OtaError::BadRequest(e.to_string()))?),
+        None => sha256_from_query(&req.query_string())
+            .transpose()
+            .map_err(|e| OtaError::BadRequest(e.to_string()))?,
+    };
+    let public_key = parse_public_key_hex(OTA_PUBLIC_KEY)?;
+    let mut writer = DataWriter::begin(label, content_len)?;
+
+    let result = (|| -> Result<(), OtaError> {
+        let start_time = Instant::now();
+        let mut buf = Box::new([0u8; 1440 * 3]);
+        loop {
+            let bytelen = req
+                .reader()
+                .read(&mut *buf)
+                .map_err(|e| OtaError::Transfer(format!("{:?}", e)))?;
+            if bytelen == 0 {
+                break;
+            }
+            writer.write(&buf[..bytelen])?;
+            if start_time.elapsed() > Duration::from_millis(900) {
+                std::thread::sleep(Duration::from_millis(10)) //wdt
+            }
+        }
+        writer.verify(expected_sha256, &public_key)
+    })();
+    writer.finalise(result)
+}
+
+/// Streams an image into a data partition, holding back the signature
+/// trailer and the final sector until verified.
+pub struct DataWriter {
+    partition: *const esp_partition_t,
+    label: String,
+    size: usize,
+    // Image offset of the final sector, from there on it is kept in `tail`
+    held_from: usize,
+    tail: Vec<u8>,
+    splitter: TrailerSplitter,
+    hasher: FirmwareHasher,
+    received: usize,
+    written: usize,
+}
+
+impl DataWriter {
+    /// Finds the partition and erases it, once the upload is known to fit.
+    pub fn begin(label: &str, upload_len: usize) -> Result<Self, OtaError> {
+        let c_label = CString::new(label).map_err(|_| OtaError::SlotNotFound(label.to_string()))?;
+        let partition = unsafe {
+            esp_partition_find_first(
+                esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+                esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY,
+                c_label.as_ptr(),
+            )
+        };
+        if partition.is_null() {
+            return Err(OtaError::SlotNotFound(label.to_string()));
+        }
+        let (subtype, size) = unsafe { ((*partition).subtype, (*partition).size as usize) };
+        if SYSTEM_SUBTYPES.contains(&subtype) {
+            return Err(OtaError::BadRequest(format!(
+                "Partition {label} holds system data and cannot be replaced"
+            )));
+        }
+        let image_len = upload_len.saturating_sub(SIGNATURE_TRAILER_LEN);
+        if image_len > size {
+            return Err(OtaError::InvalidImage(format!(
+                "{image_len} byte image does not fit the {size} byte partition {label}"
+            )));
+        }
+        info!("Erasing data partition {label}, {size} bytes");
+        esp!(unsafe { esp_partition_erase_range(partition, 0, size as _) })
+            .map_err(|e| OtaError::Flash(format!("erasing {label}: {e}")))?;
+        Ok(Self {
+            partition,
+            label: label.to_string(),
+            size,
+            held_from: image_len.saturating_sub(1) / SECTOR * SECTOR,
+            tail: Vec::with_capacity(SECTOR),
+            splitter: TrailerSplitter::new(),
+            hasher: FirmwareHasher::new(),
+            received: 0,
+            written: 0,
+        })
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> Result<(), OtaError> {
+        self.received += data.len();
+        let (partition, size, held_from) = (self.partition, self.size, self.held_from);
+        let (hasher, written, tail) = (&mut self.hasher, &mut self.written, &mut self.tail);
+        self.splitter.push(data, |image| {
+            if *written + image.len() > size {
+                return Err(OtaError::InvalidImage(format!(
+                    "Image overruns the {size} byte partition"
+                ))
+                .into());
+            }
+            let flash_len = held_from.saturating_sub(*written).min(image.len());
+            if flash_len > 0 {
+                esp!(unsafe {
+                    esp_partition_write(
+                        partition,
+                        *written as _,
+                        image.as_ptr() as _,
+                        flash_len as _,
+                    )
+                })
+                .map_err(|e| OtaError::Flash(format!("at {} bytes: {e}", *written)))?;
+            }
+            tail.extend_from_slice(&image[flash_len..]);
+            hasher.update(image);
+            *written += image.len();
+            Ok(())
+        })?;
+        let received = self.received;
+        status::update(|s| s.progress(received, Instant::now()));
+        Ok(())
+    }
+
+    /// Same checks as firmware: signature over the image, SHA-256 over the uploaded file.
+    pub fn verify(
+        &mut self,
+        expected_sha256: Option<Sha256Digest>,
+        public_key: &[u8; 32],
+    ) -> Result<(), OtaError> {
+        status::update(|s| s.verifying());
+        let trailer = std::mem::take(&mut self.splitter).into_trailer();
+        verify_signature(public_key, &self.hasher.digest(), &trailer)
+            .map_err(|e| OtaError::Signature(e.to_string()))?;
+
+        let mut file_hasher = self.hasher.clone();
+        file_hasher.update(&trailer);
+        let file_digest = file_hasher.finalize();
+        if let Some(expected) = expected_sha256 {
+            verify_sha256(&expected, &file_digest)
+                .map_err(|e| OtaError::Checksum(e.to_string()))?;
+        }
+        info!(
+            "Data image for {} verified, {} bytes, SHA-256 {}",
+            self.label,
+            self.written,
+            to_hex(&file_digest)
+        );
+        Ok(())
+    }
+
+    /// Writes the final sector if everything checked out, otherwise leaves the
+    /// partition erased.
+    pub fn finalise(self, result: Result<(), OtaError>) -> Result<(), OtaError> {
+        let result = result.and_then(|_| {
+            if self.tail.is_empty() {
+                return Ok(());
+            }
+            esp!(unsafe {
+                esp_partition_write(
+                    self.partition,
+                    self.held_from as _,
+                    self.tail.as_ptr() as _,
+                    self.tail.len() as _,
+                )
+            })
+            .map_err(|e| OtaError::Flash(format!("at {} bytes: {e}", self.held_from)))
+        });
+        if let Err(e) = result {
+            eprintln!("Data update of {} aborted: {e}", self.label);
+            esp!(unsafe { esp_partition_erase_range(self.partition, 0, self.size as _) })
+                .map_err(|e| OtaError::Flash(format!("erasing {}: {e}", self.label)))?;
+            return Err(e);
+        }
+        info!(
+            "Data partition {} written, {} bytes",
+            self.label, self.written
+        );
+        Ok(())
+    }
+}
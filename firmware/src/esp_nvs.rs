@@ -0,0 +1,52 @@
+// NvsStorage on the device's NVS partition
+
+use anyhow::{anyhow, Context};
+use embedded_svc::storage::{RawStorage, StorageBase};
+use esp_idf_svc::nvs_storage::EspNvsStorage;
+use log::info;
+
+use crate::configuration::{NvsStorage, MAX_VALUE_LEN};
+
+/// The settings namespace. A wrapper as neither the trait nor `EspNvsStorage` is ours.
+pub struct EspStorage(pub EspNvsStorage);
+
+impl NvsStorage for EspStorage {
+    fn contains(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(self.0.contains(key)?)
+    }
+
+    fn get_val(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        if !self.0.contains(key)? {
+            return Err(anyhow!("NVS Key:{key} Not found"));
+        }
+        let len = self
+            .0
+            .len(key)?
+            .context(format!("NVS Key:{key} Bad len check"))?;
+        let mut buf = [0u8; MAX_VALUE_LEN];
+
+        self.0.get_raw(key, &mut buf)?;
+        // Keys only, the values include the admin credential
+        info!("NVS Read {key}, {len}b");
+        Ok(buf[0..len].to_owned())
+    }
+
+    fn set_val(&mut self, key: &str, val: &[u8]) -> anyhow::Result<bool> {
+        if key.is_empty() {
+            panic!("set_val attempted to write to NVS with zero length key")
+        }
+        // Would write fine but never read back
+        if val.len() > MAX_VALUE_LEN {
+            return Err(anyhow!(
+                "NVS Key:{key} value is {}b, limit {MAX_VALUE_LEN}b",
+                val.len()
+            ));
+        }
+        info!("NVS Write {key}, {}b", val.len());
+        Ok(self.0.put_raw(key, val)?)
+    }
+
+    fn remove(&mut self, key: &str) -> anyhow::Result<bool> {
+        Ok(self.0.remove(key)?)
+    }
+}
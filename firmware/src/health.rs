@@ -0,0 +1,170 @@
+// Post-update probation
+//
+// A freshly flashed build boots as ESP_OTA_IMG_PENDING_VERIFY. It is only
+// marked valid once it has stayed up for `probation_secs` with every check
+// passing; if that has not happened by `timeout_secs` the bootloader is told to
+// roll back to the previous slot. Builds that are already valid skip all this.
+// The checks run on their own thread so the main loop keeps serving reboots
+// and session expiry meanwhile, and a build that fails to come up at all is
+// rolled back by `boot_failed`.
+
+use anyhow::{anyhow, bail, Result};
+use embedded_svc::http::client::{Client, Request as _, Response as _};
+use embedded_svc::http::Status as _;
+use embedded_svc::ipv4::Ipv4Addr;
+use esp_idf_svc::http::client::EspHttpClient;
+use esp_idf_sys::{
+    esp, esp_netif_get_handle_from_ifkey, esp_netif_get_ip_info, esp_netif_ip_info_t,
+    esp_ota_mark_app_invalid_rollback_and_reboot, esp_wifi_sta_get_ap_info, wifi_ap_record_t,
+};
+use log::{info, warn};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::configuration::{HealthCheck, HealthSettings};
+use crate::ota;
+use crate::wifi_init;
+
+const ROUND_INTERVAL: Duration = Duration::from_secs(5);
+
+type UserCheck = Box<dyn FnMut() -> Result<()> + Send>;
+
+pub struct Probation {
+    settings: HealthSettings,
+    user_checks: Vec<(&'static str, UserCheck)>,
+}
+
+impl Probation {
+    pub fn new(settings: HealthSettings) -> Self {
+        Self {
+            settings,
+            user_checks: Vec::new(),
+        }
+    }
+
+    /// Adds an application specific check, run after the configured ones.
+    pub fn with_check(
+        mut self,
+        name: &'static str,
+        check: impl FnMut() -> Result<()> + Send + 'static,
+    ) -> Self {
+        self.user_checks.push((name, Box::new(check)));
+        self
+    }
+
+    /// Runs the probation on its own thread, `confirmed` once the build is marked valid.
+    pub fn spawn(
+        self,
+        boot_time: Instant,
+        confirmed: impl FnOnce() -> Result<()> + Send + 'static,
+    ) -> Result<()> {
+        thread::Builder::new()
+            .name("probation".into())
+            // The http_server check
+            .stack_size(8 * 1024)
+            .spawn(move || {
+                if let Err(e) = self.run(boot_time).and_then(|_| confirmed()) {
+                    warn!("Probation: {e}");
+                }
+            })?;
+        Ok(())
+    }
+
+    /// Blocks until the running build is confirmed. Does not return if it is rolled back.
+    pub fn run(mut self, boot_time: Instant) -> Result<()> {
+        if !ota::pending_verify()? {
+            return ota::mark_app_valid(true);
+        }
+        let probation = Duration::from_secs(self.settings.probation_secs);
+        let timeout = Duration::from_secs(self.settings.timeout_secs).max(probation);
+        info!(
+            "New firmware on probation for {:?}, checks {:?} + {} user checks",
+            probation,
+            self.settings.checks,
+            self.user_checks.len()
+        );
+        loop {
+            let passed = self.round();
+            let uptime = boot_time.elapsed();
+            if passed && uptime >= probation {
+                info!("Health checks passed after {:?}, confirming update", uptime);
+                return ota::mark_app_valid(true);
+            }
+            if uptime >= timeout {
+                warn!(
+                    "Health checks still failing after {:?}, rolling back",
+                    uptime
+                );
+                return ota::mark_app_valid(false);
+            }
+            thread::sleep(ROUND_INTERVAL);
+        }
+    }
+
+    fn round(&mut self) -> bool {
+        let mut passed = true;
+        for check in self.settings.checks.clone() {
+            if let Err(e) = self.check(check) {
+                warn!("Health check {:?} failed: {e}", check);
+                passed = false;
+            }
+        }
+        for (name, check) in self.user_checks.iter_mut() {
+            if let Err(e) = check() {
+                warn!("Health check {name} failed: {e}");
+                passed = false;
+            }
+        }
+        passed
+    }
+
+    fn check(&self, check: HealthCheck) -> Result<()> {
+        match check {
+            HealthCheck::WifiSta => sta_gateway().map(|_| ()),
+            HealthCheck::GatewayPing => wifi_init::ping_gateway(sta_gateway()?),
+            HealthCheck::HttpServer => http_server(),
+        }
+    }
+}
+
+/// For main when bringing the device up fails. A build on probation is rolled
+/// back straight away, it would otherwise sit there without network until the
+/// next reset.
+pub fn boot_failed(e: &anyhow::Error) {
+    if !matches!(ota::pending_verify(), Ok(true)) {
+        return;
+    }
+    warn!("New firmware failed to start: {e:#}, rolling back");
+    // Only returns if there is nothing to roll back to
+    if let Err(err) = esp!(unsafe { esp_ota_mark_app_invalid_rollback_and_reboot() }) {
+        warn!("Rollback failed: {err}");
+    }
+}
+
+// Straight from esp_netif and the Wi-Fi driver, the EspWifi stays with main
+fn sta_gateway() -> Result<Ipv4Addr> {
+    let mut ap: wifi_ap_record_t = unsafe { std::mem::zeroed() };
+    esp!(unsafe { esp_wifi_sta_get_ap_info(&mut ap) })
+        .map_err(|e| anyhow!("Wifi sta not connected: {e}"))?;
+    let netif = unsafe { esp_netif_get_handle_from_ifkey(b"WIFI_STA_DEF\0".as_ptr() as _) };
+    if netif.is_null() {
+        bail!("Wifi sta not started");
+    }
+    let mut ip_info: esp_netif_ip_info_t = unsafe { std::mem::zeroed() };
+    esp!(unsafe { esp_netif_get_ip_info(netif, &mut ip_info) })?;
+    if ip_info.ip.addr == 0 {
+        bail!("Wifi sta has no IP address yet");
+    }
+    // Network byte order
+    Ok(Ipv4Addr::from(ip_info.gw.addr.to_le_bytes()))
+}
+
+// Our own server, over loopback
+fn http_server() -> Result<()> {
+    let mut client = EspHttpClient::new_default()?;
+    let response = client.get("http://127.0.0.1/id")?.submit()?;
+    if response.status() != 200 {
+        bail!("GET /id answered HTTP {}", response.status());
+    }
+    Ok(())
+}
@@ -0,0 +1,611 @@
+use embedded_svc::http::server::registry::Registry;
+use embedded_svc::http::server::{Request, Response};
+// use embedded_svc::http::Headers;
+use embedded_svc::http::{SendHeaders, SendStatus};
+use embedded_svc::io::adapters::ToStd;
+use esp_idf_hal::mutex::{Condvar, Mutex};
+use esp_idf_svc::http::server::{Configuration, EspHttpRequest, EspHttpResponse, EspHttpServer};
+use esp_idf_svc::netif::EspNetifStack;
+use esp_idf_svc::nvs::EspDefaultNvs;
+use esp_idf_svc::nvs_storage::EspNvsStorage;
+use esp_idf_svc::sysloop::EspSysLoopStack;
+use esp_idf_svc::wifi::EspWifi;
+use esp_idf_sys as _; // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
+use esp_idf_sys::{self as _};
+
+use crate::configuration::{AppConfiguration, NvsStruct, Wifi, WifiForm};
+use crate::error::OtaError;
+use crate::esp_nvs::EspStorage;
+use crate::fetch::FetchRequest;
+use crate::reboot::RebootWhen;
+use lazy_static::lazy_static;
+use log::*;
+use std::env;
+use std::io::Read;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+mod auth;
+mod data_partition;
+mod esp_nvs;
+mod health;
+mod ota;
+mod ota_session;
+mod slots;
+mod updater;
+mod wifi_init;
+// The hardware independent part, see core/
+use ota_core::{
+    configuration, credential, delta, error, fetch, image, manifest, ota_lock, reboot, session,
+    status, upload, verify, version,
+};
+#[macro_use]
+extern crate dotenv_codegen;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+const HTMLOTA: &str = include_str!("html/ota.html");
+
+const HTMLSETTINGS: &str = include_str!("html/settings.html");
+
+const HTMLINDEX: &str = include_str!("html/index.html");
+
+const HTMLSETUP: &str = include_str!("html/setup.html");
+
+const WIFI_SSID_KEY: &str = dotenv!("WSSID");
+const WIFI_PASS_KEY: &str = dotenv!("WPASS");
+const AP_SSID_KEY: &str = dotenv!("APSSID");
+const AP_PASS_KEY: &str = dotenv!("APPASS");
+const OTA_PUBLIC_KEY: &str = dotenv!("OTA_PUBKEY");
+
+use std::sync::RwLock;
+
+lazy_static! {
+    static ref APP_CONFIG: RwLock<AppConfiguration<EspStorage>> =
+        RwLock::new(AppConfiguration::default());
+}
+
+fn main() -> anyhow::Result<()> {
+    // Temporary. Will disappear once ESP-IDF 4.4 is released, but for now it is necessary to call this function once,
+    // or else some patches to the runtime implemented by esp-idf-sys might not link properly.
+    esp_idf_sys::link_patches();
+    esp_idf_svc::log::EspLogger::initialize_default();
+    let result = boot();
+    if let Err(e) = &result {
+        health::boot_failed(e);
+    }
+    result
+}
+
+fn boot() -> anyhow::Result<()> {
+    let boot_time = Instant::now();
+    let nvs = Arc::new(EspDefaultNvs::new()?);
+    let nvs_storage = Arc::new(RwLock::new(EspStorage(
+        EspNvsStorage::new_default(nvs.clone(), "config", true).unwrap(),
+    )));
+
+    if let Ok(mut app_config) = APP_CONFIG.write() {
+        let sta = Wifi {
+            ssid: Some(WIFI_SSID_KEY.to_owned()),
+            pass: Some(WIFI_PASS_KEY.to_owned()),
+            ..Default::default()
+        };
+        let ap = Wifi {
+            ssid: Some(AP_SSID_KEY.to_owned()),
+            pass: Some(AP_PASS_KEY.to_owned()),
+            ..Default::default()
+        };
+        if let Err(e) = app_config.init(nvs_storage.clone(), sta, ap) {
+            panic!("{e}");
+        };
+        // Channels 1-13, start over past the last
+        if let Some(val) = app_config.ap.channel.filter(|val| *val < 13) {
+            app_config.ap.channel = Some(val + 1);
+            app_config.ap.write_to_nvs(&nvs_storage.clone())?;
+        } else {
+            app_config.ap.channel = Some(1);
+            app_config.ap.write_to_nvs(&nvs_storage.clone())?;
+        }
+    }
+    #[allow(unused)]
+    let netif_stack = Arc::new(EspNetifStack::new()?);
+    #[allow(unused)]
+    let sys_loop_stack = Arc::new(EspSysLoopStack::new()?);
+
+    let (sta, ap) = if let Ok(app_config) = APP_CONFIG.read() {
+        (app_config.sta.to_owned(), app_config.ap.to_owned())
+    } else {
+        panic!()
+    };
+    let mut wifi = Box::new(EspWifi::new(netif_stack, sys_loop_stack, nvs)?);
+    let wifi_scan = wifi_init::scan(&mut wifi);
+    wifi = wifi_init::wifi(wifi, sta, ap)?;
+    // Wall clock for reboot=at=<unix time>
+    let sntp = esp_idf_svc::sntp::EspSntp::new_default()?;
+
+    let mutex = Arc::new((Mutex::new(None), Condvar::new()));
+    let httpd = httpd(mutex)?;
+
+    println!("FW version: {} testing", VERSION);
+    println!("{:?}", wifi_scan);
+
+    let health_settings = APP_CONFIG.read().unwrap().health.clone();
+    health::Probation::new(health_settings)
+        .with_check("free heap", || {
+            let free = unsafe { esp_idf_sys::esp_get_free_heap_size() };
+            if free < 16 * 1024 {
+                anyhow::bail!("only {free} bytes of heap left");
+            }
+            Ok(())
+        })
+        // Only a confirmed build goes looking for the next one
+        .spawn(boot_time, updater::spawn)?;
+
+    // Updates and boot selection schedule the reboot, see reboot.rs
+    while !reboot::due() {
+        thread::sleep(Duration::from_secs(1));
+        ota_session::expire_stalled();
+    }
+    log::info!("Restart requested");
+    if let Err(e) = ota::activate_staged() {
+        warn!("Staged update not activated, booting the running image again: {e}");
+    }
+    drop(httpd);
+    info!("Httpd stopped");
+
+    {
+        drop(sntp);
+        drop(wifi);
+        info!("Wifi stopped");
+    }
+
+    unsafe {
+        info!("Restarting...");
+        esp_idf_sys::esp_restart();
+    }
+    Ok(())
+}
+fn httpd(_mutex: Arc<(Mutex<Option<u32>>, Condvar)>) -> anyhow::Result<EspHttpServer> {
+    // Wildcards are needed for the /ota/session/{id} routes
+    let mut server = EspHttpServer::new(&Configuration {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })?;
+
+    server
+        .handle_get("/id", |_req, resp| {
+            resp.send_str("OTATest")?;
+            Ok(())
+        })?
+        .handle_get("/", move |_req, resp| page(HTMLINDEX, resp))?
+        // Holds the Wi-Fi passwords
+        .handle_get("/json", move |req, resp| {
+            if let Err(e) = auth::authorize(&req) {
+                return ota_error(e, resp);
+            }
+            let config = APP_CONFIG.read().unwrap().to_owned();
+            if let Ok(payload) = serde_json::to_string(&config) {
+                resp.send_bytes(payload.as_bytes())?;
+            }
+            Ok(())
+        })?
+        .handle_get("/restart", |req, resp| {
+            if let Err(e) = auth::authorize(&req) {
+                return ota_error(e, resp);
+            }
+            info!("Restart requested");
+            reboot::schedule(RebootWhen::Now);
+            resp.send_str("Rebooting")?;
+            Ok(())
+        })?
+        .handle_get("/settings", |_req, resp| page(HTMLSETTINGS, resp))?
+        .handle_post(
+            "/settings",
+            |mut req, resp| -> Result<(), embedded_svc::http::server::HandlerError> {
+                if let Err(e) = auth::authorize(&req) {
+                    return ota_error(e, resp);
+                }
+                let mut body = Vec::new();
+
+                ToStd::new(req.reader()).read_to_end(&mut body)?;
+
+                let WifiForm { ssid, pass } = match WifiForm::parse(&body) {
+                    Ok(form) => form,
+                    Err(e) => return ota_error(e, resp),
+                };
+                if let Ok(mut app_config) = APP_CONFIG.write() {
+                    app_config.sta.ssid = Some(ssid.to_owned());
+                    app_config.sta.pass = Some(pass.to_owned());
+                    app_config.store_values_to_nvs()?;
+                }
+                resp.send_str(&format!(
+                    "Wifi setup completed for SSID: {} Password: {}, please reboot to connect",
+                    ssid, pass
+                ))?;
+
+                Ok(())
+            },
+        )?
+        .handle_get("/ota", |_req, resp| page(HTMLOTA, resp))?
+        // *********** Admin credential, see auth.rs
+        .handle_get("/setup", |_req, resp| {
+            resp.send_str(HTMLSETUP)?;
+            Ok(())
+        })?
+        // Open until the first credential is set, after that it changes it
+        .handle_post(
+            "/api/auth/setup",
+            |mut req, resp| -> Result<(), embedded_svc::http::server::HandlerError> {
+                if auth::configured() {
+                    if let Err(e) = auth::authorize(&req) {
+                        return ota_error(e, resp);
+                    }
+                }
+                let mut body = Vec::new();
+                ToStd::new(req.reader()).read_to_end(&mut body)?;
+                let result = serde_json::from_slice(&body)
+                    .map_err(|e| OtaError::BadRequest(e.to_string()))
+                    .and_then(auth::set_credential);
+                match result {
+                    Ok(()) => resp.send_str("Admin credential saved")?,
+                    Err(e) => return ota_error(e, resp),
+                };
+                Ok(())
+            },
+        )?
+        .handle_post("/api/auth/token", |req, resp| {
+            if let Err(e) = auth::authorize(&req) {
+                return ota_error(e, resp);
+            }
+            match auth::new_token() {
+                Ok(token) => resp
+                    .header("Content-Type", "application/json")
+                    .send_str(&serde_json::json!({ "token": token }).to_string())?,
+                Err(e) => return ota_error(e, resp),
+            };
+            Ok(())
+        })?
+        // ?seq=<last seen seq> holds the request until the status changes. Kept
+        // short, httpd serves one request at a time.
+        .handle_get("/ota/status", |req, resp| {
+            let seen = url::form_urlencoded::parse(req.query_string().as_bytes())
+                .find(|(k, _)| k == "seq")
+                .and_then(|(_, v)| v.parse::<u32>().ok());
+            let current = match seen {
+                Some(seen) => status::wait_for_change(seen, Duration::from_secs(2)),
+                None => status::snapshot(),
+            };
+            resp.send_str(&serde_json::to_string(&current)?)?;
+            Ok(())
+        })?
+        .handle_get("/api/ota/slots", |_req, resp| {
+            match slots::app_slots() {
+                Ok(slots) => resp
+                    .header("Content-Type", "application/json")
+                    .send_str(&serde_json::to_string(&slots)?)?,
+                Err(e) => return ota_error(e.into(), resp),
+            };
+            Ok(())
+        })?
+        // *********** Boot slot selection, see slots.rs
+        .handle_post("/api/ota/rollback", |req, resp| {
+            if let Err(e) = auth::authorize(&req) {
+                return ota_error(e, resp);
+            }
+            let result = RebootWhen::from_query(&req.query_string())
+                .and_then(|when| Ok((slots::rollback()?, when)));
+            boot_response(result, resp)
+        })?
+        .handle_post("/api/ota/boot/*", |req, resp| {
+            if let Err(e) = auth::authorize(&req) {
+                return ota_error(e, resp);
+            }
+            let result = RebootWhen::from_query(&req.query_string()).and_then(|when| {
+                let uri = req.uri();
+                let label =
+                    slots::boot_route(&uri).ok_or_else(|| OtaError::SlotNotFound(String::new()))?;
+                Ok((slots::set_boot_slot(label)?, when))
+            });
+            boot_response(result, resp)
+        })?
+        // *********** Staged update, see reboot.rs
+        .handle_get("/api/ota/pending", |_req, resp| {
+            match slots::pending() {
+                Ok(pending) => resp
+                    .header("Content-Type", "application/json")
+                    .send_str(&serde_json::to_string(&pending)?)?,
+                Err(e) => return ota_error(e.into(), resp),
+            };
+            Ok(())
+        })?
+        .handle_post("/api/ota/apply", |req, resp| {
+            if let Err(e) = auth::authorize(&req) {
+                return ota_error(e, resp);
+            }
+            match slots::pending() {
+                Ok(pending) if pending.staged => {
+                    reboot::schedule(RebootWhen::Now);
+                    resp.send_str(&RebootWhen::Now.to_string())?;
+                }
+                Ok(_) => return ota_error(OtaError::NothingStaged, resp),
+                Err(e) => return ota_error(e.into(), resp),
+            }
+            Ok(())
+        })?
+        // *********** Update server, see updater.rs
+        .handle_get("/api/ota/update", |_req, resp| {
+            resp.header("Content-Type", "application/json")
+                .send_str(&serde_json::to_string(&updater::status())?)?;
+            Ok(())
+        })?
+        .handle_post("/api/ota/update/check", |req, resp| {
+            if let Err(e) = auth::authorize(&req) {
+                return ota_error(e, resp);
+            }
+            match updater::check() {
+                Ok(check) => resp
+                    .header("Content-Type", "application/json")
+                    .send_str(&serde_json::to_string(&check)?)?,
+                Err(e) => return ota_error(e, resp),
+            };
+            Ok(())
+        })?
+        .handle_post("/api/ota/update/install", |req, resp| {
+            if let Err(e) = auth::authorize(&req) {
+                return ota_error(e, resp);
+            }
+            let result = RebootWhen::from_query(&req.query_string())
+                .and_then(|when| Ok((updater::install()?, when)));
+            match result {
+                Ok(((manifest, time), when)) => {
+                    reboot::schedule(when);
+                    resp.send_str(&format!(
+                        "Installed {} in {:?} - {}",
+                        manifest.version,
+                        time.elapsed(),
+                        when
+                    ))?;
+                }
+                Err(e) => return ota_error(e, resp),
+            }
+            Ok(())
+        })?
+        // Body is the whole "update" settings object, missing fields take defaults
+        .handle_put(
+            "/api/ota/update/settings",
+            |mut req, resp| -> Result<(), embedded_svc::http::server::HandlerError> {
+                if let Err(e) = auth::authorize(&req) {
+                    return ota_error(e, resp);
+                }
+                let mut body = Vec::new();
+                ToStd::new(req.reader()).read_to_end(&mut body)?;
+                let result = serde_json::from_slice(&body)
+                    .map_err(|e| OtaError::BadRequest(e.to_string()))
+                    .and_then(updater::configure);
+                match result {
+                    Ok(settings) => resp
+                        .header("Content-Type", "application/json")
+                        .send_str(&serde_json::to_string(&settings)?)?,
+                    Err(e) => return ota_error(e, resp),
+                };
+                Ok(())
+            },
+        )?
+        // *********** Data partition images, see data_partition.rs
+        .handle_put("/api/data/*", |mut req, resp| {
+            if let Err(e) = auth::authorize(&req) {
+                return ota_error(e, resp);
+            }
+            let uri = req.uri().to_string();
+            let result = match data_partition::data_route(&uri) {
+                Some(label) => data_partition::data_upload(&mut req, label),
+                None => Err(OtaError::SlotNotFound(String::new())),
+            };
+            match result {
+                Ok(time) => {
+                    resp.send_str(&format!("Updated data partition in {:?}", time.elapsed()))?
+                }
+                Err(e) => return ota_error(e, resp),
+            };
+            Ok(())
+        })?
+        // *********** OTA POST handler, multipart form or raw image
+        .handle_post("/ota", ota_upload)?
+        .handle_put("/firmware", ota_upload)?
+        // *********** OTA pull handler, body {"url": "http://...", "sha256": "...", "save": true, "force": false, "reboot": "now"}
+        .handle_post(
+            "/ota/fetch",
+            |mut req, resp| -> Result<(), embedded_svc::http::server::HandlerError> {
+                if let Err(e) = auth::authorize(&req) {
+                    return ota_error(e, resp);
+                }
+                let mut body = Vec::new();
+                ToStd::new(req.reader()).read_to_end(&mut body)?;
+
+                let fetch: FetchRequest = if body.is_empty() {
+                    Default::default()
+                } else {
+                    match serde_json::from_slice(&body) {
+                        Ok(fetch) => fetch,
+                        Err(e) => {
+                            return ota_error(OtaError::BadRequest(e.to_string()), resp);
+                        }
+                    }
+                };
+                let when = match fetch.reboot.as_deref().map(RebootWhen::parse) {
+                    Some(Ok(when)) => when,
+                    Some(Err(e)) => return ota_error(e, resp),
+                    None => RebootWhen::Now,
+                };
+                let configured_url = APP_CONFIG.read().unwrap().ota.url.clone();
+                let (url, expected_sha256) = match fetch.resolve(configured_url.as_deref()) {
+                    Ok(v) => v,
+                    Err(e) => return ota_error(OtaError::BadRequest(e.to_string()), resp),
+                };
+                match ota::ota_fetch(&url, expected_sha256, None, fetch.force) {
+                    Ok(time) => {
+                        // Only a URL that delivered a verified image becomes the default
+                        if fetch.save {
+                            if let Ok(mut app_config) = APP_CONFIG.write() {
+                                app_config.ota.url = Some(url.clone());
+                                if let Err(e) = app_config.store_values_to_nvs() {
+                                    warn!("Saving the update url failed: {e}");
+                                }
+                            }
+                        }
+                        reboot::schedule(when);
+                        resp.send_str(&format!(
+                            "Flashed device from {} in {:?} - {}",
+                            url,
+                            time.elapsed(),
+                            when
+                        ))?;
+                    }
+                    Err(e) => return ota_error(e, resp),
+                }
+                Ok(())
+            },
+        )?
+        // *********** Resumable uploads, see ota_session.rs
+        .handle_post(
+            "/ota/session",
+            |mut req, resp| -> Result<(), embedded_svc::http::server::HandlerError> {
+                if let Err(e) = auth::authorize(&req) {
+                    return ota_error(e, resp);
+                }
+                let mut body = Vec::new();
+                ToStd::new(req.reader()).read_to_end(&mut body)?;
+                let request = if body.is_empty() {
+                    Ok(Default::default())
+                } else {
+                    serde_json::from_slice(&body)
+                };
+                let result = match request {
+                    Ok(request) => ota_session::create(request),
+                    Err(e) => Err(OtaError::BadRequest(e.to_string())),
+                };
+                session_response(result, resp)
+            },
+        )?
+        .handle_get("/ota/session/*", |req, resp| {
+            let result = match session::session_route(&req.uri()) {
+                Some((id, None)) => ota_session::info(id),
+                _ => Err(OtaError::NotFound),
+            };
+            session_response(result, resp)
+        })?
+        .handle_put("/ota/session/*", |mut req, resp| {
+            if let Err(e) = auth::authorize(&req) {
+                return ota_error(e, resp);
+            }
+            let uri = req.uri().to_string();
+            let result = match session::session_route(&uri) {
+                Some((id, None)) => ota_session::put(id, &mut req),
+                _ => Err(OtaError::NotFound),
+            };
+            session_response(result, resp)
+        })?
+        .handle_post("/ota/session/*", |req, resp| {
+            if let Err(e) = auth::authorize(&req) {
+                return ota_error(e, resp);
+            }
+            let result = RebootWhen::from_query(&req.query_string()).and_then(|when| {
+                match session::session_route(&req.uri()) {
+                    Some((id, Some("commit"))) => Ok((ota_session::commit(id)?, when)),
+                    _ => Err(OtaError::NotFound),
+                }
+            });
+            match result {
+                Ok((time, when)) => {
+                    reboot::schedule(when);
+                    resp.send_str(&format!(
+                        "Flashed device in {:?} - {}",
+                        time.elapsed(),
+                        when
+                    ))?;
+                }
+                Err(e) => return ota_error(e, resp),
+            }
+            Ok(())
+        })?;
+    Ok(server)
+}
+
+fn ota_upload(
+    req: EspHttpRequest,
+    resp: EspHttpResponse,
+) -> Result<(), embedded_svc::http::server::HandlerError> {
+    if let Err(e) = auth::authorize(&req) {
+        return ota_error(e, resp);
+    }
+    // Checked before flashing, a typo should not cost a whole upload
+    let when = match RebootWhen::from_query(&req.query_string()) {
+        Ok(when) => when,
+        Err(e) => return ota_error(e, resp),
+    };
+    match ota::ota_processing(req) {
+        Ok(time) => {
+            reboot::schedule(when);
+            resp.send_str(&format!(
+                "Flashed device in {:?} - {}",
+                time.elapsed(),
+                when
+            ))?;
+        }
+        Err(e) => return ota_error(e, resp),
+    }
+    Ok(())
+}
+
+fn ota_error(
+    e: OtaError,
+    resp: EspHttpResponse,
+) -> Result<(), embedded_svc::http::server::HandlerError> {
+    info!("OTA request failed: {e}");
+    let resp = resp
+        .status(e.status())
+        .header("Content-Type", "application/json");
+    // Lets browsers ask for the credential themselves
+    let resp = match e {
+        OtaError::Unauthorized => resp.header("WWW-Authenticate", credential::CHALLENGE),
+        _ => resp,
+    };
+    resp.send_str(&e.json(status::snapshot().bytes_written))?;
+    Ok(())
+}
+
+// Until the admin credential is set every page leads to /setup
+fn page(html: &str, resp: EspHttpResponse) -> Result<(), embedded_svc::http::server::HandlerError> {
+    if auth::configured() {
+        resp.send_str(html)?;
+    } else {
+        resp.status(302).header("Location", "/setup").send_str("")?;
+    }
+    Ok(())
+}
+
+fn boot_response(
+    result: Result<(slots::SlotInfo, RebootWhen), OtaError>,
+    resp: EspHttpResponse,
+) -> Result<(), embedded_svc::http::server::HandlerError> {
+    match result {
+        Ok((slot, when)) => {
+            reboot::schedule(when);
+            resp.header("Content-Type", "application/json")
+                .send_str(&serde_json::to_string(&slot)?)?;
+        }
+        Err(e) => return ota_error(e, resp),
+    }
+    Ok(())
+}
+
+fn session_response(
+    result: Result<session::SessionInfo, OtaError>,
+    resp: EspHttpResponse,
+) -> Result<(), embedded_svc::http::server::HandlerError> {
+    match result {
+        Ok(info) => resp.send_str(&serde_json::to_string(&info)?)?,
+        Err(e) => return ota_error(e, resp),
+    };
+    Ok(())
+}
@@ -0,0 +1,400 @@
+use anyhow::anyhow;
+use anyhow::Result;
+use embedded_svc::http::client::{Client, Request as _, Response as _};
+use embedded_svc::http::Headers;
+use embedded_svc::http::Status;
+use embedded_svc::ota::{Ota, OtaSlot};
+use esp_idf_svc::http::client::{EspHttpClient, EspHttpResponse};
+use esp_idf_svc::http::server::EspHttpRequest;
+use esp_idf_svc::ota::*;
+use esp_idf_sys::{
+    esp, esp_app_get_description, esp_ota_abort, esp_ota_begin, esp_ota_end,
+    esp_ota_get_next_update_partition, esp_ota_get_running_partition, esp_ota_get_state_partition,
+    esp_ota_handle_t, esp_ota_img_states_t, esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY,
+    esp_ota_set_boot_partition, esp_ota_write, esp_partition_read, esp_partition_t,
+    ESP_ERR_INVALID_STATE, ESP_ERR_NOT_FOUND, ESP_ERR_NOT_SUPPORTED, OTA_SIZE_UNKNOWN,
+};
+// use esp_ota::*;
+use lazy_static::lazy_static;
+use log::info;
+
+use embedded_svc::io::Read;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use embedded_svc::http::server::Request;
+
+use crate::delta::SlotReader;
+use crate::error::OtaError;
+use crate::fetch::receive_download;
+use crate::image::Chip;
+use crate::ota_lock::OtaLock;
+use crate::status;
+use crate::upload::{receive_upload, BodySource, FirmwareWriter, SlotWriter};
+use crate::verify::{parse_public_key_hex, Sha256Digest};
+use crate::version::RollbackPolicy;
+use crate::{APP_CONFIG, OTA_PUBLIC_KEY, VERSION};
+
+lazy_static! {
+    static ref STAGED: Mutex<Option<StagedSlot>> = Mutex::new(None);
+}
+
+// A verified image, only made the boot partition by the planned reboot
+struct StagedSlot(*const esp_partition_t);
+
+// Points at the static partition table
+unsafe impl Send for StagedSlot {}
+
+/// The slot a completed update waits in, `None` when nothing is staged.
+pub fn staged_partition() -> Option<*const esp_partition_t> {
+    STAGED.lock().unwrap().as_ref().map(|slot| slot.0)
+}
+
+/// Makes the staged image the boot partition, called by `main` right before
+/// it restarts. Any other reset leaves the running image as the next boot.
+pub fn activate_staged() -> Result<()> {
+    if let Some(slot) = STAGED.lock().unwrap().take() {
+        esp!(unsafe { esp_ota_set_boot_partition(slot.0) })?;
+        info!("Boot partition switched to the staged update");
+    }
+    Ok(())
+}
+
+/// Forgets the staged image, an explicitly selected boot slot wins.
+pub fn discard_staged() {
+    STAGED.lock().unwrap().take();
+}
+
+pub fn mark_app_valid(ok: bool) -> anyhow::Result<()> {
+    let mut ota = EspOta::new()?;
+    if ok {
+        if let Err(e) = ota.mark_running_slot_valid() {
+            return Err(anyhow!("{}", e));
+        } else {
+            let slot = ota.get_running_slot()?;
+            info!(
+                "Running slot: Firmware info = {:?}",
+                slot.get_firmware_info()
+            );
+            info!("Running slot: Label info = {:?}", slot.get_label());
+            info!("Running slot: State info = {:?}", slot.get_state());
+            let slot = ota.get_update_slot()?;
+            info!(
+                "Updating slot: Firmware info = {:?}",
+                slot.get_firmware_info()
+            );
+            info!("Updating slot: Label info = {:?}", slot.get_label());
+            info!("Updating slot: State info = {:?}", slot.get_state());
+            raise_secure_version()?;
+            return Ok(());
+        }
+    }
+    ota.mark_running_slot_invalid_and_reboot();
+    Ok(())
+}
+
+/// True on the first boot of a new image, until it is marked valid or rolled back.
+pub fn pending_verify() -> Result<bool> {
+    let partition = unsafe { esp_ota_get_running_partition() };
+    let mut state: esp_ota_img_states_t = 0;
+    match esp!(unsafe { esp_ota_get_state_partition(partition, &mut state) }) {
+        Ok(()) => Ok(state == esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY),
+        // Factory app, or a slot flashed over serial without otadata
+        Err(e)
+            if e.code() == ESP_ERR_NOT_SUPPORTED as i32 || e.code() == ESP_ERR_NOT_FOUND as i32 =>
+        {
+            Ok(false)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Once a build is known good, images older than its secure_version are refused.
+fn raise_secure_version() -> Result<()> {
+    let app_config = APP_CONFIG
+        .read()
+        .map_err(|_| anyhow!("Failed to get read lock"))?;
+    if app_config.ota.secure_version_check {
+        let running = unsafe { (*esp_app_get_description()).secure_version };
+        app_config.raise_secure_version(running)?;
+    }
+    Ok(())
+}
+
+/// Downgrade rules for an incoming image, checked before the update slot is erased.
+pub fn rollback_policy(force: bool) -> Result<RollbackPolicy> {
+    let running_version = EspOta::new()?
+        .get_running_slot()?
+        .get_firmware_info()?
+        .map(|info| info.version.to_string())
+        .unwrap_or_else(|| VERSION.to_string());
+    let app_config = APP_CONFIG
+        .read()
+        .map_err(|_| anyhow!("Failed to get read lock"))?;
+    let min_secure_version = if app_config.ota.secure_version_check {
+        Some(app_config.secure_version()?)
+    } else {
+        None
+    };
+    Ok(RollbackPolicy {
+        running_version,
+        min_secure_version,
+        force,
+    })
+}
+
+pub fn ota_processing(mut req: EspHttpRequest) -> Result<Instant, OtaError> {
+    let _lock = OtaLock::acquire("upload")?;
+    let start_time = Instant::now();
+    status::update(|s| s.begin(req.content_len(), start_time));
+    let result = (|| {
+        let public_key = parse_public_key_hex(OTA_PUBLIC_KEY)?;
+        receive_upload(
+            &mut RequestBody(&mut req),
+            next_slot()?,
+            running_chip(),
+            rollback_policy(false)?,
+            &public_key,
+        )
+    })();
+    status::finish(&result);
+    result.map(|_| start_time)
+}
+
+/// Streams into the next update slot, for callers that feed the image themselves.
+pub fn firmware_writer(
+    total: usize,
+    force: bool,
+) -> Result<FirmwareWriter<UpdateHandle>, OtaError> {
+    Ok(FirmwareWriter::new(
+        next_slot()?,
+        running_chip(),
+        total,
+        rollback_policy(force)?,
+    ))
+}
+
+fn next_slot() -> Result<UpdateHandle, OtaError> {
+    UpdateHandle::next().map_err(|e| OtaError::Flash(e.to_string()))
+}
+
+fn running_chip() -> Chip {
+    Chip::from(esp_idf_sys::CONFIG_IDF_FIRMWARE_CHIP_ID as u16)
+}
+
+/// Pull mode, the device downloads the image itself. Same checks as a POST to /ota.
+/// `expected_len` is the file size an update manifest announced.
+pub fn ota_fetch(
+    url: &str,
+    expected_sha256: Option<Sha256Digest>,
+    expected_len: Option<usize>,
+    force: bool,
+) -> Result<Instant, OtaError> {
+    let _lock = OtaLock::acquire("pull update")?;
+    let start_time = Instant::now();
+    status::update(|s| s.begin(None, start_time));
+    let result = fetch_update(url, expected_sha256, expected_len, force);
+    status::finish(&result);
+    result.map(|_| start_time)
+}
+
+fn fetch_update(
+    url: &str,
+    expected_sha256: Option<Sha256Digest>,
+    expected_len: Option<usize>,
+    force: bool,
+) -> Result<(), OtaError> {
+    let public_key = parse_public_key_hex(OTA_PUBLIC_KEY)?;
+    info!("Fetching firmware from {url}");
+
+    let mut client = EspHttpClient::new_default().map_err(|e| OtaError::Internal(e.to_string()))?;
+    let mut response = client
+        .get(url)
+        .and_then(|request| request.submit())
+        .map_err(|e| OtaError::Download(format!("{url}: {:?}", e)))?;
+    if response.status() != 200 {
+        return Err(OtaError::Download(format!(
+            "{url} answered HTTP {}",
+            response.status()
+        )));
+    }
+    receive_download(
+        &mut ResponseBody(&mut response, url),
+        next_slot()?,
+        running_chip(),
+        rollback_policy(force)?,
+        &public_key,
+        expected_sha256,
+        expected_len,
+    )
+}
+
+/// The next OTA partition through esp_ota_begin/write/end, without borrowing
+/// an `EspOta` so an update can outlive the request that started it. Dropping
+/// an unfinished update aborts it.
+pub struct UpdateHandle {
+    handle: Option<esp_ota_handle_t>,
+    partition: *const esp_partition_t,
+}
+
+// The handle and partition pointer are plain values owned by the OTA component
+unsafe impl Send for UpdateHandle {}
+
+impl UpdateHandle {
+    pub fn next() -> Result<Self> {
+        let partition = unsafe { esp_ota_get_next_update_partition(std::ptr::null()) };
+        if partition.is_null() {
+            return Err(anyhow!("No OTA update partition available"));
+        }
+        Ok(Self {
+            handle: None,
+            partition,
+        })
+    }
+}
+
+impl SlotWriter for UpdateHandle {
+    type Running = RunningSlot;
+
+    fn capacity(&self) -> usize {
+        unsafe { (*self.partition).size as usize }
+    }
+
+    fn begin(&mut self) -> Result<()> {
+        // The slot is about to be erased
+        discard_staged();
+        let mut handle: esp_ota_handle_t = 0;
+        esp!(unsafe { esp_ota_begin(self.partition, OTA_SIZE_UNKNOWN as _, &mut handle) })?;
+        self.handle = Some(handle);
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        match self.handle {
+            Some(handle) => {
+                esp!(unsafe { esp_ota_write(handle, data.as_ptr() as _, data.len() as _) })?
+            }
+            None => esp!(ESP_ERR_INVALID_STATE)?,
+        }
+        Ok(())
+    }
+
+    fn abort(&mut self) -> Result<()> {
+        if let Some(handle) = self.handle.take() {
+            esp!(unsafe { esp_ota_abort(handle) })?;
+        }
+        Ok(())
+    }
+
+    fn complete(&mut self) -> Result<()> {
+        match self.handle.take() {
+            Some(handle) => {
+                esp!(unsafe { esp_ota_end(handle) })?;
+                *STAGED.lock().unwrap() = Some(StagedSlot(self.partition));
+            }
+            None => esp!(ESP_ERR_INVALID_STATE)?,
+        }
+        Ok(())
+    }
+
+    fn running(&self) -> Result<RunningSlot> {
+        RunningSlot::open()
+    }
+}
+
+impl Drop for UpdateHandle {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            info!("Aborting unfinished OTA update");
+            unsafe { esp_ota_abort(handle) };
+        }
+    }
+}
+
+/// Read access to the running slot, the base image for delta updates.
+pub struct RunningSlot {
+    partition: *const esp_partition_t,
+}
+
+// Points at the static partition table
+unsafe impl Send for RunningSlot {}
+
+impl RunningSlot {
+    pub fn open() -> Result<Self> {
+        let partition = unsafe { esp_ota_get_running_partition() };
+        if partition.is_null() {
+            return Err(anyhow!("Running partition not found"));
+        }
+        Ok(Self { partition })
+    }
+}
+
+impl SlotReader for RunningSlot {
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        esp!(unsafe {
+            esp_partition_read(
+                self.partition,
+                offset as _,
+                buf.as_mut_ptr() as _,
+                buf.len() as _,
+            )
+        })?;
+        Ok(())
+    }
+}
+
+/// An upload request as `receive_upload` reads it
+pub struct RequestBody<'a, 'r>(pub &'a mut EspHttpRequest<'r>);
+
+impl BodySource for RequestBody<'_, '_> {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.0.header(name)
+    }
+
+    fn query_string(&self) -> String {
+        self.0.query_string().to_string()
+    }
+
+    fn content_len(&self) -> Option<usize> {
+        self.0.content_len()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, OtaError> {
+        self.0
+            .reader()
+            .read(buf)
+            .map_err(|e| OtaError::Transfer(format!("{:?}", e)))
+    }
+}
+
+/// A download as `receive_download` reads it
+struct ResponseBody<'a, 'r>(&'a mut EspHttpResponse<'r>, &'a str);
+
+impl BodySource for ResponseBody<'_, '_> {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.0.header(name)
+    }
+
+    fn query_string(&self) -> String {
+        String::new()
+    }
+
+    fn content_len(&self) -> Option<usize> {
+        self.0.content_len()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, OtaError> {
+        self.0
+            .reader()
+            .read(buf)
+            .map_err(|e| OtaError::Download(format!("{}: {:?}", self.1, e)))
+    }
+}
+
+// fn finalise_ota(
+//     ota: esp_ota::OtaUpdate,
+//     ota_bytes_counter: usize,
+//     // resp: &EspHttpResponse,
+//     start_time: Instant,
+// ) -> Result<Option<Instant>, HandlerError> {
+// }
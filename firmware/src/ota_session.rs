@@ -0,0 +1,218 @@
+// Resumable OTA uploads
+//
+//   POST /ota/session                 {"size": 1173192, "sha256": "..."} -> {"id", "offset", "size"}
+//   GET  /ota/session/{id}            current offset, ask this after a dropped connection
+//   PUT  /ota/session/{id}            Content-Range: bytes <start>-<end>/<size>
+//   POST /ota/session/{id}/commit     verify and switch boot slot
+//
+// The session and its open update handle live in RAM, so a dropped connection
+// can carry on from the last byte written to flash. A reboot, watchdog resets
+// included, ends the session: esp_ota_begin cannot continue a half written
+// slot and the hash and inflate state is not persisted. The unknown id then
+// gets session_not_found and the partial image is erased by the next update.
+// It holds the OTA lock throughout, a session that sees no data for
+// STALL_TIMEOUT is aborted so it cannot block updates forever. SESSION is only
+// locked around each write, never while waiting on the socket, so a client
+// that stops mid chunk cannot keep it from expiring. httpd's receive timeout
+// (5 s by default) ends such a read, the bytes before it count.
+
+use embedded_svc::http::server::Request;
+use embedded_svc::http::Headers;
+use embedded_svc::io::Read;
+use esp_idf_svc::http::server::EspHttpRequest;
+use lazy_static::lazy_static;
+use log::info;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::OtaError;
+use crate::ota::{firmware_writer, UpdateHandle};
+use crate::ota_lock::OtaLock;
+use crate::session::{ContentRange, SessionInfo, SessionRequest};
+use crate::status;
+use crate::upload::FirmwareWriter;
+use crate::verify::{parse_public_key_hex, parse_sha256_hex, Sha256Digest};
+use crate::OTA_PUBLIC_KEY;
+
+const STALL_TIMEOUT: Duration = Duration::from_secs(120);
+
+lazy_static! {
+    static ref SESSION: Mutex<Option<UploadSession>> = Mutex::new(None);
+}
+
+struct UploadSession {
+    id: String,
+    writer: FirmwareWriter<UpdateHandle>,
+    size: Option<usize>,
+    expected_sha256: Option<Sha256Digest>,
+    last_activity: Instant,
+    // Dropped with the session
+    _lock: OtaLock,
+}
+
+impl UploadSession {
+    fn info(&self) -> SessionInfo {
+        SessionInfo {
+            id: self.id.clone(),
+            offset: self.writer.received(),
+            size: self.size,
+        }
+    }
+}
+
+fn bad_range(e: String) -> OtaError {
+    OtaError::BadRequest(format!("Content-Range {e}"))
+}
+
+/// Starts a new session. While another one is open this is refused, resume that one instead.
+pub fn create(request: SessionRequest) -> Result<SessionInfo, OtaError> {
+    let lock = OtaLock::acquire("upload session")?;
+    let expected_sha256 = request
+        .sha256
+        .as_deref()
+        .map(parse_sha256_hex)
+        .transpose()
+        .map_err(|e| OtaError::BadRequest(e.to_string()))?;
+    let id = format!("{:08x}", unsafe { esp_idf_sys::esp_random() });
+    let session = UploadSession {
+        id,
+        writer: firmware_writer(request.size.unwrap_or(0), request.force)?,
+        size: request.size,
+        expected_sha256,
+        last_activity: Instant::now(),
+        _lock: lock,
+    };
+    let info = session.info();
+    status::update(|s| s.begin(request.size, Instant::now()));
+    *SESSION.lock().unwrap() = Some(session);
+    info!("Upload session {} started", info.id);
+    Ok(info)
+}
+
+/// Aborts the open session if it has stalled, called periodically from main.
+pub fn expire_stalled() {
+    let mut current = SESSION.lock().unwrap();
+    if !matches!(current.as_ref(), Some(session) if session.last_activity.elapsed() > STALL_TIMEOUT)
+    {
+        return;
+    }
+    let session = current.take().unwrap();
+    let error = OtaError::Transfer(format!(
+        "Upload session {} stalled at {}b, no chunk for {:?}",
+        session.id,
+        session.writer.received(),
+        STALL_TIMEOUT
+    ));
+    info!("{error}");
+    let result = session.writer.finalise(Err(error));
+    status::finish(&result);
+}
+
+pub fn info(id: &str) -> Result<SessionInfo, OtaError> {
+    open(&mut SESSION.lock().unwrap(), id).map(|session| session.info())
+}
+
+fn open<'a>(
+    current: &'a mut Option<UploadSession>,
+    id: &str,
+) -> Result<&'a mut UploadSession, OtaError> {
+    match current.as_mut() {
+        Some(session) if session.id == id => Ok(session),
+        _ => Err(OtaError::NotFound),
+    }
+}
+
+/// Writes one chunk. Bytes before the current offset are skipped so a client
+/// can simply resend the chunk that was interrupted.
+pub fn put(id: &str, req: &mut EspHttpRequest) -> Result<SessionInfo, OtaError> {
+    let range = match req.header("Content-Range") {
+        Some(header) => ContentRange::parse(header).map_err(bad_range)?,
+        None => return Err(bad_range("header missing".to_string())),
+    };
+    if req.content_len() != Some(range.byte_count()) {
+        return Err(bad_range(format!(
+            "range is {}b, body is {:?}b",
+            range.byte_count(),
+            req.content_len()
+        )));
+    }
+
+    let (mut offset, mut skip) = {
+        let mut current = SESSION.lock().unwrap();
+        let session = open(&mut current, id)?;
+        match (session.size, range.size) {
+            (Some(size), Some(total)) if size != total => {
+                return Err(bad_range(format!(
+                    "session size is {size}b, range says {total}b"
+                )))
+            }
+            (None, Some(total)) => session.size = Some(total),
+            _ => (),
+        }
+        let offset = session.writer.received();
+        if range.start > offset {
+            return Err(OtaError::Offset(offset));
+        }
+        session.last_activity = Instant::now();
+        (offset, offset - range.start)
+    };
+
+    let start_time = Instant::now();
+    let mut buf = Box::new([0u8; 1440 * 3]);
+    loop {
+        // Without the session, see the top of the file
+        let bytelen = match req.reader().read(&mut *buf) {
+            Ok(bytelen) => bytelen,
+            Err(e) => {
+                // Whatever made it to flash counts, the client resumes from there
+                info!("Upload session {id} chunk interrupted: {:?}", e);
+                break;
+            }
+        };
+        if bytelen == 0 {
+            break;
+        }
+        let duplicate = skip.min(bytelen);
+        skip -= duplicate;
+        let mut current = SESSION.lock().unwrap();
+        // Expired meanwhile, or another request wrote to it
+        let session = open(&mut current, id)?;
+        if session.writer.received() != offset {
+            return Err(OtaError::Offset(session.writer.received()));
+        }
+        if let Err(e) = session.writer.write(&buf[duplicate..bytelen]) {
+            // The image itself is bad, no point in resuming
+            let failed = current.take().unwrap();
+            let result = failed.writer.finalise(Err(e));
+            status::finish(&result);
+            return Err(result.unwrap_err());
+        }
+        offset = session.writer.received();
+        session.last_activity = Instant::now();
+        drop(current);
+        if start_time.elapsed() > Duration::from_millis(900) {
+            std::thread::sleep(Duration::from_millis(10)) //wdt
+        }
+    }
+    info(id)
+}
+
+pub fn commit(id: &str) -> Result<Instant, OtaError> {
+    let start_time = Instant::now();
+    let public_key = parse_public_key_hex(OTA_PUBLIC_KEY)?;
+    let mut current = SESSION.lock().unwrap();
+    let session = open(&mut current, id)?;
+    if let Some(size) = session.size {
+        if session.writer.received() != size {
+            return Err(OtaError::Offset(session.writer.received()));
+        }
+    }
+
+    let mut session = current.take().unwrap();
+    let result = session.writer.verify(session.expected_sha256, &public_key);
+    let result = session.writer.finalise(result);
+    status::finish(&result);
+    result?;
+    info!("Upload session {id} committed");
+    Ok(start_time)
+}
@@ -0,0 +1,245 @@
+// App partition inspection and boot slot selection
+//
+// GET /api/ota/slots lists the app partitions. State comes from otadata,
+// version and build info from the app description in each image, so slots
+// can be checked without a serial cable. POST /api/ota/rollback and
+// /api/ota/boot/{label} pick the partition to boot next.
+
+use anyhow::Result;
+use esp_idf_sys::{
+    esp, esp_app_desc_t, esp_ota_get_boot_partition, esp_ota_get_partition_description,
+    esp_ota_get_running_partition, esp_ota_get_state_partition, esp_ota_img_states_t,
+    esp_ota_img_states_t_ESP_OTA_IMG_ABORTED, esp_ota_img_states_t_ESP_OTA_IMG_INVALID,
+    esp_ota_img_states_t_ESP_OTA_IMG_NEW, esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY,
+    esp_ota_img_states_t_ESP_OTA_IMG_VALID, esp_ota_set_boot_partition, esp_partition_find,
+    esp_partition_get, esp_partition_next, esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY,
+    esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_APP_OTA_MAX,
+    esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_APP_OTA_MIN, esp_partition_t,
+    esp_partition_type_t_ESP_PARTITION_TYPE_APP, ESP_ERR_NOT_FOUND, ESP_ERR_NOT_SUPPORTED,
+    ESP_ERR_OTA_VALIDATE_FAILED,
+};
+use log::info;
+use serde::Serialize;
+use std::os::raw::c_char;
+
+use crate::error::OtaError;
+use crate::{ota, reboot};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlotState {
+    Valid,
+    Invalid,
+    PendingVerify,
+    /// Written but not booted yet
+    New,
+    /// Holds an image otadata knows nothing about, e.g. flashed over serial
+    Undefined,
+    Unused,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SlotInfo {
+    pub label: String,
+    pub offset: u32,
+    pub size: u32,
+    pub state: SlotState,
+    pub version: Option<String>,
+    pub project_name: Option<String>,
+    /// "Mmm dd yyyy hh:mm:ss" as the app description has it
+    pub build_date: Option<String>,
+    pub running: bool,
+    pub next_boot: bool,
+}
+
+/// Served on GET /api/ota/pending
+#[derive(Debug, Clone, Serialize)]
+pub struct PendingUpdate {
+    /// The next boot is into another slot than the running one
+    pub staged: bool,
+    pub running: Option<SlotInfo>,
+    pub next_boot: Option<SlotInfo>,
+    /// Until the scheduled reboot, `None` while waiting for POST /api/ota/apply
+    pub reboot_in_secs: Option<u64>,
+}
+
+pub fn pending() -> Result<PendingUpdate> {
+    let slots = app_slots()?;
+    let running = slots.iter().find(|slot| slot.running).cloned();
+    let next_boot = slots.iter().find(|slot| slot.next_boot).cloned();
+    Ok(PendingUpdate {
+        staged: matches!(&next_boot, Some(slot) if !slot.running),
+        running,
+        next_boot,
+        reboot_in_secs: reboot::reboot_in().map(|left| left.as_secs()),
+    })
+}
+
+/// Every app partition in partition table order.
+pub fn app_slots() -> Result<Vec<SlotInfo>> {
+    let running = unsafe { esp_ota_get_running_partition() };
+    let boot = next_boot_partition();
+    app_partitions()
+        .into_iter()
+        .map(|partition| slot_info(partition, running, boot))
+        .collect()
+}
+
+pub fn app_partitions() -> Vec<*const esp_partition_t> {
+    let mut partitions = Vec::new();
+    unsafe {
+        let mut iter = esp_partition_find(
+            esp_partition_type_t_ESP_PARTITION_TYPE_APP,
+            esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY,
+            std::ptr::null(),
+        );
+        // esp_partition_next releases the iterator once it runs out
+        while !iter.is_null() {
+            partitions.push(esp_partition_get(iter));
+            iter = esp_partition_next(iter);
+        }
+    }
+    partitions
+}
+
+/// Boots the other OTA slot next, the one not running.
+pub fn rollback() -> Result<SlotInfo, OtaError> {
+    let running = unsafe { esp_ota_get_running_partition() };
+    let other = app_partitions()
+        .into_iter()
+        .find(|partition| *partition != running && is_ota_slot(*partition))
+        .ok_or_else(|| OtaError::SlotNotBootable("There is no other OTA slot".to_string()))?;
+    set_boot_slot(&partition_label(other))
+}
+
+/// Makes the slot the boot partition, ESP-IDF verifies the image first.
+pub fn set_boot_slot(label: &str) -> Result<SlotInfo, OtaError> {
+    let running = unsafe { esp_ota_get_running_partition() };
+    let boot = next_boot_partition();
+    let partition = app_partitions()
+        .into_iter()
+        .find(|partition| partition_label(*partition) == label)
+        .ok_or_else(|| OtaError::SlotNotFound(label.to_string()))?;
+    let slot = slot_info(partition, running, boot)?;
+    if slot.version.is_none() {
+        return Err(OtaError::SlotNotBootable(format!(
+            "Slot {label} holds no valid image"
+        )));
+    }
+    if slot.state == SlotState::Invalid {
+        return Err(OtaError::SlotNotBootable(format!(
+            "Slot {label} holds an image marked invalid"
+        )));
+    }
+    match esp!(unsafe { esp_ota_set_boot_partition(partition) }) {
+        Ok(()) => (),
+        Err(e) if e.code() == ESP_ERR_OTA_VALIDATE_FAILED as i32 => {
+            return Err(OtaError::SlotNotBootable(format!(
+                "Slot {label} failed image verification"
+            )))
+        }
+        Err(e) => return Err(OtaError::Flash(e.to_string())),
+    }
+    ota::discard_staged();
+    info!("Next boot from {label} ({:?})", slot.version);
+    Ok(SlotInfo {
+        next_boot: true,
+        ..slot
+    })
+}
+
+// A staged update takes over the boot partition at the planned reboot
+fn next_boot_partition() -> *const esp_partition_t {
+    ota::staged_partition().unwrap_or_else(|| unsafe { esp_ota_get_boot_partition() })
+}
+
+/// Partition label from /api/ota/boot/{label}
+pub fn boot_route(uri: &str) -> Option<&str> {
+    let path = uri.split('?').next()?;
+    path.strip_prefix("/api/ota/boot/")
+        .filter(|label| !label.is_empty() && !label.contains('/'))
+}
+
+fn is_ota_slot(partition: *const esp_partition_t) -> bool {
+    let subtype = unsafe { (*partition).subtype };
+    (esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_APP_OTA_MIN
+        ..esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_APP_OTA_MAX)
+        .contains(&subtype)
+}
+
+pub fn partition_label(partition: *const esp_partition_t) -> String {
+    c_string(unsafe { &(*partition).label })
+}
+
+fn slot_info(
+    partition: *const esp_partition_t,
+    running: *const esp_partition_t,
+    boot: *const esp_partition_t,
+) -> Result<SlotInfo> {
+    let firmware = app_description(partition)?;
+    let state = match (ota_state(partition)?, &firmware) {
+        (Some(state), _) => state,
+        (None, Some(_)) => SlotState::Undefined,
+        (None, None) => SlotState::Unused,
+    };
+    let (offset, size) = unsafe { ((*partition).address, (*partition).size) };
+    Ok(SlotInfo {
+        label: partition_label(partition),
+        offset,
+        size,
+        state,
+        version: firmware.as_ref().map(|desc| c_string(&desc.version)),
+        project_name: firmware.as_ref().map(|desc| c_string(&desc.project_name)),
+        build_date: firmware
+            .as_ref()
+            .map(|desc| format!("{} {}", c_string(&desc.date), c_string(&desc.time))),
+        running: partition == running,
+        next_boot: partition == boot,
+    })
+}
+
+/// `None` when otadata has no entry for the partition, or for a factory app.
+fn ota_state(partition: *const esp_partition_t) -> Result<Option<SlotState>> {
+    let mut state: esp_ota_img_states_t = 0;
+    match esp!(unsafe { esp_ota_get_state_partition(partition, &mut state) }) {
+        Ok(()) => Ok(Some(if state == esp_ota_img_states_t_ESP_OTA_IMG_VALID {
+            SlotState::Valid
+        } else if state == esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY {
+            SlotState::PendingVerify
+        } else if state == esp_ota_img_states_t_ESP_OTA_IMG_NEW {
+            SlotState::New
+        } else if state == esp_ota_img_states_t_ESP_OTA_IMG_INVALID
+            || state == esp_ota_img_states_t_ESP_OTA_IMG_ABORTED
+        {
+            SlotState::Invalid
+        } else {
+            SlotState::Undefined
+        })),
+        Err(e)
+            if e.code() == ESP_ERR_NOT_SUPPORTED as i32 || e.code() == ESP_ERR_NOT_FOUND as i32 =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// `None` when the partition holds no valid image.
+fn app_description(partition: *const esp_partition_t) -> Result<Option<esp_app_desc_t>> {
+    let mut desc: esp_app_desc_t = unsafe { std::mem::zeroed() };
+    match esp!(unsafe { esp_ota_get_partition_description(partition, &mut desc) }) {
+        Ok(()) => Ok(Some(desc)),
+        Err(e) if e.code() == ESP_ERR_NOT_FOUND as i32 => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Fixed size fields, not terminated when full
+fn c_string(chars: &[c_char]) -> String {
+    let bytes: Vec<u8> = chars
+        .iter()
+        .take_while(|c| **c != 0)
+        .map(|c| *c as u8)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
@@ -0,0 +1,209 @@
+// Update server polling
+//
+// Every `poll_interval_secs` the manifest at `update.manifest_url` is fetched
+// and compared with the running VERSION, see manifest.rs. Under the `auto`
+// policy a newer build is installed and booted straight away, under `notify`
+// it is only reported on GET /api/ota/update. `manual` does not poll, but
+// POST /api/ota/update/check and /api/ota/update/install work under any policy.
+
+use embedded_svc::http::client::{Client, Request as _, Response as _};
+use embedded_svc::http::Status as _;
+use embedded_svc::io::Read;
+use esp_idf_svc::http::client::EspHttpClient;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use serde::Serialize;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::configuration::{UpdateSettings, MIN_POLL_INTERVAL_SECS};
+use crate::error::OtaError;
+use crate::image::Chip;
+use crate::manifest::{Check, Manifest, MAX_MANIFEST_LEN};
+use crate::reboot::{self, RebootWhen};
+use crate::verify::parse_sha256_hex;
+use crate::{ota, APP_CONFIG, VERSION};
+
+const MIN_POLL_INTERVAL: Duration = Duration::from_secs(MIN_POLL_INTERVAL_SECS);
+
+lazy_static! {
+    static ref STATE: Mutex<State> = Mutex::new(State::default());
+}
+
+#[derive(Default)]
+struct State {
+    checked_at: Option<Instant>,
+    check: Option<Check>,
+    error: Option<String>,
+}
+
+/// Served on GET /api/ota/update
+#[derive(Debug, Serialize)]
+pub struct UpdateStatus {
+    pub running_version: &'static str,
+    pub hardware_id: String,
+    pub settings: UpdateSettings,
+    pub checked_secs_ago: Option<u64>,
+    /// Outcome of the last check
+    pub check: Option<Check>,
+    pub error: Option<String>,
+}
+
+pub fn status() -> UpdateStatus {
+    let settings = settings();
+    let state = STATE.lock().unwrap();
+    UpdateStatus {
+        running_version: VERSION,
+        hardware_id: hardware_id(&settings),
+        settings,
+        checked_secs_ago: state.checked_at.map(|at| at.elapsed().as_secs()),
+        check: state.check.clone(),
+        error: state.error.clone(),
+    }
+}
+
+/// Fetches the manifest and records what it offers.
+pub fn check() -> Result<Check, OtaError> {
+    let settings = settings();
+    let result = settings
+        .manifest_url
+        .as_deref()
+        .ok_or_else(|| OtaError::BadRequest("No update manifest_url configured".to_string()))
+        .and_then(fetch_manifest)
+        .map(|manifest| manifest.check(VERSION, &hardware_id(&settings), &settings.channel));
+    let mut state = STATE.lock().unwrap();
+    state.checked_at = Some(Instant::now());
+    match &result {
+        Ok(check) => {
+            info!("Update check: {:?}", check);
+            state.check = Some(check.clone());
+            state.error = None;
+        }
+        Err(e) => state.error = Some(e.to_string()),
+    }
+    result
+}
+
+/// Checks again and installs what the manifest offers, for POST /api/ota/update/install.
+/// The reboot is left to the caller.
+pub fn install() -> Result<(Manifest, Instant), OtaError> {
+    available(check()?).and_then(install_manifest)
+}
+
+/// Installs a manifest that has already been checked, without fetching it again.
+fn install_manifest(manifest: Manifest) -> Result<(Manifest, Instant), OtaError> {
+    let expected_sha256 = manifest
+        .sha256
+        .as_deref()
+        .map(parse_sha256_hex)
+        .transpose()?;
+    info!("Installing {} from {}", manifest.version, manifest.url);
+    let result = ota::ota_fetch(&manifest.url, expected_sha256, manifest.size, false);
+    if let Err(e) = &result {
+        STATE.lock().unwrap().error = Some(e.to_string());
+    }
+    result.map(|time| (manifest, time))
+}
+
+/// Validates and stores new settings, keeping the NVS key.
+pub fn configure(mut settings: UpdateSettings) -> Result<UpdateSettings, OtaError> {
+    settings.validate()?;
+    let mut app_config = APP_CONFIG
+        .write()
+        .map_err(|_| OtaError::Internal("Failed to get write lock".to_string()))?;
+    settings.nvs = app_config.update.nvs.clone();
+    app_config.update = settings.clone();
+    app_config.store_values_to_nvs()?;
+    Ok(settings)
+}
+
+/// Runs the poll loop on its own thread, call once the running build is confirmed.
+pub fn spawn() -> anyhow::Result<()> {
+    thread::Builder::new()
+        .name("updater".into())
+        // TLS handshakes for https manifests
+        .stack_size(16 * 1024)
+        .spawn(poll)?;
+    Ok(())
+}
+
+fn poll() {
+    loop {
+        thread::sleep(MIN_POLL_INTERVAL);
+        let settings = settings();
+        let checked_at = STATE.lock().unwrap().checked_at;
+        if !settings.poll_due(checked_at.map(|at| at.elapsed())) {
+            continue;
+        }
+        let result = match check() {
+            // Installs the manifest the policy was asked about, not a fresh fetch
+            Ok(check) if settings.installs(&check) => available(check)
+                .and_then(install_manifest)
+                .map(|(manifest, time)| {
+                    info!(
+                        "Installed {} in {:?}, rebooting",
+                        manifest.version,
+                        time.elapsed()
+                    );
+                    reboot::schedule(RebootWhen::Now);
+                }),
+            other => other.map(|_| ()),
+        };
+        if let Err(e) = result {
+            warn!("Update poll failed: {e}");
+        }
+    }
+}
+
+fn available(check: Check) -> Result<Manifest, OtaError> {
+    match check {
+        Check::Available { manifest } => Ok(manifest),
+        Check::UpToDate => Err(OtaError::NoUpdate(format!(
+            "{VERSION} is already the newest build"
+        ))),
+        Check::Skipped { reason } => Err(OtaError::NoUpdate(reason)),
+    }
+}
+
+fn settings() -> UpdateSettings {
+    APP_CONFIG.read().unwrap().update.clone()
+}
+
+fn hardware_id(settings: &UpdateSettings) -> String {
+    settings
+        .hardware_id
+        .clone()
+        .unwrap_or_else(|| Chip::from(esp_idf_sys::CONFIG_IDF_FIRMWARE_CHIP_ID as u16).to_string())
+}
+
+fn fetch_manifest(url: &str) -> Result<Manifest, OtaError> {
+    info!("Fetching update manifest from {url}");
+    let mut client = EspHttpClient::new_default().map_err(|e| OtaError::Internal(e.to_string()))?;
+    let mut response = client
+        .get(url)
+        .and_then(|request| request.submit())
+        .map_err(|e| OtaError::Download(format!("{url}: {:?}", e)))?;
+    if response.status() != 200 {
+        return Err(OtaError::Download(format!(
+            "{url} answered HTTP {}",
+            response.status()
+        )));
+    }
+    let mut reader = response.reader();
+    let mut body = Vec::new();
+    let mut buf = [0u8; 512];
+    loop {
+        let bytelen = reader
+            .read(&mut buf)
+            .map_err(|e| OtaError::Download(format!("{url}: {:?}", e)))?;
+        if bytelen == 0 {
+            break;
+        }
+        body.extend_from_slice(&buf[..bytelen]);
+        if body.len() > MAX_MANIFEST_LEN {
+            break;
+        }
+    }
+    Manifest::parse(&body, url).map_err(|e| OtaError::Download(format!("manifest {url}: {e}")))
+}
@@ -134,16 +134,15 @@ fn wifiap(mut wifi: Box<EspWifi>, ap: configuration::Wifi) -> Result<Box<EspWifi
     Ok(wifi)
 }
 
-fn ping_init(ip_settings: &ipv4::ClientSettings) -> Result<()> {
+pub fn ping_init(ip_settings: &ipv4::ClientSettings) -> Result<()> {
     info!("About to do some sta pings for {:?}", ip_settings);
+    ping_gateway(ip_settings.subnet.gateway)
+}
 
-    let ping_summary =
-        ping::EspPing::default().ping(ip_settings.subnet.gateway, &Default::default())?;
+pub fn ping_gateway(gateway: ipv4::Ipv4Addr) -> Result<()> {
+    let ping_summary = ping::EspPing::default().ping(gateway, &Default::default())?;
     if ping_summary.transmitted != ping_summary.received {
-        bail!(
-            "Pinging sta gateway {} resulted in timeouts",
-            ip_settings.subnet.gateway
-        );
+        bail!("Pinging sta gateway {} resulted in timeouts", gateway);
     }
 
     info!("Pinging done");
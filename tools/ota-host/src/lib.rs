@@ -0,0 +1,183 @@
+// Host backends for the core crate, files standing in for flash and NVS
+
+pub use ota_core::{
+    configuration, delta, error, fetch, image, inflate, multipart, status, upload, verify, version,
+};
+
+pub mod storage;
+
+use anyhow::{anyhow, Result};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use delta::SlotReader;
+use error::OtaError;
+use upload::{BodySource, SlotWriter};
+
+/// An update slot backed by a file. Written to `<path>.partial` and renamed
+/// to `path` on completion, so `path` only ever holds a completed image.
+pub struct FileSlot {
+    path: PathBuf,
+    capacity: usize,
+    running: Option<PathBuf>,
+    fail_after: Option<usize>,
+    file: Option<File>,
+    written: usize,
+}
+
+impl FileSlot {
+    pub fn new(path: impl Into<PathBuf>, capacity: usize) -> Self {
+        Self {
+            path: path.into(),
+            capacity,
+            running: None,
+            fail_after: None,
+            file: None,
+            written: 0,
+        }
+    }
+
+    /// The running slot's image, the base for delta updates
+    pub fn with_running(mut self, path: impl Into<PathBuf>) -> Self {
+        self.running = Some(path.into());
+        self
+    }
+
+    /// Fails the write that would take the slot past `bytes`
+    pub fn fail_after(mut self, bytes: usize) -> Self {
+        self.fail_after = Some(bytes);
+        self
+    }
+
+    pub fn partial_path(&self) -> PathBuf {
+        partial_path(&self.path)
+    }
+}
+
+pub fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    partial.into()
+}
+
+impl SlotWriter for FileSlot {
+    type Running = FileReader;
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn begin(&mut self) -> Result<()> {
+        self.file = Some(File::create(self.partial_path())?);
+        self.written = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        if matches!(self.fail_after, Some(limit) if self.written + data.len() > limit) {
+            return Err(anyhow!("simulated flash failure at {} bytes", self.written));
+        }
+        let file = self
+            .file
+            .as_mut()
+            .ok_or_else(|| anyhow!("slot not begun"))?;
+        file.write_all(data)?;
+        self.written += data.len();
+        Ok(())
+    }
+
+    fn abort(&mut self) -> Result<()> {
+        if self.file.take().is_some() {
+            fs::remove_file(self.partial_path())?;
+        }
+        Ok(())
+    }
+
+    fn complete(&mut self) -> Result<()> {
+        let file = self.file.take().ok_or_else(|| anyhow!("slot not begun"))?;
+        file.sync_all()?;
+        fs::rename(self.partial_path(), &self.path)?;
+        Ok(())
+    }
+
+    fn running(&self) -> Result<FileReader> {
+        match &self.running {
+            Some(path) => Ok(FileReader(File::open(path)?)),
+            None => Err(anyhow!("No running slot image")),
+        }
+    }
+}
+
+pub struct FileReader(File);
+
+impl SlotReader for FileReader {
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        self.0.seek(SeekFrom::Start(offset as u64))?;
+        self.0.read_exact(buf)?;
+        Ok(())
+    }
+}
+
+/// A request body held in memory, handed out `chunk` bytes per read like a socket would.
+pub struct MemoryBody {
+    headers: Vec<(String, String)>,
+    query: String,
+    data: Vec<u8>,
+    position: usize,
+    chunk: usize,
+}
+
+impl MemoryBody {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            headers: Vec::new(),
+            query: String::new(),
+            data,
+            position: 0,
+            chunk: 1440,
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn query(mut self, query: &str) -> Self {
+        self.query = query.to_string();
+        self
+    }
+
+    pub fn chunk(mut self, chunk: usize) -> Self {
+        self.chunk = chunk.max(1);
+        self
+    }
+}
+
+impl BodySource for MemoryBody {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn query_string(&self) -> String {
+        self.query.clone()
+    }
+
+    fn content_len(&self) -> Option<usize> {
+        Some(self.data.len())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, OtaError> {
+        let len = buf
+            .len()
+            .min(self.chunk)
+            .min(self.data.len() - self.position);
+        buf[..len].copy_from_slice(&self.data[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
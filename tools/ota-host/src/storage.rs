@@ -0,0 +1,149 @@
+// NvsStorage backends for running off the device
+//
+// MemoryStorage forgets everything when dropped. JsonFileStorage keeps the
+// keys in one JSON file and rewrites it on every change, as NVS commits each
+// write. Both hold values to the same MAX_VALUE_LEN as NVS reads.
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::configuration::{NvsStorage, MAX_VALUE_LEN};
+
+fn check_write(key: &str, val: &[u8]) -> Result<()> {
+    if key.is_empty() {
+        return Err(anyhow!("set_val attempted to write with zero length key"));
+    }
+    if val.len() > MAX_VALUE_LEN {
+        return Err(anyhow!(
+            "NVS Key:{key} value is {}b, limit {MAX_VALUE_LEN}b",
+            val.len()
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStorage {
+    values: BTreeMap<String, Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl NvsStorage for MemoryStorage {
+    fn contains(&self, key: &str) -> Result<bool> {
+        Ok(self.values.contains_key(key))
+    }
+
+    fn get_val(&self, key: &str) -> Result<Vec<u8>> {
+        self.values
+            .get(key)
+            .cloned()
+            .ok_or_else(|| anyhow!("NVS Key:{key} Not found"))
+    }
+
+    fn set_val(&mut self, key: &str, val: &[u8]) -> Result<bool> {
+        check_write(key, val)?;
+        self.values.insert(key.to_string(), val.to_vec());
+        Ok(true)
+    }
+
+    fn remove(&mut self, key: &str) -> Result<bool> {
+        Ok(self.values.remove(key).is_some())
+    }
+}
+
+// Settings are JSON text and stay readable in the file, anything else is a byte array
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Stored {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl From<&[u8]> for Stored {
+    fn from(val: &[u8]) -> Self {
+        match std::str::from_utf8(val) {
+            Ok(text) => Stored::Text(text.to_string()),
+            Err(_) => Stored::Bytes(val.to_vec()),
+        }
+    }
+}
+
+impl Stored {
+    fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Stored::Text(text) => text.into_bytes(),
+            Stored::Bytes(bytes) => bytes,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct JsonFileStorage {
+    path: PathBuf,
+    values: BTreeMap<String, Stored>,
+}
+
+impl JsonFileStorage {
+    /// Starts empty when the file does not exist yet.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let values = match fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .with_context(|| format!("{} is not a settings file", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e).with_context(|| format!("Reading {}", path.display())),
+        };
+        Ok(Self { path, values })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Written aside and renamed, so a crash leaves the old file rather than half a new one
+    fn save(&self) -> Result<()> {
+        let mut partial = self.path.as_os_str().to_owned();
+        partial.push(".tmp");
+        fs::write(&partial, serde_json::to_vec_pretty(&self.values)?)?;
+        fs::rename(&partial, &self.path)
+            .with_context(|| format!("Writing {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+impl NvsStorage for JsonFileStorage {
+    fn contains(&self, key: &str) -> Result<bool> {
+        Ok(self.values.contains_key(key))
+    }
+
+    fn get_val(&self, key: &str) -> Result<Vec<u8>> {
+        self.values
+            .get(key)
+            .cloned()
+            .map(Stored::into_bytes)
+            .ok_or_else(|| anyhow!("NVS Key:{key} Not found"))
+    }
+
+    fn set_val(&mut self, key: &str, val: &[u8]) -> Result<bool> {
+        check_write(key, val)?;
+        self.values.insert(key.to_string(), Stored::from(val));
+        self.save()?;
+        Ok(true)
+    }
+
+    fn remove(&mut self, key: &str) -> Result<bool> {
+        let removed = self.values.remove(key).is_some();
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+}
@@ -0,0 +1,262 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use ota_host::configuration::{
+    AppConfiguration, NvsStorage, NvsStruct, UpdatePolicy, Wifi, MAX_VALUE_LEN,
+};
+use ota_host::storage::{JsonFileStorage, MemoryStorage};
+
+const KEYS: [&str; 8] = [
+    "ap", "sta", "bms", "mqtt", "ota", "health", "update", "auth",
+];
+
+fn wifi(ssid: &str, pass: &str) -> Wifi {
+    Wifi {
+        ssid: Some(ssid.to_string()),
+        pass: Some(pass.to_string()),
+        ..Default::default()
+    }
+}
+
+fn init<S: NvsStorage>(store: &Arc<RwLock<S>>) -> AppConfiguration<S> {
+    let mut config = AppConfiguration::default();
+    config
+        .init(
+            store.clone(),
+            wifi("home", "home-pass"),
+            wifi("ota-test", "ap-pass"),
+        )
+        .unwrap();
+    config
+}
+
+fn settings_file(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ota-host-{}-{test}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir.join("nvs.json")
+}
+
+#[test]
+fn empty_storage_gets_defaults() {
+    let store = Arc::new(RwLock::new(MemoryStorage::new()));
+    let config = init(&store);
+    assert_eq!(config.sta.ssid.as_deref(), Some("home"));
+    assert_eq!(config.ap.ssid.as_deref(), Some("ota-test"));
+    assert_eq!(config.ap.channel, Some(1));
+    assert_eq!(config.update.policy, UpdatePolicy::Manual);
+    assert_eq!(config.health.probation_secs, 30);
+    for key in KEYS {
+        assert!(store.read().unwrap().contains(key).unwrap(), "{key}");
+    }
+}
+
+#[test]
+fn saved_settings_survive_init() {
+    let store = Arc::new(RwLock::new(MemoryStorage::new()));
+    let mut config = init(&store);
+    config.sta.ssid = Some("office".to_string());
+    config.ap.channel = Some(6);
+    config.mqtt.address = "mqtt://broker".to_string();
+    config.ota.url = Some("http://updates/ota.bin".to_string());
+    config.store_values_to_nvs().unwrap();
+
+    let config = init(&store);
+    assert_eq!(config.sta.ssid.as_deref(), Some("office"));
+    assert_eq!(config.sta.nvs, "sta");
+    assert_eq!(config.ap.channel, Some(6));
+    assert_eq!(config.mqtt.address, "mqtt://broker");
+    assert_eq!(config.ota.url.as_deref(), Some("http://updates/ota.bin"));
+}
+
+#[test]
+fn missing_later_key_keeps_the_rest() {
+    let store = Arc::new(RwLock::new(MemoryStorage::new()));
+    let mut config = init(&store);
+    config.sta.ssid = Some("office".to_string());
+    config.health.probation_secs = 5;
+    config.store_values_to_nvs().unwrap();
+    store.write().unwrap().remove("health").unwrap();
+
+    let config = init(&store);
+    assert_eq!(config.sta.ssid.as_deref(), Some("office"));
+    assert_eq!(config.health.probation_secs, 30);
+    assert!(store.read().unwrap().contains("health").unwrap());
+}
+
+#[test]
+fn missing_original_key_resets_everything() {
+    let store = Arc::new(RwLock::new(MemoryStorage::new()));
+    let mut config = init(&store);
+    config.sta.ssid = Some("office".to_string());
+    config.store_values_to_nvs().unwrap();
+    store.write().unwrap().remove("mqtt").unwrap();
+
+    let config = init(&store);
+    assert_eq!(config.sta.ssid.as_deref(), Some("home"));
+}
+
+// Found by fuzzing, init used to fail on these and the firmware panics on that
+#[test]
+fn corrupt_value_falls_back_to_its_default() {
+    let store = Arc::new(RwLock::new(MemoryStorage::new()));
+    let mut config = init(&store);
+    config.sta.ssid = Some("office".to_string());
+    config.mqtt.address = "mqtt://broker".to_string();
+    config.health.probation_secs = 5;
+    config.store_values_to_nvs().unwrap();
+    for (key, value) in [("sta", &b"\xff{"[..]), ("health", b"{\"nvs\":\"health\"}")] {
+        store.write().unwrap().set_val(key, value).unwrap();
+    }
+
+    let config = init(&store);
+    assert_eq!(config.sta.ssid.as_deref(), Some("home"));
+    assert_eq!(config.health.probation_secs, 30);
+    assert_eq!(config.mqtt.address, "mqtt://broker");
+    // The defaults replace the corrupt values
+    let sta = store.read().unwrap().get_val("sta").unwrap();
+    let sta: Wifi = serde_json::from_slice(&sta).unwrap();
+    assert_eq!(sta.ssid.as_deref(), Some("home"));
+}
+
+#[test]
+fn erase_removes_every_setting_but_the_credential() {
+    let store = Arc::new(RwLock::new(MemoryStorage::new()));
+    let mut config = init(&store);
+    config.erase_values_in_nvs().unwrap();
+    for key in KEYS {
+        let kept = store.read().unwrap().contains(key).unwrap();
+        assert_eq!(kept, key == "auth", "{key}");
+    }
+}
+
+#[test]
+fn credential_survives_a_settings_reset() {
+    let store = Arc::new(RwLock::new(MemoryStorage::new()));
+    let mut config = init(&store);
+    config.auth.password_hash = Some("ab".repeat(32));
+    config.store_values_to_nvs().unwrap();
+    store.write().unwrap().remove("mqtt").unwrap();
+
+    let config = init(&store);
+    assert_eq!(config.auth.password_hash, Some("ab".repeat(32)));
+}
+
+#[test]
+fn missing_credential_opens_setup() {
+    let store = Arc::new(RwLock::new(MemoryStorage::new()));
+    init(&store);
+    store.write().unwrap().remove("auth").unwrap();
+
+    let config = init(&store);
+    assert!(!config.auth.locked);
+    assert!(config.auth.password_hash.is_none());
+    assert!(store.read().unwrap().contains("auth").unwrap());
+}
+
+#[test]
+fn corrupt_credential_locks_instead_of_resetting() {
+    let store = Arc::new(RwLock::new(MemoryStorage::new()));
+    let mut config = init(&store);
+    config.auth.password_hash = Some("ab".repeat(32));
+    config.store_values_to_nvs().unwrap();
+    store.write().unwrap().set_val("auth", b"\xff{").unwrap();
+
+    let mut config = init(&store);
+    assert!(config.auth.locked);
+    // Saving the other settings leaves it alone, and it stays locked
+    config.store_values_to_nvs().unwrap();
+    config.erase_values_in_nvs().unwrap();
+    let config = init(&store);
+    assert!(config.auth.locked);
+    assert_eq!(store.read().unwrap().get_val("auth").unwrap(), b"\xff{");
+}
+
+#[test]
+fn secure_version_only_rises() {
+    let store = Arc::new(RwLock::new(MemoryStorage::new()));
+    let config = init(&store);
+    assert_eq!(config.secure_version().unwrap(), 0);
+    config.raise_secure_version(3).unwrap();
+    config.raise_secure_version(2).unwrap();
+    assert_eq!(config.secure_version().unwrap(), 3);
+}
+
+// Holds the counter but fails to read it back
+struct UnreadableCounter(MemoryStorage);
+
+impl NvsStorage for UnreadableCounter {
+    fn contains(&self, key: &str) -> anyhow::Result<bool> {
+        self.0.contains(key)
+    }
+
+    fn get_val(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        match key {
+            "secure_ver" => anyhow::bail!("read failed"),
+            _ => self.0.get_val(key),
+        }
+    }
+
+    fn set_val(&mut self, key: &str, val: &[u8]) -> anyhow::Result<bool> {
+        self.0.set_val(key, val)
+    }
+
+    fn remove(&mut self, key: &str) -> anyhow::Result<bool> {
+        self.0.remove(key)
+    }
+}
+
+#[test]
+fn unreadable_secure_version_is_an_error() {
+    let store = Arc::new(RwLock::new(UnreadableCounter(MemoryStorage::new())));
+    let config = init(&store);
+    assert_eq!(config.secure_version().unwrap(), 0);
+    config.raise_secure_version(3).unwrap();
+    assert!(config.secure_version().is_err());
+    assert!(config.raise_secure_version(4).is_err());
+}
+
+#[test]
+fn oversized_value_is_refused() {
+    let store = RwLock::new(MemoryStorage::new());
+    let mut wifi = wifi(&"x".repeat(MAX_VALUE_LEN), "pass");
+    wifi.set_nvs_key("sta".into());
+    assert!(wifi.write_to_nvs(&store).is_err());
+    assert!(!store.read().unwrap().contains("sta").unwrap());
+}
+
+#[test]
+fn json_file_keeps_settings_across_restarts() {
+    let path = settings_file("json-storage");
+    let store = Arc::new(RwLock::new(JsonFileStorage::open(&path).unwrap()));
+    let mut config = init(&store);
+    config.sta.ssid = Some("office".to_string());
+    config.store_values_to_nvs().unwrap();
+    config.raise_secure_version(7).unwrap();
+    drop(config);
+    drop(store);
+
+    let store = Arc::new(RwLock::new(JsonFileStorage::open(&path).unwrap()));
+    let config = init(&store);
+    assert_eq!(config.sta.ssid.as_deref(), Some("office"));
+    assert_eq!(config.secure_version().unwrap(), 7);
+
+    // Settings stay readable in the file
+    let file: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+    let sta: serde_json::Value = serde_json::from_str(file["sta"].as_str().unwrap()).unwrap();
+    assert_eq!(sta["ssid"], "office");
+}
+
+#[test]
+fn json_file_erase_is_persisted() {
+    let path = settings_file("json-erase");
+    let store = Arc::new(RwLock::new(JsonFileStorage::open(&path).unwrap()));
+    let mut config = init(&store);
+    config.erase_values_in_nvs().unwrap();
+
+    let reopened = JsonFileStorage::open(&path).unwrap();
+    for key in KEYS {
+        assert_eq!(reopened.contains(key).unwrap(), key == "auth", "{key}");
+    }
+}
@@ -0,0 +1,308 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::thread;
+
+use flate2::write::GzEncoder;
+use flate2::Compression as Level;
+use ota_host::error::OtaError;
+use ota_host::fetch::{receive_download, FetchRequest};
+use ota_host::image::Chip;
+use ota_host::upload::BodySource;
+use ota_host::verify::{to_hex, Sha256Digest};
+use ota_host::version::RollbackPolicy;
+use ota_host::FileSlot;
+use ota_sign::{public_key, sign_image};
+use sha2::{Digest, Sha256};
+
+const SEED: [u8; 32] = [7; 32];
+const SLOT_SIZE: usize = 0x1f0000;
+
+fn firmware() -> Vec<u8> {
+    fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/../../ota.bin")).unwrap()
+}
+
+fn signed() -> Vec<u8> {
+    sign_image(&SEED, &firmware()).unwrap()
+}
+
+fn slot_path(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ota-fetch-{}-{test}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir.join("ota_1.bin")
+}
+
+/// Serves `file` once on a free local port, returns its URL
+fn serve(file: Vec<u8>) -> String {
+    serve_with(format!("Content-Length: {}\r\n", file.len()), file)
+}
+
+/// Serves `file` as it is after the given header lines
+fn serve_with(headers: String, file: Vec<u8>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/fw/ota.bin", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = BufReader::new(stream.try_clone().unwrap());
+        let mut line = String::new();
+        while request.read_line(&mut line).unwrap() > 2 {
+            line.clear();
+        }
+        let head = format!("HTTP/1.1 200 OK\r\n{headers}Connection: close\r\n\r\n");
+        stream.write_all(head.as_bytes()).unwrap();
+        let _ = stream.write_all(&file);
+    });
+    url
+}
+
+fn chunked(data: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for chunk in data.chunks(5000) {
+        write!(body, "{:x}\r\n", chunk.len()).unwrap();
+        body.extend_from_slice(chunk);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(b"0\r\n\r\n");
+    body
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Level::best());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// A plain HTTP/1.1 GET, standing in for EspHttpClient
+struct Download {
+    headers: Vec<(String, String)>,
+    body: BufReader<TcpStream>,
+    // Bytes left of the current chunk of a chunked response
+    chunk: Option<usize>,
+}
+
+impl Download {
+    fn get(url: &str) -> Self {
+        let rest = url.strip_prefix("http://").unwrap();
+        let (host, path) = rest.split_once('/').unwrap();
+        let mut stream = TcpStream::connect(host).unwrap();
+        write!(stream, "GET /{path} HTTP/1.1\r\nHost: {host}\r\n\r\n").unwrap();
+        let mut body = BufReader::new(stream);
+        let mut headers = Vec::new();
+        let mut line = String::new();
+        body.read_line(&mut line).unwrap();
+        assert!(line.starts_with("HTTP/1.1 200"), "{line}");
+        loop {
+            line.clear();
+            body.read_line(&mut line).unwrap();
+            match line.trim_end().split_once(':') {
+                Some((name, value)) => headers.push((name.to_string(), value.trim().to_string())),
+                None => break,
+            }
+        }
+        let mut download = Self {
+            headers,
+            body,
+            chunk: None,
+        };
+        if download.header("Transfer-Encoding") == Some("chunked") {
+            download.chunk = Some(0);
+        }
+        download
+    }
+}
+
+impl BodySource for Download {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn query_string(&self) -> String {
+        String::new()
+    }
+
+    fn content_len(&self) -> Option<usize> {
+        self.header("Content-Length")?.parse().ok()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, OtaError> {
+        let download_error = |e: std::io::Error| OtaError::Download(e.to_string());
+        let mut remaining = match self.chunk {
+            Some(remaining) => remaining,
+            None => return self.body.read(buf).map_err(download_error),
+        };
+        if remaining == 0 {
+            // The size line, after the blank line ending the previous chunk
+            let mut line = String::new();
+            while line.trim().is_empty() {
+                line.clear();
+                if self.body.read_line(&mut line).map_err(download_error)? == 0 {
+                    return Ok(0);
+                }
+            }
+            remaining = usize::from_str_radix(line.trim(), 16).unwrap();
+            if remaining == 0 {
+                return Ok(0);
+            }
+        }
+        let len = remaining.min(buf.len());
+        let read = self.body.read(&mut buf[..len]).map_err(download_error)?;
+        self.chunk = Some(remaining - read);
+        Ok(read)
+    }
+}
+
+fn fetch(
+    url: &str,
+    slot: FileSlot,
+    expected_sha256: Option<Sha256Digest>,
+    expected_len: Option<usize>,
+) -> Result<(), OtaError> {
+    let policy = RollbackPolicy {
+        running_version: "0.0.0".to_string(),
+        ..Default::default()
+    };
+    receive_download(
+        &mut Download::get(url),
+        slot,
+        Chip::Esp32c3,
+        policy,
+        &public_key(&SEED),
+        expected_sha256,
+        expected_len,
+    )
+}
+
+#[test]
+fn served_image_is_installed() {
+    let path = slot_path("served");
+    let signed = signed();
+    let url = serve(signed.clone());
+    let request: FetchRequest = serde_json::from_str(&format!(
+        r#"{{"sha256": "{}"}}"#,
+        to_hex(&Sha256::digest(&signed))
+    ))
+    .unwrap();
+    let (url, expected_sha256) = request.resolve(Some(&url)).unwrap();
+    fetch(
+        &url,
+        FileSlot::new(&path, SLOT_SIZE),
+        expected_sha256,
+        Some(signed.len()),
+    )
+    .unwrap();
+    assert_eq!(fs::read(&path).unwrap(), firmware());
+}
+
+#[test]
+fn wrong_size_or_digest_is_refused() {
+    let path = slot_path("refused");
+    let signed = signed();
+
+    let url = serve(signed.clone());
+    let result = fetch(&url, FileSlot::new(&path, SLOT_SIZE), None, Some(1000));
+    assert!(matches!(result, Err(OtaError::Download(_))), "{result:?}");
+
+    let url = serve(signed);
+    let result = fetch(&url, FileSlot::new(&path, SLOT_SIZE), Some([0; 32]), None);
+    assert!(matches!(result, Err(OtaError::Checksum(_))), "{result:?}");
+    assert!(!path.exists());
+    assert!(!ota_host::partial_path(&path).exists());
+}
+
+#[test]
+fn chunked_download_is_held_to_the_manifest_size() {
+    let path = slot_path("chunked");
+    let signed = signed();
+    let headers = "Transfer-Encoding: chunked\r\n".to_string();
+
+    let url = serve_with(headers.clone(), chunked(&signed));
+    let result = fetch(
+        &url,
+        FileSlot::new(&path, SLOT_SIZE),
+        None,
+        Some(signed.len() - 1),
+    );
+    assert!(matches!(result, Err(OtaError::Download(_))), "{result:?}");
+    assert!(!path.exists());
+
+    let url = serve_with(headers, chunked(&signed));
+    fetch(
+        &url,
+        FileSlot::new(&path, SLOT_SIZE),
+        None,
+        Some(signed.len()),
+    )
+    .unwrap();
+    assert_eq!(fs::read(&path).unwrap(), firmware());
+}
+
+#[test]
+fn compressed_download_is_held_to_the_manifest_size() {
+    let path = slot_path("gzip");
+    let signed = signed();
+    let compressed = gzip(&signed);
+    let headers = format!(
+        "Content-Encoding: gzip\r\nContent-Length: {}\r\n",
+        compressed.len()
+    );
+
+    // The size of the file, not of what went over the wire
+    let url = serve_with(headers.clone(), compressed.clone());
+    let result = fetch(
+        &url,
+        FileSlot::new(&path, SLOT_SIZE),
+        None,
+        Some(compressed.len()),
+    );
+    assert!(matches!(result, Err(OtaError::Download(_))), "{result:?}");
+    assert!(!path.exists());
+
+    let url = serve_with(headers, compressed);
+    fetch(
+        &url,
+        FileSlot::new(&path, SLOT_SIZE),
+        None,
+        Some(signed.len()),
+    )
+    .unwrap();
+    assert_eq!(fs::read(&path).unwrap(), firmware());
+}
+
+#[test]
+fn unsigned_download_is_refused() {
+    let path = slot_path("unsigned");
+    let url = serve(firmware());
+    let result = fetch(&url, FileSlot::new(&path, SLOT_SIZE), None, None);
+    assert!(matches!(result, Err(OtaError::Signature(_))), "{result:?}");
+    assert!(!path.exists());
+}
+
+#[test]
+fn request_url_falls_back_to_the_configured_one() {
+    let request = FetchRequest::default();
+    assert!(request.resolve(None).is_err());
+    let (url, sha256) = request.resolve(Some("http://fw.local/ota.bin")).unwrap();
+    assert_eq!((url.as_str(), sha256), ("http://fw.local/ota.bin", None));
+
+    let request: FetchRequest =
+        serde_json::from_str(r#"{"url": "https://x/y.bin", "force": true}"#).unwrap();
+    assert!(request.force && !request.save);
+    assert_eq!(
+        request.resolve(Some("http://fw.local/ota.bin")).unwrap().0,
+        "https://x/y.bin"
+    );
+
+    for json in [
+        r#"{"url": "ftp://x/y.bin"}"#,
+        r#"{"url": "not a url"}"#,
+        r#"{"url": "http://x/y.bin", "sha256": "abc"}"#,
+    ] {
+        let request: FetchRequest = serde_json::from_str(json).unwrap();
+        assert!(request.resolve(None).is_err(), "{json}");
+    }
+}
@@ -0,0 +1,495 @@
+use anyhow::{anyhow, Result};
+use flate2::write::{DeflateEncoder, GzEncoder, ZlibEncoder};
+use flate2::Compression as Level;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use ota_host::error::OtaError;
+use ota_host::image::Chip;
+use ota_host::upload::{receive_upload, SlotWriter};
+use ota_host::verify::{to_hex, SHA256_HEADER};
+use ota_host::version::RollbackPolicy;
+use ota_host::{FileReader, FileSlot, MemoryBody};
+use ota_sign::{public_key, sign_image};
+use sha2::{Digest, Sha256};
+
+const SEED: [u8; 32] = [7; 32];
+const SLOT_SIZE: usize = 0x1f0000;
+const BOUNDARY: &str = "----ota-host-test";
+
+fn firmware() -> Vec<u8> {
+    fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/../../ota.bin")).unwrap()
+}
+
+fn signed() -> Vec<u8> {
+    sign_image(&SEED, &firmware()).unwrap()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    to_hex(&Sha256::digest(data))
+}
+
+// A fresh directory per test, the tests run in parallel
+fn slot_path(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ota-host-{}-{test}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir.join("ota_1.bin")
+}
+
+fn upload(body: &mut MemoryBody, slot: FileSlot) -> Result<(), OtaError> {
+    let policy = RollbackPolicy {
+        running_version: "0.0.0".to_string(),
+        ..Default::default()
+    };
+    receive_upload(body, slot, Chip::Esp32c3, policy, &public_key(&SEED))
+}
+
+fn multipart(fields: &[(&str, &str)], file: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (name, value) in fields {
+        body.extend_from_slice(
+            format!(
+                "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n"
+            )
+            .as_bytes(),
+        );
+    }
+    body.extend_from_slice(
+        format!(
+            "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"firmware\"; filename=\"ota.bin\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n"
+        )
+        .as_bytes(),
+    );
+    body.extend_from_slice(file);
+    body.extend_from_slice(format!("\r\n--{BOUNDARY}--\r\n").as_bytes());
+    body
+}
+
+fn assert_not_installed(path: &Path) {
+    assert!(!path.exists(), "slot was completed");
+    assert!(
+        !ota_host::partial_path(path).exists(),
+        "slot was not aborted"
+    );
+}
+
+#[test]
+fn raw_upload_installs_firmware() {
+    let path = slot_path("raw");
+    for chunk in [1440, 4096, 7] {
+        let mut body = MemoryBody::new(signed()).chunk(chunk);
+        upload(&mut body, FileSlot::new(&path, SLOT_SIZE)).unwrap();
+        // The signature trailer is held back from flash
+        assert_eq!(fs::read(&path).unwrap(), firmware());
+        assert!(!ota_host::partial_path(&path).exists());
+    }
+}
+
+#[test]
+fn raw_upload_checks_sha256_header() {
+    let path = slot_path("raw-sha256");
+    let signed = signed();
+    let mut body =
+        MemoryBody::new(signed.clone()).header("X-Firmware-SHA256", &sha256_hex(&signed));
+    upload(&mut body, FileSlot::new(&path, SLOT_SIZE)).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), firmware());
+
+    fs::remove_file(&path).unwrap();
+    let mut body = MemoryBody::new(signed).query(&format!("sha256={}", sha256_hex(b"other")));
+    let result = upload(&mut body, FileSlot::new(&path, SLOT_SIZE));
+    assert!(matches!(result, Err(OtaError::Checksum(_))), "{result:?}");
+    assert_not_installed(&path);
+}
+
+#[test]
+fn multipart_upload_installs_firmware() {
+    let path = slot_path("multipart");
+    let signed = signed();
+    let form = multipart(&[("sha256", &sha256_hex(&signed))], &signed);
+    for chunk in [1440, 333] {
+        let mut body = MemoryBody::new(form.clone())
+            .header(
+                "Content-Type",
+                &format!("multipart/form-data; boundary={BOUNDARY}"),
+            )
+            .chunk(chunk);
+        upload(&mut body, FileSlot::new(&path, SLOT_SIZE)).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), firmware());
+    }
+}
+
+#[test]
+fn multipart_upload_rejects_wrong_sha256() {
+    let path = slot_path("multipart-sha256");
+    let form = multipart(&[("sha256", &sha256_hex(b"other"))], &signed());
+    let mut body = MemoryBody::new(form).header(
+        "Content-Type",
+        &format!("multipart/form-data; boundary={BOUNDARY}"),
+    );
+    let result = upload(&mut body, FileSlot::new(&path, SLOT_SIZE));
+    assert!(matches!(result, Err(OtaError::Checksum(_))), "{result:?}");
+    assert_not_installed(&path);
+}
+
+#[test]
+fn failing_slot_aborts_update() {
+    let path = slot_path("failing");
+    let mut body = MemoryBody::new(signed());
+    let result = upload(
+        &mut body,
+        FileSlot::new(&path, SLOT_SIZE).fail_after(64 * 1024),
+    );
+    assert!(matches!(result, Err(OtaError::Flash(_))), "{result:?}");
+    assert_not_installed(&path);
+}
+
+#[test]
+fn image_larger_than_slot_is_rejected() {
+    let path = slot_path("too-large");
+    let mut body = MemoryBody::new(signed());
+    let result = upload(&mut body, FileSlot::new(&path, 512 * 1024));
+    assert!(
+        matches!(result, Err(OtaError::InvalidImage(_))),
+        "{result:?}"
+    );
+    assert_not_installed(&path);
+}
+
+#[test]
+fn unsigned_image_is_rejected() {
+    let path = slot_path("unsigned");
+    let mut body = MemoryBody::new(firmware());
+    let result = upload(&mut body, FileSlot::new(&path, SLOT_SIZE));
+    assert!(matches!(result, Err(OtaError::Signature(_))), "{result:?}");
+    assert_not_installed(&path);
+}
+
+#[test]
+fn sha256_header_wins_over_form_field() {
+    let path = slot_path("sha256-header");
+    let signed = signed();
+    let form = multipart(&[("sha256", &sha256_hex(b"other"))], &signed);
+    let mut body = MemoryBody::new(form)
+        .header(
+            "Content-Type",
+            &format!("multipart/form-data; boundary={BOUNDARY}"),
+        )
+        .header("X-Firmware-SHA256", &sha256_hex(&signed));
+    upload(&mut body, FileSlot::new(&path, SLOT_SIZE)).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), firmware());
+}
+
+#[test]
+fn malformed_sha256_is_a_bad_request() {
+    let path = slot_path("sha256-malformed");
+    let mut body = MemoryBody::new(signed()).header("X-Firmware-SHA256", "abc");
+    let result = upload(&mut body, FileSlot::new(&path, SLOT_SIZE));
+    assert!(matches!(result, Err(OtaError::BadRequest(_))), "{result:?}");
+    assert_not_installed(&path);
+}
+
+/// A slot that must never be erased, the upload has to fail on its header first
+struct Untouched;
+
+impl SlotWriter for Untouched {
+    type Running = FileReader;
+
+    fn capacity(&self) -> usize {
+        SLOT_SIZE
+    }
+
+    fn begin(&mut self) -> Result<()> {
+        panic!("update slot erased");
+    }
+
+    fn write(&mut self, _: &[u8]) -> Result<()> {
+        panic!("update slot written");
+    }
+
+    fn abort(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn complete(&mut self) -> Result<()> {
+        panic!("update slot completed");
+    }
+
+    fn running(&self) -> Result<FileReader> {
+        Err(anyhow!("No running slot image"))
+    }
+}
+
+fn rejected_before_begin(file: Vec<u8>) -> OtaError {
+    let policy = RollbackPolicy {
+        running_version: "0.0.0".to_string(),
+        ..Default::default()
+    };
+    let mut body = MemoryBody::new(file);
+    receive_upload(
+        &mut body,
+        Untouched,
+        Chip::Esp32c3,
+        policy,
+        &public_key(&SEED),
+    )
+    .unwrap_err()
+}
+
+#[test]
+fn text_file_is_rejected_before_begin() {
+    let text = "#!/bin/sh\necho not firmware\n".repeat(40).into_bytes();
+    let error = rejected_before_begin(sign_image(&SEED, &text).unwrap());
+    assert!(matches!(error, OtaError::InvalidImage(_)), "{error:?}");
+}
+
+#[test]
+fn truncated_header_is_rejected_before_begin() {
+    let error = rejected_before_begin(firmware()[..200].to_vec());
+    assert!(matches!(error, OtaError::InvalidImage(_)), "{error:?}");
+}
+
+#[test]
+fn wrong_chip_is_rejected_before_begin() {
+    let mut esp32 = firmware();
+    esp32[12] = 0x00;
+    let error = rejected_before_begin(sign_image(&SEED, &esp32).unwrap());
+    assert!(matches!(error, OtaError::InvalidImage(_)), "{error:?}");
+    assert!(
+        error.to_string().contains("ESP32, this device is ESP32-C3"),
+        "{error}"
+    );
+}
+
+#[test]
+fn content_type_picks_raw_or_multipart() {
+    let path = slot_path("content-type");
+    let signed = signed();
+    for content_type in ["application/octet-stream", "text/plain", "form-data"] {
+        let mut body = MemoryBody::new(signed.clone()).header("Content-Type", content_type);
+        upload(&mut body, FileSlot::new(&path, SLOT_SIZE)).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), firmware(), "{content_type}");
+        fs::remove_file(&path).unwrap();
+    }
+    // Any multipart type is parsed as a form
+    for content_type in [
+        format!("Multipart/Form-Data; boundary=\"{BOUNDARY}\""),
+        format!("multipart/mixed; boundary={BOUNDARY}"),
+    ] {
+        let mut body =
+            MemoryBody::new(multipart(&[], &signed)).header("Content-Type", &content_type);
+        upload(&mut body, FileSlot::new(&path, SLOT_SIZE)).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), firmware(), "{content_type}");
+        fs::remove_file(&path).unwrap();
+    }
+}
+
+#[test]
+fn multipart_without_boundary_or_file_is_a_bad_request() {
+    let path = slot_path("bad-multipart");
+    let signed = signed();
+    for content_type in ["multipart/form-data", "multipart/mixed; boundary=x"] {
+        let mut body =
+            MemoryBody::new(multipart(&[], &signed)).header("Content-Type", content_type);
+        let result = upload(&mut body, FileSlot::new(&path, SLOT_SIZE));
+        assert!(
+            matches!(result, Err(OtaError::BadRequest(_))),
+            "{content_type} {result:?}"
+        );
+    }
+    let fields_only = format!(
+        "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"sha256\"\r\n\r\nabcd\r\n--{BOUNDARY}--\r\n"
+    );
+    let mut body = MemoryBody::new(fields_only.into_bytes()).header(
+        "Content-Type",
+        &format!("multipart/form-data; boundary={BOUNDARY}"),
+    );
+    let result = upload(&mut body, FileSlot::new(&path, SLOT_SIZE));
+    assert!(matches!(result, Err(OtaError::BadRequest(_))), "{result:?}");
+    assert_not_installed(&path);
+}
+
+#[test]
+fn empty_body_needs_a_length() {
+    let path = slot_path("empty");
+    let result = upload(
+        &mut MemoryBody::new(Vec::new()),
+        FileSlot::new(&path, SLOT_SIZE),
+    );
+    assert!(matches!(result, Err(OtaError::MissingLength)), "{result:?}");
+}
+
+fn upload_over(running_version: &str, body: &mut MemoryBody, path: &Path) -> Result<(), OtaError> {
+    let policy = RollbackPolicy {
+        running_version: running_version.to_string(),
+        ..Default::default()
+    };
+    let slot = FileSlot::new(path, SLOT_SIZE);
+    receive_upload(body, slot, Chip::Esp32c3, policy, &public_key(&SEED))
+}
+
+#[test]
+fn downgrade_needs_force() {
+    let path = slot_path("downgrade");
+    let mut body = MemoryBody::new(signed());
+    let result = upload_over("2.0.0", &mut body, &path);
+    assert!(matches!(result, Err(OtaError::Downgrade(_))), "{result:?}");
+    assert_not_installed(&path);
+
+    let mut body = MemoryBody::new(signed()).query("force=true");
+    upload_over("2.0.0", &mut body, &path).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), firmware());
+}
+
+#[test]
+fn force_field_split_across_reads_counts() {
+    let path = slot_path("force-split");
+    let form = multipart(&[("force", "true")], &signed());
+    let at = form.windows(4).position(|w| w == b"true").unwrap();
+    let content_type = format!("multipart/form-data; boundary={BOUNDARY}");
+    // "tr" ends one read, "ue" starts the next
+    let mut body = MemoryBody::new(form)
+        .header("Content-Type", &content_type)
+        .chunk(at + 2);
+    upload_over("2.0.0", &mut body, &path).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), firmware());
+
+    fs::remove_file(&path).unwrap();
+    let form = multipart(&[("force", "truest")], &signed());
+    let mut body = MemoryBody::new(form).header("Content-Type", &content_type);
+    let result = upload_over("2.0.0", &mut body, &path);
+    assert!(matches!(result, Err(OtaError::Downgrade(_))), "{result:?}");
+}
+
+#[test]
+fn version_that_does_not_compare_needs_force() {
+    let path = slot_path("unknown-version");
+    let mut body = MemoryBody::new(signed());
+    let result = upload_over("9269a53-dirty", &mut body, &path);
+    assert!(matches!(result, Err(OtaError::Downgrade(_))), "{result:?}");
+
+    let mut body = MemoryBody::new(signed()).query("force=1");
+    upload_over("9269a53-dirty", &mut body, &path).unwrap();
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Level::best());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Level::best());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn raw_deflate(data: &[u8]) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Level::best());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[test]
+fn compressed_uploads_install_firmware() {
+    let path = slot_path("compressed");
+    let signed = signed();
+    for (file, encoding) in [
+        (gzip(&signed), None),
+        (zlib(&signed), None),
+        (gzip(&signed), Some("gzip")),
+        (zlib(&signed), Some("deflate")),
+        (raw_deflate(&signed), Some("deflate")),
+    ] {
+        for chunk in [7, 1440 * 3] {
+            let mut body = MemoryBody::new(file.clone())
+                .header(SHA256_HEADER, &sha256_hex(&signed))
+                .chunk(chunk);
+            if let Some(encoding) = encoding {
+                body = body.header("Content-Encoding", encoding);
+            }
+            upload(&mut body, FileSlot::new(&path, SLOT_SIZE)).unwrap();
+            assert_eq!(fs::read(&path).unwrap(), firmware(), "{encoding:?} {chunk}");
+            fs::remove_file(&path).unwrap();
+        }
+    }
+}
+
+#[test]
+fn compressed_upload_checks_the_decompressed_sha256() {
+    let path = slot_path("compressed-sha256");
+    let signed = signed();
+    for file in [gzip(&signed), zlib(&signed)] {
+        // The digest of what was sent is not the image's
+        let mut body = MemoryBody::new(file.clone()).header(SHA256_HEADER, &sha256_hex(&file));
+        let result = upload(&mut body, FileSlot::new(&path, SLOT_SIZE));
+        assert!(matches!(result, Err(OtaError::Checksum(_))), "{result:?}");
+        assert_not_installed(&path);
+    }
+}
+
+#[test]
+fn compressed_file_in_a_form_installs_firmware() {
+    let path = slot_path("compressed-form");
+    let mut body = MemoryBody::new(multipart(&[], &gzip(&signed())))
+        .header(
+            "Content-Type",
+            &format!("multipart/form-data; boundary={BOUNDARY}"),
+        )
+        .chunk(300);
+    upload(&mut body, FileSlot::new(&path, SLOT_SIZE)).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), firmware());
+}
+
+#[test]
+fn truncated_or_corrupt_compressed_upload_is_rejected() {
+    let path = slot_path("compressed-bad");
+    let compressed = gzip(&signed());
+
+    let truncated = compressed[..compressed.len() - 100].to_vec();
+    let result = upload(
+        &mut MemoryBody::new(truncated),
+        FileSlot::new(&path, SLOT_SIZE),
+    );
+    assert!(
+        matches!(result, Err(OtaError::InvalidImage(_))),
+        "{result:?}"
+    );
+    assert_not_installed(&path);
+
+    let mut corrupt = compressed;
+    let len = corrupt.len();
+    corrupt[len / 2] ^= 0xff;
+    let result = upload(
+        &mut MemoryBody::new(corrupt),
+        FileSlot::new(&path, SLOT_SIZE),
+    );
+    assert!(
+        matches!(
+            result,
+            Err(OtaError::InvalidImage(_)) | Err(OtaError::Signature(_))
+        ),
+        "{result:?}"
+    );
+    assert_not_installed(&path);
+}
+
+#[test]
+fn content_encoding_must_match_the_upload() {
+    let path = slot_path("encoding-mismatch");
+    let mut body = MemoryBody::new(signed()).header("Content-Encoding", "gzip");
+    let result = upload(&mut body, FileSlot::new(&path, SLOT_SIZE));
+    assert!(matches!(result, Err(OtaError::BadRequest(_))), "{result:?}");
+
+    // Too short to tell by its header
+    let mut body = MemoryBody::new(vec![0x1f]).header("Content-Encoding", "gzip");
+    let result = upload(&mut body, FileSlot::new(&path, SLOT_SIZE));
+    assert!(matches!(result, Err(OtaError::BadRequest(_))), "{result:?}");
+
+    let mut body = MemoryBody::new(gzip(&signed())).header("Content-Encoding", "br");
+    let result = upload(&mut body, FileSlot::new(&path, SLOT_SIZE));
+    assert!(matches!(result, Err(OtaError::BadRequest(_))), "{result:?}");
+    assert_not_installed(&path);
+}
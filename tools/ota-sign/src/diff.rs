@@ -0,0 +1,249 @@
+// Patch generation for delta updates, the format is described in core/src/delta.rs
+//
+// Match finding is bsdiff's: a suffix array over the old image, approximate
+// matches extended forwards and backwards, the rest sent as extra bytes.
+
+use crate::delta::{SlotReader, PATCH_MAGIC};
+use anyhow::{bail, Result};
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+// Shorter zero runs are cheaper to send as part of a literal
+const MIN_ZERO_RUN: usize = 4;
+
+/// One bsdiff control record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Record {
+    new_start: usize,
+    old_start: usize,
+    diff_len: usize,
+    extra_len: usize,
+    seek: i64,
+}
+
+pub fn diff(old: &[u8], new: &[u8]) -> Result<Vec<u8>> {
+    if old.len() > u32::MAX as usize || new.len() > u32::MAX as usize {
+        bail!("Images over 4 GiB are not supported");
+    }
+    let mut patch = Vec::new();
+    patch.extend_from_slice(PATCH_MAGIC);
+    patch.extend_from_slice(&(old.len() as u32).to_le_bytes());
+    patch.extend_from_slice(&(new.len() as u32).to_le_bytes());
+    patch.extend_from_slice(&Sha256::digest(old));
+
+    for record in records(old, new) {
+        write_varint(&mut patch, record.diff_len as u64);
+        write_varint(&mut patch, record.extra_len as u64);
+        write_varint(
+            &mut patch,
+            ((record.seek << 1) ^ (record.seek >> 63)) as u64,
+        );
+        let diff: Vec<u8> = (0..record.diff_len)
+            .map(|i| new[record.new_start + i].wrapping_sub(old[record.old_start + i]))
+            .collect();
+        write_diff_runs(&mut patch, &diff);
+        let extra_start = record.new_start + record.diff_len;
+        patch.extend_from_slice(&new[extra_start..extra_start + record.extra_len]);
+    }
+    Ok(patch)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn write_diff_runs(out: &mut Vec<u8>, diff: &[u8]) {
+    let mut i = 0;
+    while i < diff.len() {
+        let zeros = diff[i..].iter().take_while(|b| **b == 0).count();
+        write_varint(out, zeros as u64);
+        i += zeros;
+        if i == diff.len() {
+            break;
+        }
+        let start = i;
+        while i < diff.len() && !diff[i..].starts_with(&[0; MIN_ZERO_RUN]) {
+            i += 1;
+        }
+        write_varint(out, (i - start) as u64);
+        out.extend_from_slice(&diff[start..i]);
+    }
+}
+
+/// Prefix doubling, fine for images of a few MB
+fn suffix_array(data: &[u8]) -> Vec<usize> {
+    let n = data.len();
+    let mut sa: Vec<usize> = (0..n).collect();
+    let mut rank: Vec<usize> = data.iter().map(|b| *b as usize).collect();
+    let mut next = vec![0; n];
+    let mut k = 1;
+    if n < 2 {
+        return sa;
+    }
+    loop {
+        let key = |rank: &[usize], i: usize| (rank[i], rank.get(i + k).map_or(0, |r| r + 1));
+        sa.sort_unstable_by_key(|&i| key(&rank, i));
+        next[sa[0]] = 0;
+        for w in 1..n {
+            let step = (key(&rank, sa[w - 1]) < key(&rank, sa[w])) as usize;
+            next[sa[w]] = next[sa[w - 1]] + step;
+        }
+        std::mem::swap(&mut rank, &mut next);
+        if rank[sa[n - 1]] == n - 1 {
+            break;
+        }
+        k *= 2;
+    }
+    sa
+}
+
+fn match_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+/// Longest match for the start of `new` in `old`, as (old position, length)
+fn search(sa: &[usize], old: &[u8], new: &[u8]) -> (usize, usize) {
+    if sa.is_empty() {
+        return (0, 0);
+    }
+    let (mut st, mut en) = (0, sa.len() - 1);
+    while en - st >= 2 {
+        let x = st + (en - st) / 2;
+        let suffix = &old[sa[x]..];
+        let len = suffix.len().min(new.len());
+        if suffix[..len].cmp(&new[..len]) == Ordering::Less {
+            st = x;
+        } else {
+            en = x;
+        }
+    }
+    let x = match_len(&old[sa[st]..], new);
+    let y = match_len(&old[sa[en]..], new);
+    if x > y {
+        (sa[st], x)
+    } else {
+        (sa[en], y)
+    }
+}
+
+fn records(old: &[u8], new: &[u8]) -> Vec<Record> {
+    let sa = suffix_array(old);
+    let mut records = Vec::new();
+    let (mut scan, mut len, mut pos) = (0usize, 0usize, 0usize);
+    let (mut lastscan, mut lastpos, mut lastoffset) = (0usize, 0usize, 0isize);
+    let old_matches =
+        |at: isize, byte: u8| at >= 0 && (at as usize) < old.len() && old[at as usize] == byte;
+
+    while scan < new.len() {
+        let mut oldscore = 0isize;
+        scan += len;
+        let mut scsc = scan;
+        while scan < new.len() {
+            (pos, len) = search(&sa, old, &new[scan..]);
+            while scsc < scan + len {
+                if old_matches(scsc as isize + lastoffset, new[scsc]) {
+                    oldscore += 1;
+                }
+                scsc += 1;
+            }
+            if (len as isize == oldscore && len != 0) || len as isize > oldscore + 8 {
+                break;
+            }
+            if old_matches(scan as isize + lastoffset, new[scan]) {
+                oldscore -= 1;
+            }
+            scan += 1;
+        }
+
+        if len as isize != oldscore || scan == new.len() {
+            // Extend the previous match forwards...
+            let (mut s, mut best, mut lenf) = (0isize, 0isize, 0usize);
+            let mut i = 0;
+            while lastscan + i < scan && lastpos + i < old.len() {
+                if old[lastpos + i] == new[lastscan + i] {
+                    s += 1;
+                }
+                i += 1;
+                if s * 2 - i as isize > best * 2 - lenf as isize {
+                    best = s;
+                    lenf = i;
+                }
+            }
+            // ...and the new one backwards
+            let mut lenb = 0;
+            if scan < new.len() {
+                let (mut s, mut best) = (0isize, 0isize);
+                let mut i = 1;
+                while scan >= lastscan + i && pos >= i {
+                    if old[pos - i] == new[scan - i] {
+                        s += 1;
+                    }
+                    if s * 2 - i as isize > best * 2 - lenb as isize {
+                        best = s;
+                        lenb = i;
+                    }
+                    i += 1;
+                }
+            }
+            // Split any overlap where it costs least
+            if lastscan + lenf > scan - lenb {
+                let overlap = (lastscan + lenf) - (scan - lenb);
+                let (mut s, mut best, mut lens) = (0isize, 0isize, 0usize);
+                for i in 0..overlap {
+                    if new[lastscan + lenf - overlap + i] == old[lastpos + lenf - overlap + i] {
+                        s += 1;
+                    }
+                    if new[scan - lenb + i] == old[pos - lenb + i] {
+                        s -= 1;
+                    }
+                    if s > best {
+                        best = s;
+                        lens = i + 1;
+                    }
+                }
+                lenf = lenf - overlap + lens;
+                lenb -= lens;
+            }
+
+            records.push(Record {
+                new_start: lastscan,
+                old_start: lastpos,
+                diff_len: lenf,
+                extra_len: (scan - lenb) - (lastscan + lenf),
+                seek: (pos - lenb) as i64 - (lastpos + lenf) as i64,
+            });
+            lastscan = scan - lenb;
+            lastpos = pos - lenb;
+            lastoffset = pos as isize - scan as isize;
+        }
+    }
+    records
+}
+
+/// An image file standing in for the running slot
+pub struct FileSlot {
+    file: File,
+}
+
+impl FileSlot {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self {
+            file: File::open(path)?,
+        })
+    }
+}
+
+impl SlotReader for FileSlot {
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(offset as u64))?;
+        self.file.read_exact(buf)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+// Host side of the signed firmware and delta patch formats, shares the device's code
+
+pub use ota_core::{delta, verify};
+
+pub mod diff;
+
+use anyhow::{anyhow, Result};
+use delta::{DeltaApplier, SlotReader};
+use ed25519_compact::{KeyPair, Noise, Seed};
+use verify::{FirmwareHasher, SIGNATURE_MAGIC, SIGNATURE_TRAILER_LEN};
+
+pub fn generate_seed() -> [u8; 32] {
+    *Seed::generate()
+}
+
+pub fn public_key(seed: &[u8; 32]) -> [u8; 32] {
+    *KeyPair::from_seed(Seed::new(*seed)).pk
+}
+
+/// Appends the signature trailer the device expects to a `save-image` binary.
+pub fn sign_image(seed: &[u8; 32], firmware: &[u8]) -> Result<Vec<u8>> {
+    if firmware.ends_with(SIGNATURE_MAGIC) {
+        return Err(anyhow!("Image is already signed"));
+    }
+    let key_pair = KeyPair::from_seed(Seed::new(*seed));
+    let mut hasher = FirmwareHasher::new();
+    hasher.update(firmware);
+    let signature = key_pair.sk.sign(hasher.finalize(), Some(Noise::generate()));
+
+    let mut signed = Vec::with_capacity(firmware.len() + SIGNATURE_TRAILER_LEN);
+    signed.extend_from_slice(firmware);
+    signed.extend_from_slice(signature.as_ref());
+    signed.extend_from_slice(SIGNATURE_MAGIC);
+    Ok(signed)
+}
+
+/// Same checks the device runs at the end of an upload, minus the flash writes.
+pub fn verify_image(public_key: &[u8; 32], image: &[u8]) -> Result<()> {
+    let mut hasher = FirmwareHasher::new();
+    let mut splitter = verify::TrailerSplitter::new();
+    splitter.push(image, |firmware| {
+        hasher.update(firmware);
+        Ok(())
+    })?;
+    verify::verify_signature(public_key, &hasher.finalize(), &splitter.into_trailer())
+}
+
+/// The running slot holds the image without its signature trailer, patches are made against that.
+pub fn strip_signature(image: &[u8]) -> &[u8] {
+    if image.len() >= SIGNATURE_TRAILER_LEN && image.ends_with(SIGNATURE_MAGIC) {
+        &image[..image.len() - SIGNATURE_TRAILER_LEN]
+    } else {
+        image
+    }
+}
+
+/// What the device does with a patch, minus the flash writes.
+pub fn apply_patch(old: impl SlotReader, patch: &[u8]) -> Result<Vec<u8>> {
+    let mut applier = DeltaApplier::new(old);
+    let mut new = Vec::new();
+    applier.push(patch, |data| {
+        new.extend_from_slice(data);
+        Ok(())
+    })?;
+    applier.finish()?;
+    Ok(new)
+}
@@ -0,0 +1,80 @@
+use anyhow::{bail, Context, Result};
+use ota_sign::diff::FileSlot;
+use ota_sign::verify::{parse_hex, parse_public_key_hex, to_hex};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+
+const USAGE: &str = "Usage:
+    ota-sign keygen <secret.key>
+    ota-sign pubkey <secret.key>
+    ota-sign sign <secret.key> <ota.bin> <ota.signed.bin>
+    ota-sign verify <public key hex> <ota.signed.bin>
+    ota-sign diff <running.bin> <ota.signed.bin> <ota.patch>
+    ota-sign patch <running.bin> <ota.patch> <ota.signed.bin>";
+
+fn read_seed(path: &str) -> Result<[u8; 32]> {
+    let hex = fs::read_to_string(path).with_context(|| format!("Reading {path}"))?;
+    parse_hex(&hex)
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args[..] {
+        ["keygen", key_path] => {
+            // Readable by the owner only, and never over an existing key
+            let mut file = match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(key_path)
+            {
+                Ok(file) => file,
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    bail!("{key_path} already exists, refusing to overwrite")
+                }
+                Err(e) => return Err(e).with_context(|| format!("Creating {key_path}")),
+            };
+            let seed = ota_sign::generate_seed();
+            file.write_all(to_hex(&seed).as_bytes())?;
+            println!("OTA_PUBKEY=\"{}\"", to_hex(&ota_sign::public_key(&seed)));
+        }
+        ["pubkey", key_path] => {
+            let seed = read_seed(key_path)?;
+            println!("OTA_PUBKEY=\"{}\"", to_hex(&ota_sign::public_key(&seed)));
+        }
+        ["sign", key_path, input, output] => {
+            let seed = read_seed(key_path)?;
+            let firmware = fs::read(input).with_context(|| format!("Reading {input}"))?;
+            fs::write(output, ota_sign::sign_image(&seed, &firmware)?)?;
+            println!("Signed {input} -> {output}");
+        }
+        ["verify", public_key, input] => {
+            let image = fs::read(input).with_context(|| format!("Reading {input}"))?;
+            ota_sign::verify_image(&parse_public_key_hex(public_key)?, &image)?;
+            println!("{input}: signature OK");
+        }
+        ["diff", old, new, output] => {
+            let old_image = fs::read(old).with_context(|| format!("Reading {old}"))?;
+            let new_image = fs::read(new).with_context(|| format!("Reading {new}"))?;
+            let patch = ota_sign::diff::diff(ota_sign::strip_signature(&old_image), &new_image)?;
+            fs::write(output, &patch)?;
+            println!(
+                "{output}: {} bytes, {:.1}% of {new}",
+                patch.len(),
+                patch.len() as f32 / new_image.len().max(1) as f32 * 100.0
+            );
+        }
+        ["patch", old, patch, output] => {
+            // A signed file works as the old image, the patch only reads the firmware part
+            let slot = FileSlot::open(old).with_context(|| format!("Opening {old}"))?;
+            let patch = fs::read(patch).with_context(|| format!("Reading {patch}"))?;
+            fs::write(output, ota_sign::apply_patch(slot, &patch)?)?;
+            println!("Rebuilt {output}");
+        }
+        _ => bail!("{USAGE}"),
+    }
+    Ok(())
+}
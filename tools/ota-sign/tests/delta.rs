@@ -0,0 +1,274 @@
+use ota_sign::delta::{DeltaApplier, SlotReader};
+use ota_sign::diff::{diff, FileSlot};
+use ota_sign::{apply_patch, sign_image, strip_signature};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const SEED: [u8; 32] = [7; 32];
+
+fn firmware() -> Vec<u8> {
+    fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/../../ota.bin")).unwrap()
+}
+
+/// Writes `data` to a scratch file standing in for a flash slot
+fn slot_file(name: &str, data: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("ota-delta-{}-{name}", std::process::id()));
+    fs::write(&path, data).unwrap();
+    path
+}
+
+fn file_slot(path: &Path) -> FileSlot {
+    FileSlot::open(path).unwrap()
+}
+
+/// Streams the patch in `chunk` sized pieces into an update slot file
+fn apply_in_chunks(old: &Path, patch: &[u8], chunk: usize, update: &Path) {
+    let mut out = fs::File::create(update).unwrap();
+    let mut applier = DeltaApplier::new(file_slot(old));
+    for piece in patch.chunks(chunk) {
+        applier
+            .push(piece, |data| {
+                out.write_all(data)?;
+                Ok(())
+            })
+            .unwrap();
+    }
+    applier.finish().unwrap();
+}
+
+// Code moving around the way it does after a small change
+fn edited(old: &[u8]) -> Vec<u8> {
+    let mut new = old.to_vec();
+    new[0x200..0x240].copy_from_slice(&[0x5a; 0x40]);
+    let moved: Vec<u8> = new[0x8000..0x8800].to_vec();
+    new.splice(0x4000..0x4000, moved);
+    new.drain(0x20000..0x20100);
+    for i in (0x30000..new.len()).step_by(997) {
+        new[i] = new[i].wrapping_add(1);
+    }
+    new.extend_from_slice(b"appended at the end");
+    new
+}
+
+#[test]
+fn firmware_patch_rebuilds_signed_image() {
+    let old = firmware();
+    let new = sign_image(&SEED, &edited(&old)).unwrap();
+    let patch = diff(&old, &new).unwrap();
+    assert!(
+        patch.len() < new.len() / 4,
+        "patch is {} of {} bytes",
+        patch.len(),
+        new.len()
+    );
+
+    let old_slot = slot_file("fw-old", &old);
+    let update_slot = slot_file("fw-update", &[]);
+    apply_in_chunks(&old_slot, &patch, 1440 * 3, &update_slot);
+    assert_eq!(fs::read(&update_slot).unwrap(), new);
+
+    // The running slot never holds the trailer, a signed old file diffs the same
+    let signed_old = sign_image(&SEED, &old).unwrap();
+    assert_eq!(strip_signature(&signed_old), &old[..]);
+    assert_eq!(diff(strip_signature(&signed_old), &new).unwrap(), patch);
+
+    fs::remove_file(old_slot).unwrap();
+    fs::remove_file(update_slot).unwrap();
+}
+
+#[test]
+fn any_chunking_gives_the_same_image() {
+    let base: Vec<u8> = (0..0x31000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let old = base[..0x30000].to_vec();
+    let new = edited(&base);
+    let patch = diff(&old, &new).unwrap();
+
+    let old_slot = slot_file("chunk-old", &old);
+    let update_slot = slot_file("chunk-update", &[]);
+    for chunk in [1, 2, 3, 7, 64, 4096, patch.len()] {
+        apply_in_chunks(&old_slot, &patch, chunk, &update_slot);
+        assert_eq!(fs::read(&update_slot).unwrap(), new, "chunk size {chunk}");
+    }
+    fs::remove_file(old_slot).unwrap();
+    fs::remove_file(update_slot).unwrap();
+}
+
+#[test]
+fn edge_cases_round_trip() {
+    let data: Vec<u8> = (0..5000u32).map(|i| (i % 13) as u8).collect();
+    for (old, new) in [
+        (&[][..], &data[..]),
+        (&data[..], &[][..]),
+        (&data[..], &data[..]),
+        (&data[..100], &data[..]),
+    ] {
+        let patch = diff(old, new).unwrap();
+        let old_slot = slot_file("edge-old", old);
+        assert_eq!(apply_patch(file_slot(&old_slot), &patch).unwrap(), new);
+        fs::remove_file(old_slot).unwrap();
+    }
+}
+
+#[test]
+fn patch_for_another_image_is_rejected() {
+    let old = firmware();
+    let patch = diff(&old, &edited(&old)).unwrap();
+    let mut other = old.clone();
+    other[0x1234] ^= 0xff;
+    let old_slot = slot_file("other-old", &other);
+    let err = apply_patch(file_slot(&old_slot), &patch).unwrap_err();
+    assert!(err.to_string().contains("different image"), "{err}");
+    fs::remove_file(old_slot).unwrap();
+}
+
+struct Memory(Vec<u8>);
+
+impl SlotReader for Memory {
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> anyhow::Result<()> {
+        match self.0.get(offset..offset + buf.len()) {
+            Some(data) => buf.copy_from_slice(data),
+            None => anyhow::bail!("read past the slot"),
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn truncated_or_damaged_patch_fails() {
+    let old: Vec<u8> = (0..50_000u32).map(|i| (i * 31 % 256) as u8).collect();
+    let mut new = old.clone();
+    new[100..200].fill(0);
+    new.extend_from_slice(b"more");
+    let patch = diff(&old, &new).unwrap();
+
+    let err = apply_patch(Memory(old.clone()), &patch[..patch.len() - 1]).unwrap_err();
+    assert!(err.to_string().contains("ends early"), "{err}");
+
+    let mut extended = patch.clone();
+    extended.push(0);
+    assert!(apply_patch(Memory(old.clone()), &extended).is_err());
+
+    let mut not_a_patch = patch;
+    not_a_patch[0] = b'X';
+    assert!(apply_patch(Memory(old), &not_a_patch).is_err());
+}
+
+#[test]
+fn old_image_is_checked_across_every_block() {
+    // Several hashing steps and a partial last block
+    let old: Vec<u8> = (0..3 * 0x10000 + 2 * 4096 + 123u32)
+        .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+        .collect();
+    let mut new = old.clone();
+    new[0x18000..0x18100].fill(0x5a);
+    new.extend_from_slice(b"tail");
+    let patch = diff(&old, &new).unwrap();
+
+    let old_slot = slot_file("blocks-old", &old);
+    let update_slot = slot_file("blocks-update", &[]);
+    apply_in_chunks(&old_slot, &patch, 1440, &update_slot);
+    assert_eq!(fs::read(&update_slot).unwrap(), new);
+
+    for at in [0, 0x10000, 0x2ffff, old.len() - 1] {
+        let mut other = old.clone();
+        other[at] ^= 1;
+        fs::write(&old_slot, &other).unwrap();
+        let mut applier = DeltaApplier::new(file_slot(&old_slot));
+        let mut written = 0;
+        let err = applier
+            .push(&patch, |data| {
+                written += data.len();
+                Ok(())
+            })
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("different image"),
+            "{at:#x}: {err}"
+        );
+        assert_eq!(written, 0, "{at:#x}");
+    }
+    fs::remove_file(old_slot).unwrap();
+    fs::remove_file(update_slot).unwrap();
+}
+
+fn varint(mut value: u64, out: &mut Vec<u8>) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+// diff_len 0, extra_len, seek and the extra bytes
+fn record(extra: &[u8], seek: i64, out: &mut Vec<u8>) {
+    varint(0, out);
+    varint(extra.len() as u64, out);
+    varint(((seek << 1) ^ (seek >> 63)) as u64, out);
+    out.extend_from_slice(extra);
+}
+
+#[test]
+fn huge_seek_is_refused_without_overflow() {
+    let old = vec![5u8; 100];
+    for seek in [i64::MAX, i64::MIN, 91, -11] {
+        let mut patch = ota_sign::delta::PATCH_MAGIC.to_vec();
+        patch.extend_from_slice(&(old.len() as u32).to_le_bytes());
+        patch.extend_from_slice(&1u32.to_le_bytes());
+        patch.extend_from_slice(&Sha256::digest(&old));
+        // Away from the start first, so any seek past i64::MAX - 10 overflows
+        record(&[], 10, &mut patch);
+        record(b"x", seek, &mut patch);
+        let err = apply_patch(Memory(old.clone()), &patch).unwrap_err();
+        assert!(
+            err.to_string().contains("seeks outside the old image"),
+            "{seek}: {err}"
+        );
+    }
+}
+
+#[test]
+fn lengths_past_u32_are_refused_without_truncation() {
+    let old = vec![5u8; 100];
+    // Truncated to 32 bits each of these reads as a 1 byte run
+    let huge = u64::from(u32::MAX) + 2;
+    for lengths in [[huge, 0, 0, 0], [0, huge, 0, 0], [1, 0, 0, huge]] {
+        let mut patch = ota_sign::delta::PATCH_MAGIC.to_vec();
+        patch.extend_from_slice(&(old.len() as u32).to_le_bytes());
+        patch.extend_from_slice(&1u32.to_le_bytes());
+        patch.extend_from_slice(&Sha256::digest(&old));
+        // diff_len, extra_len, seek, then the zero run and literal length
+        for value in lengths {
+            varint(value, &mut patch);
+        }
+        patch.push(b'x');
+        let mut written = 0;
+        let result = DeltaApplier::new(Memory(old.clone())).push(&patch, |data| {
+            written += data.len();
+            Ok(())
+        });
+        assert!(result.is_err(), "{lengths:?}");
+        assert_eq!(written, 0, "{lengths:?}");
+    }
+}
+
+#[test]
+fn varint_past_64_bits_is_refused() {
+    let old = vec![5u8; 100];
+    // Bit 63 alone still fits, the bit above it does not
+    for (last, ok) in [(0x01, true), (0x02, false), (0x7f, false)] {
+        let mut patch = ota_sign::delta::PATCH_MAGIC.to_vec();
+        patch.extend_from_slice(&(old.len() as u32).to_le_bytes());
+        patch.extend_from_slice(&1u32.to_le_bytes());
+        patch.extend_from_slice(&Sha256::digest(&old));
+        patch.extend_from_slice(&[0x80; 9]);
+        patch.push(last);
+        let err = apply_patch(Memory(old.clone()), &patch).unwrap_err();
+        assert_eq!(
+            err.to_string().contains("Bad varint"),
+            !ok,
+            "{last:#x}: {err}"
+        );
+    }
+}
@@ -0,0 +1,58 @@
+use ota_sign::verify::{TrailerSplitter, SIGNATURE_TRAILER_LEN};
+use ota_sign::{public_key, sign_image, verify_image};
+
+const SEED: [u8; 32] = [7; 32];
+
+fn firmware() -> Vec<u8> {
+    std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/../../ota.bin")).unwrap()
+}
+
+#[test]
+fn signed_image_verifies() {
+    let firmware = firmware();
+    let signed = sign_image(&SEED, &firmware).unwrap();
+    assert_eq!(signed.len(), firmware.len() + SIGNATURE_TRAILER_LEN);
+    assert!(verify_image(&public_key(&SEED), &signed).is_ok());
+}
+
+#[test]
+fn unsigned_image_is_rejected() {
+    assert!(verify_image(&public_key(&SEED), &firmware()).is_err());
+}
+
+#[test]
+fn tampered_image_is_rejected() {
+    let mut signed = sign_image(&SEED, &firmware()).unwrap();
+    signed[0x1000] ^= 0x01;
+    assert!(verify_image(&public_key(&SEED), &signed).is_err());
+
+    let mut signed = sign_image(&SEED, &firmware()).unwrap();
+    let sig_at = signed.len() - SIGNATURE_TRAILER_LEN;
+    signed[sig_at] ^= 0x01;
+    assert!(verify_image(&public_key(&SEED), &signed).is_err());
+}
+
+#[test]
+fn wrong_key_is_rejected() {
+    let signed = sign_image(&SEED, &firmware()).unwrap();
+    assert!(verify_image(&public_key(&[8; 32]), &signed).is_err());
+}
+
+#[test]
+fn splitter_holds_back_trailer_across_chunk_sizes() {
+    let signed = sign_image(&SEED, &firmware()).unwrap();
+    for chunk in [1, 71, 72, 73, 4320] {
+        let mut splitter = TrailerSplitter::new();
+        let mut written = Vec::new();
+        for data in signed.chunks(chunk) {
+            splitter
+                .push(data, |firmware| {
+                    written.extend_from_slice(firmware);
+                    Ok(())
+                })
+                .unwrap();
+        }
+        assert_eq!(written.len(), signed.len() - SIGNATURE_TRAILER_LEN);
+        assert_eq!(splitter.into_trailer(), signed[written.len()..]);
+    }
+}
@@ -0,0 +1,154 @@
+// One boot of the simulated device
+//
+// Holds what the firmware keeps in APP_CONFIG and reads from the partition
+// table. A reboot drops it and boots a new one from the state directory.
+
+use anyhow::{Context, Result};
+use log::info;
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use ota_core::configuration::{AppConfiguration, Wifi};
+use ota_core::credential::{self, SetupRequest, SALT_LEN, TOKEN_LEN};
+use ota_core::error::OtaError;
+use ota_core::verify::parse_public_key_hex;
+use ota_core::version::RollbackPolicy;
+use ota_host::storage::JsonFileStorage;
+
+use crate::{flash, wifi, VERSION};
+
+/// The .env values the firmware is built with, from the environment or ./.env
+pub struct BuildEnv {
+    pub wifi_ssid: String,
+    pub wifi_pass: String,
+    pub ap_ssid: String,
+    pub ap_pass: String,
+    pub public_key: [u8; 32],
+}
+
+impl BuildEnv {
+    pub fn load() -> Result<Self> {
+        let dotenv = fs::read_to_string(".env").unwrap_or_default();
+        let value = |key: &str, default: &str| {
+            env::var(key).ok().unwrap_or_else(|| {
+                dotenv
+                    .lines()
+                    .filter_map(|line| line.split_once('='))
+                    .find(|(k, _)| k.trim() == key)
+                    .map_or(default.to_string(), |(_, v)| {
+                        v.trim().trim_matches('"').to_string()
+                    })
+            })
+        };
+        let public_key = value("OTA_PUBKEY", "");
+        Ok(Self {
+            wifi_ssid: value("WSSID", "home"),
+            wifi_pass: value("WPASS", "home-pass"),
+            ap_ssid: value("APSSID", "ota-test"),
+            ap_pass: value("APPASS", "ota-test-pass"),
+            public_key: parse_public_key_hex(&public_key)
+                .context("OTA_PUBKEY, see ota-sign keygen")?,
+        })
+    }
+}
+
+pub struct Device {
+    pub dir: PathBuf,
+    pub running: &'static str,
+    pub config: RwLock<AppConfiguration<JsonFileStorage>>,
+    pub public_key: [u8; 32],
+}
+
+impl Device {
+    pub fn boot(dir: &Path, build: &BuildEnv) -> Result<Self> {
+        let storage = JsonFileStorage::open(dir.join("nvs.json"))?;
+        let mut config = AppConfiguration::default();
+        config.init(
+            Arc::new(RwLock::new(storage)),
+            Wifi {
+                ssid: Some(build.wifi_ssid.clone()),
+                pass: Some(build.wifi_pass.clone()),
+                ..Default::default()
+            },
+            Wifi {
+                ssid: Some(build.ap_ssid.clone()),
+                pass: Some(build.ap_pass.clone()),
+                ..Default::default()
+            },
+        )?;
+        let device = Self {
+            dir: dir.to_path_buf(),
+            running: flash::boot_slot(dir),
+            config: RwLock::new(config),
+            public_key: build.public_key,
+        };
+        let wifi_scan = wifi::scan(&build.wifi_ssid);
+        {
+            let config = device.config.read().unwrap();
+            wifi::start(&wifi_scan, &config.sta, &config.ap);
+        }
+        info!("Booted from {}", device.running);
+        println!("FW version: {} simulated", device.running_version());
+        Ok(device)
+    }
+
+    /// From the running slot's app descriptor, the simulator's own when empty
+    pub fn running_version(&self) -> String {
+        flash::image_info(&self.dir, self.running)
+            .map_or_else(|| VERSION.to_string(), |info| info.version)
+    }
+
+    pub fn rollback_policy(&self) -> Result<RollbackPolicy> {
+        let config = self.config.read().unwrap();
+        let min_secure_version = if config.ota.secure_version_check {
+            Some(config.secure_version()?)
+        } else {
+            None
+        };
+        Ok(RollbackPolicy {
+            running_version: self.running_version(),
+            min_secure_version,
+            force: false,
+        })
+    }
+
+    /// An update is waiting for the next reboot
+    pub fn staged(&self) -> bool {
+        flash::boot_slot(&self.dir) != self.running
+    }
+
+    pub fn configured(&self) -> bool {
+        credential::configured(&self.config.read().unwrap().auth)
+    }
+
+    pub fn authorize(&self, authorization: Option<&str>) -> Result<(), OtaError> {
+        credential::authorize(&self.config.read().unwrap().auth, authorization)
+    }
+
+    pub fn set_credential(&self, request: SetupRequest) -> Result<(), OtaError> {
+        let mut config = self.config.write().unwrap();
+        credential::set_credential(&mut config.auth, &request, random::<SALT_LEN>()?)?;
+        config.store_values_to_nvs()?;
+        info!("Admin credential set for {}", request.username.trim());
+        Ok(())
+    }
+
+    pub fn new_token(&self) -> Result<String, OtaError> {
+        let mut config = self.config.write().unwrap();
+        let token = credential::set_token(&mut config.auth, random::<TOKEN_LEN>()?);
+        config.store_values_to_nvs()?;
+        info!("New API token issued");
+        Ok(token)
+    }
+}
+
+fn random<const N: usize>() -> Result<[u8; N], OtaError> {
+    let mut bytes = [0u8; N];
+    File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut bytes))
+        .map_err(|e| OtaError::Internal(format!("/dev/urandom: {e}")))?;
+    Ok(bytes)
+}
@@ -0,0 +1,107 @@
+// App slots and otadata as files in the state directory
+//
+// ota_0.bin and ota_1.bin hold the images, otadata the label of the slot to
+// boot next. An empty slot file is fine, the simulator then reports its own
+// version as the running one.
+
+use anyhow::Result;
+use log::info;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use ota_core::image::{AppImageInfo, APP_HEADER_LEN};
+use ota_core::upload::SlotWriter;
+use ota_host::{FileReader, FileSlot};
+
+pub const SLOTS: [&str; 2] = ["ota_0", "ota_1"];
+// ota_0 and ota_1 in partitions.csv
+pub const SLOT_SIZE: usize = 0x1c0000;
+
+const OTADATA: &str = "otadata";
+
+pub fn slot_path(dir: &Path, label: &str) -> PathBuf {
+    dir.join(format!("{label}.bin"))
+}
+
+/// The slot otadata points at, ota_0 on a fresh state directory.
+pub fn boot_slot(dir: &Path) -> &'static str {
+    let label = fs::read_to_string(dir.join(OTADATA)).unwrap_or_default();
+    SLOTS
+        .into_iter()
+        .find(|slot| *slot == label.trim())
+        .unwrap_or(SLOTS[0])
+}
+
+pub fn set_boot_slot(dir: &Path, label: &str) -> Result<()> {
+    fs::write(dir.join(OTADATA), label)?;
+    info!("Boot slot set to {label}");
+    Ok(())
+}
+
+/// The other slot than `running`
+pub fn next_slot(running: &str) -> &'static str {
+    if running == SLOTS[0] {
+        SLOTS[1]
+    } else {
+        SLOTS[0]
+    }
+}
+
+/// The app descriptor of a flashed slot, `None` when it holds no image.
+pub fn image_info(dir: &Path, label: &str) -> Option<AppImageInfo> {
+    let mut header = vec![0; APP_HEADER_LEN];
+    File::open(slot_path(dir, label))
+        .and_then(|mut file| file.read_exact(&mut header))
+        .ok()?;
+    AppImageInfo::parse(&header).ok()
+}
+
+/// The next update slot, completing it points otadata at it like
+/// esp_ota_set_boot_partition would.
+pub struct UpdateSlot {
+    dir: PathBuf,
+    label: &'static str,
+    file: FileSlot,
+}
+
+impl UpdateSlot {
+    pub fn next(dir: &Path, running: &str) -> Self {
+        let label = next_slot(running);
+        Self {
+            dir: dir.to_path_buf(),
+            label,
+            file: FileSlot::new(slot_path(dir, label), SLOT_SIZE)
+                .with_running(slot_path(dir, running)),
+        }
+    }
+}
+
+impl SlotWriter for UpdateSlot {
+    type Running = FileReader;
+
+    fn capacity(&self) -> usize {
+        self.file.capacity()
+    }
+
+    fn begin(&mut self) -> Result<()> {
+        self.file.begin()
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.file.write(data)
+    }
+
+    fn abort(&mut self) -> Result<()> {
+        self.file.abort()
+    }
+
+    fn complete(&mut self) -> Result<()> {
+        self.file.complete()?;
+        set_boot_slot(&self.dir, self.label)
+    }
+
+    fn running(&self) -> Result<FileReader> {
+        self.file.running()
+    }
+}
@@ -0,0 +1,90 @@
+// The firmware's web UI and API on a PC
+//
+// Serves the routes of httpd() against a state directory instead of flash:
+// ota_0.bin and ota_1.bin are the app slots, otadata names the one to boot and
+// nvs.json holds the settings. Wi-Fi is a fixed scan list. A reboot stops the
+// server and boots again from the state directory, into a freshly flashed
+// image when there is one.
+
+use anyhow::{anyhow, bail, Result};
+use log::info;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tiny_http::Server;
+
+use ota_core::reboot;
+use ota_core::status::{self, OtaStatus};
+
+mod device;
+mod flash;
+mod routes;
+mod wifi;
+
+use device::{BuildEnv, Device};
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+const USAGE: &str = "Usage:
+    ota-sim [<state dir> [<port>]]
+
+Defaults to ./sim-state and port 8080, port 0 picks a free one. OTA_PUBKEY,
+WSSID, WPASS, APSSID and APPASS come from the environment or ./.env";
+
+fn main() -> Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let (dir, port) = match args[..] {
+        [] => ("sim-state", "8080"),
+        [dir] => (dir, "8080"),
+        [dir, port] => (dir, port),
+        _ => bail!("{USAGE}"),
+    };
+    let mut port: u16 = port.parse().map_err(|_| anyhow!("{USAGE}"))?;
+    let dir = Path::new(dir);
+    fs::create_dir_all(dir)?;
+    let build = BuildEnv::load()?;
+
+    loop {
+        let device = Device::boot(dir, &build)?;
+        let server = listen(port)?;
+        // Later boots keep the port picked for port 0
+        if let Some(addr) = server.server_addr().to_ip() {
+            port = addr.port();
+        }
+        println!("Listening on http://127.0.0.1:{port}/");
+
+        // Updates and /restart schedule the reboot, see reboot.rs
+        while !reboot::due() {
+            if let Some(request) = server.recv_timeout(Duration::from_secs(1))? {
+                routes::handle(&device, request);
+            }
+        }
+        info!("Restart requested");
+        drop(server);
+        info!("Httpd stopped");
+        // What the device forgets on a reboot
+        reboot::cancel();
+        status::update(|s| *s = OtaStatus::default());
+        info!("Restarting...");
+    }
+}
+
+// The old server lets go of the port in the background
+fn listen(port: u16) -> Result<Server> {
+    let mut attempts = 0;
+    loop {
+        match Server::http(("0.0.0.0", port)) {
+            Ok(server) => return Ok(server),
+            Err(e) if attempts < 20 => {
+                info!("Port {port} busy, retrying: {e}");
+                attempts += 1;
+                thread::sleep(Duration::from_millis(250));
+            }
+            Err(e) => bail!("Listening on port {port}: {e}"),
+        }
+    }
+}
@@ -0,0 +1,229 @@
+// The routes of httpd() in the firmware's main.rs, served by tiny_http
+//
+// Answers the way the device does, same pages, texts, status codes and JSON
+// errors. Pull updates, upload sessions, boot selection and data partitions
+// are left to the device.
+
+use log::info;
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Method, Request, Response};
+
+use ota_core::configuration::WifiForm;
+use ota_core::credential::{SetupRequest, CHALLENGE};
+use ota_core::error::OtaError;
+use ota_core::image::Chip;
+use ota_core::ota_lock::OtaLock;
+use ota_core::reboot::{self, RebootWhen};
+use ota_core::status;
+use ota_core::upload::{receive_upload, BodySource};
+
+use crate::device::Device;
+use crate::flash::UpdateSlot;
+
+const HTMLOTA: &str = include_str!("../../../firmware/src/html/ota.html");
+
+const HTMLSETTINGS: &str = include_str!("../../../firmware/src/html/settings.html");
+
+const HTMLINDEX: &str = include_str!("../../../firmware/src/html/index.html");
+
+const HTMLSETUP: &str = include_str!("../../../firmware/src/html/setup.html");
+
+type Reply = Response<Cursor<Vec<u8>>>;
+
+pub fn handle(device: &Device, mut request: Request) {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let method = request.method().clone();
+    let result = match (method, path) {
+        (Method::Get, "/id") => Ok(text("OTATest")),
+        (Method::Get, "/") => Ok(page(device, HTMLINDEX)),
+        // Holds the Wi-Fi passwords
+        (Method::Get, "/json") => authorize(device, &request).and_then(|_| {
+            let config = device.config.read().unwrap();
+            Ok(json(serde_json::to_string(&*config).map_err(internal)?))
+        }),
+        (Method::Get, "/restart") => authorize(device, &request).map(|_| {
+            info!("Restart requested");
+            reboot::schedule(RebootWhen::Now);
+            text("Rebooting")
+        }),
+        (Method::Get, "/settings") => Ok(page(device, HTMLSETTINGS)),
+        (Method::Post, "/settings") => authorize(device, &request)
+            .and_then(|_| read_body(&mut request))
+            .and_then(|body| settings(device, &body)),
+        (Method::Get, "/ota") => Ok(page(device, HTMLOTA)),
+        (Method::Get, "/setup") => Ok(html(HTMLSETUP)),
+        // Open until the first credential is set, after that it changes it
+        (Method::Post, "/api/auth/setup") => {
+            let allowed = match device.configured() {
+                true => authorize(device, &request),
+                false => Ok(()),
+            };
+            allowed
+                .and_then(|_| read_body(&mut request))
+                .and_then(|body| {
+                    serde_json::from_slice::<SetupRequest>(&body)
+                        .map_err(|e| OtaError::BadRequest(e.to_string()))
+                })
+                .and_then(|setup| device.set_credential(setup))
+                .map(|_| text("Admin credential saved"))
+        }
+        (Method::Post, "/api/auth/token") => authorize(device, &request)
+            .and_then(|_| device.new_token())
+            .map(|token| json(serde_json::json!({ "token": token }).to_string())),
+        // ?seq=<last seen seq> holds the request until the status changes
+        (Method::Get, "/ota/status") => {
+            let seen = url::form_urlencoded::parse(query.as_bytes())
+                .find(|(k, _)| k == "seq")
+                .and_then(|(_, v)| v.parse::<u32>().ok());
+            let current = match seen {
+                Some(seen) => status::wait_for_change(seen, Duration::from_secs(2)),
+                None => status::snapshot(),
+            };
+            serde_json::to_string(&current).map(text).map_err(internal)
+        }
+        (Method::Post, "/api/ota/apply") => authorize(device, &request).and_then(|_| {
+            if !device.staged() {
+                return Err(OtaError::NothingStaged);
+            }
+            reboot::schedule(RebootWhen::Now);
+            Ok(text(RebootWhen::Now.to_string()))
+        }),
+        // Multipart form or raw image
+        (Method::Post, "/ota") | (Method::Put, "/firmware") => {
+            authorize(device, &request).and_then(|_| ota_upload(device, &mut request, query))
+        }
+        _ => Ok(text("Not served by the simulator").with_status_code(404)),
+    };
+    let response = result.unwrap_or_else(ota_error);
+    if let Err(e) = request.respond(response) {
+        info!("Sending the response failed: {e}");
+    }
+}
+
+fn settings(device: &Device, body: &[u8]) -> Result<Reply, OtaError> {
+    let WifiForm { ssid, pass } = WifiForm::parse(body)?;
+    let mut config = device.config.write().unwrap();
+    config.sta.ssid = Some(ssid.to_owned());
+    config.sta.pass = Some(pass.to_owned());
+    config.store_values_to_nvs()?;
+    Ok(text(format!(
+        "Wifi setup completed for SSID: {} Password: {}, please reboot to connect",
+        ssid, pass
+    )))
+}
+
+fn ota_upload(device: &Device, request: &mut Request, query: &str) -> Result<Reply, OtaError> {
+    // Checked before flashing, a typo should not cost a whole upload
+    let when = RebootWhen::from_query(query)?;
+    let _lock = OtaLock::acquire("upload")?;
+    let start_time = Instant::now();
+    status::update(|s| s.begin(request.body_length(), start_time));
+    let result = (|| {
+        receive_upload(
+            &mut RequestBody(request),
+            UpdateSlot::next(&device.dir, device.running),
+            Chip::Esp32c3,
+            device.rollback_policy()?,
+            &device.public_key,
+        )
+    })();
+    status::finish(&result);
+    result?;
+    reboot::schedule(when);
+    Ok(text(format!(
+        "Flashed device in {:?} - {}",
+        start_time.elapsed(),
+        when
+    )))
+}
+
+/// An upload request as `receive_upload` reads it
+struct RequestBody<'a>(&'a mut Request);
+
+impl BodySource for RequestBody<'_> {
+    fn header(&self, name: &str) -> Option<&str> {
+        header(self.0, name)
+    }
+
+    fn query_string(&self) -> String {
+        self.0
+            .url()
+            .split_once('?')
+            .map_or(String::new(), |(_, query)| query.to_string())
+    }
+
+    fn content_len(&self) -> Option<usize> {
+        self.0.body_length()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, OtaError> {
+        self.0
+            .as_reader()
+            .read(buf)
+            .map_err(|e| OtaError::Transfer(format!("{:?}", e)))
+    }
+}
+
+fn header<'r>(request: &'r Request, name: &str) -> Option<&'r str> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(name))
+        .map(|h| h.value.as_str())
+}
+
+fn authorize(device: &Device, request: &Request) -> Result<(), OtaError> {
+    device.authorize(header(request, "Authorization"))
+}
+
+fn read_body(request: &mut Request) -> Result<Vec<u8>, OtaError> {
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .read_to_end(&mut body)
+        .map_err(|e| OtaError::Transfer(e.to_string()))?;
+    Ok(body)
+}
+
+fn internal(e: impl ToString) -> OtaError {
+    OtaError::Internal(e.to_string())
+}
+
+fn ota_error(e: OtaError) -> Reply {
+    info!("OTA request failed: {e}");
+    let resp = json(e.json(status::snapshot().bytes_written)).with_status_code(e.status());
+    // Lets browsers ask for the credential themselves
+    match e {
+        OtaError::Unauthorized => resp.with_header(header_line("WWW-Authenticate", CHALLENGE)),
+        _ => resp,
+    }
+}
+
+// Until the admin credential is set every page leads to /setup
+fn page(device: &Device, page: &str) -> Reply {
+    if device.configured() {
+        html(page)
+    } else {
+        text("")
+            .with_status_code(302)
+            .with_header(header_line("Location", "/setup"))
+    }
+}
+
+fn text(body: impl Into<String>) -> Reply {
+    Response::from_string(body)
+}
+
+fn html(body: &str) -> Reply {
+    text(body).with_header(header_line("Content-Type", "text/html; charset=UTF-8"))
+}
+
+fn json(body: String) -> Reply {
+    text(body).with_header(header_line("Content-Type", "application/json"))
+}
+
+fn header_line(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
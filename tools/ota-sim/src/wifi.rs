@@ -0,0 +1,70 @@
+// Simulated Wi-Fi, a fixed scan list instead of a radio
+//
+// Joining goes the way wifi_init.rs decides it on the device: the station
+// takes the channel of its access point when the scan finds it.
+
+use log::info;
+use std::cmp::Reverse;
+
+use ota_core::configuration::Wifi;
+
+#[derive(Debug, Clone)]
+pub struct AccessPointInfo {
+    pub ssid: String,
+    pub channel: u8,
+    pub signal_strength: i8,
+}
+
+const NEIGHBOURS: [(&str, u8, i8); 4] = [
+    ("office", 6, -58),
+    ("Guest", 6, -71),
+    ("FRITZ!Box 7530", 11, -77),
+    ("DIRECT-printer", 1, -84),
+];
+
+/// The neighbours plus `home`, the SSID the build was configured with.
+pub fn scan(home: &str) -> Vec<AccessPointInfo> {
+    let mut ours: Vec<AccessPointInfo> = NEIGHBOURS
+        .iter()
+        .map(|(ssid, channel, signal_strength)| AccessPointInfo {
+            ssid: ssid.to_string(),
+            channel: *channel,
+            signal_strength: *signal_strength,
+        })
+        .collect();
+    if !home.is_empty() {
+        ours.push(AccessPointInfo {
+            ssid: home.to_string(),
+            channel: 3,
+            signal_strength: -49,
+        });
+    }
+    ours.sort_by_key(|ap| Reverse(ap.signal_strength));
+    for our in ours.iter() {
+        println!("{:?}", our);
+    }
+    ours
+}
+
+pub fn start(scan: &[AccessPointInfo], sta: &Wifi, ap: &Wifi) {
+    let ap_ssid = ap.ssid.as_deref().unwrap_or_default();
+    match (sta.ssid.as_deref(), sta.pass.is_some()) {
+        (Some(ssid), true) => {
+            match scan.iter().find(|a| a.ssid.contains(ssid)) {
+                Some(ours) => {
+                    info!(
+                        "Found configured access point {} on channel {}",
+                        ssid, ours.channel
+                    );
+                    info!("Wifi connected to {ssid}");
+                }
+                None => info!(
+                    "Configured access point {} not found during scanning, will go with unknown channel",
+                    ssid
+                ),
+            }
+            info!("Access point {ap_ssid} up, mixed mode");
+        }
+        _ => info!("Access point {ap_ssid} up, no station configured"),
+    }
+}
@@ -0,0 +1,185 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+use ota_host::image::AppImageInfo;
+use ota_sign::verify::to_hex;
+use ota_sign::{public_key, sign_image};
+
+const SEED: [u8; 32] = [7; 32];
+// "admin:correct horse"
+const BASIC: &str = "Basic YWRtaW46Y29ycmVjdCBob3JzZQ==";
+
+/// The simulator on a free port with its own state directory
+struct Sim {
+    child: Child,
+    lines: Receiver<String>,
+    port: u16,
+    dir: PathBuf,
+}
+
+impl Sim {
+    fn start(test: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("ota-sim-{}-{test}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut child = Command::new(env!("CARGO_BIN_EXE_ota-sim"))
+            .arg(&dir)
+            .arg("0")
+            .env("OTA_PUBKEY", to_hex(&public_key(&SEED)))
+            .env("WSSID", "home")
+            .env("WPASS", "home-pass")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        let (send, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in stdout.lines().map_while(Result::ok) {
+                let _ = send.send(line);
+            }
+        });
+        let mut sim = Self {
+            child,
+            lines,
+            port: 0,
+            dir,
+        };
+        sim.port = sim.wait_for_boot().1;
+        sim
+    }
+
+    /// The firmware version and port of the next boot
+    fn wait_for_boot(&self) -> (String, u16) {
+        let mut version = String::new();
+        loop {
+            let line = self.lines.recv_timeout(Duration::from_secs(10)).unwrap();
+            if let Some(v) = line.strip_prefix("FW version: ") {
+                version = v.trim_end_matches(" simulated").to_string();
+            }
+            if let Some(url) = line.strip_prefix("Listening on http://127.0.0.1:") {
+                return (version, url.trim_end_matches('/').parse().unwrap());
+            }
+        }
+    }
+
+    fn request(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> (u16, String) {
+        let mut stream = TcpStream::connect(("127.0.0.1", self.port)).unwrap();
+        let mut head = format!(
+            "{method} {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n",
+            body.len()
+        );
+        for (name, value) in headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.write_all(body).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, body.to_string())
+    }
+
+    fn get(&self, path: &str) -> (u16, String) {
+        self.request("GET", path, &[("Authorization", BASIC)], b"")
+    }
+
+    fn setup(&self) {
+        let body = br#"{"username": "admin", "password": "correct horse"}"#;
+        let (status, _) = self.request("POST", "/api/auth/setup", &[], body);
+        assert_eq!(status, 200);
+    }
+}
+
+impl Drop for Sim {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn firmware() -> Vec<u8> {
+    fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/../../ota.bin")).unwrap()
+}
+
+#[test]
+fn pages_lead_to_setup_until_a_credential_is_set() {
+    let sim = Sim::start("setup");
+    assert_eq!(
+        sim.request("GET", "/id", &[], b""),
+        (200, "OTATest".to_string())
+    );
+    assert_eq!(sim.request("GET", "/", &[], b"").0, 302);
+    assert_eq!(sim.request("GET", "/json", &[], b"").0, 403);
+
+    sim.setup();
+    assert_eq!(sim.request("GET", "/ota", &[], b"").0, 200);
+    assert_eq!(sim.request("GET", "/json", &[], b"").0, 401);
+    let (status, body) = sim.get("/json");
+    assert_eq!(status, 200);
+    assert!(body.contains("\"ssid\":\"home\""), "{body}");
+}
+
+#[test]
+fn upload_boots_the_new_image() {
+    let sim = Sim::start("upload");
+    sim.setup();
+    let signed = sign_image(&SEED, &firmware()).unwrap();
+    let (status, body) = sim.request("PUT", "/firmware", &[("Authorization", BASIC)], &signed);
+    assert_eq!(status, 200, "{body}");
+    assert!(body.starts_with("Flashed device"), "{body}");
+    assert_eq!(fs::read(sim.dir.join("ota_1.bin")).unwrap(), firmware());
+
+    let (version, port) = sim.wait_for_boot();
+    assert_eq!(port, sim.port);
+    assert_eq!(version, AppImageInfo::parse(&firmware()).unwrap().version);
+    assert_eq!(
+        fs::read_to_string(sim.dir.join("otadata")).unwrap(),
+        "ota_1"
+    );
+}
+
+#[test]
+fn unsigned_upload_is_refused() {
+    let sim = Sim::start("unsigned");
+    sim.setup();
+    let (status, body) = sim.request("PUT", "/firmware", &[("Authorization", BASIC)], &firmware());
+    assert_eq!(status, 403, "{body}");
+    assert!(body.contains("signature_invalid"), "{body}");
+    assert!(!sim.dir.join("otadata").exists());
+}
+
+#[test]
+fn settings_survive_a_restart() {
+    let sim = Sim::start("settings");
+    sim.setup();
+    let form = [
+        ("Authorization", BASIC),
+        ("Content-Type", "application/x-www-form-urlencoded"),
+    ];
+    let (status, body) = sim.request("POST", "/settings", &form, b"ssid=office&pass=secret");
+    assert_eq!(status, 200, "{body}");
+    assert_eq!(
+        sim.request("POST", "/settings", &form, b"ssid=office").0,
+        400
+    );
+
+    assert_eq!(sim.get("/restart"), (200, "Rebooting".to_string()));
+    sim.wait_for_boot();
+    let (_, body) = sim.get("/json");
+    assert!(body.contains("\"ssid\":\"office\""), "{body}");
+}